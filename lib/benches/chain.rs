@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use btclib::{
+    U256,
+    crypto::PrivateKey,
+    params::ChainParams,
+    sha256::Hash,
+    types::{
+        Block, BlockHeader, Blockchain, Transaction, TransactionOutput, UnsignedTransaction, Utxo,
+    },
+    util::MerkleRoot,
+};
+use chrono::Utc;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use uuid::Uuid;
+
+const MINE_STEPS: usize = 1_000;
+
+fn output(private_key: &PrivateKey, value: u64) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: Uuid::new_v4(),
+        pubkey: private_key.public_key(),
+    }
+}
+
+// hashing rate of the nonce search, the target is all but unreachable so every step hashes
+fn mine(c: &mut Criterion) {
+    let mut header = BlockHeader::new(
+        Utc::now(),
+        0,
+        Hash::zero(),
+        MerkleRoot::calculate(&[Transaction::new(vec![], vec![])]).unwrap(),
+        U256::one(),
+    );
+
+    let mut group = c.benchmark_group("mine");
+    group.throughput(Throughput::Elements(MINE_STEPS as u64));
+    group.bench_function("hashes", |b| b.iter(|| header.mine(MINE_STEPS).unwrap()));
+    group.finish();
+}
+
+// a block at height 1 where every transaction spends one of our utxos back to us
+fn signed_block(transactions: usize) -> (Block, HashMap<Hash, Utxo>) {
+    let mut private_key = PrivateKey::new_key();
+    let mut utxos = HashMap::new();
+    let mut block_transactions = vec![];
+
+    let fee = 10;
+    for _ in 0..transactions {
+        let spent = output(&private_key, 1_000);
+        let hash = spent.hash().unwrap();
+        utxos.insert(hash, (false, spent.clone()));
+
+        block_transactions.push(
+            UnsignedTransaction {
+                inputs: vec![spent],
+                outputs: vec![output(&private_key, 1_000 - fee)],
+                locktime: None,
+            }
+            .sign(&mut private_key)
+            .unwrap(),
+        );
+    }
+
+    block_transactions.insert(
+        0,
+        Transaction::coinbase(&private_key.public_key(), 0, 1, None),
+    );
+
+    let header = BlockHeader::new(
+        Utc::now(),
+        0,
+        Hash::zero(),
+        MerkleRoot::calculate(&block_transactions).unwrap(),
+        btclib::MIN_TARGET,
+    );
+    let mut block = Block::new(header, block_transactions);
+    block.transactions[0].outputs[0].value =
+        block.calcualte_block_reward(1) + fee * transactions as u64;
+
+    (block, utxos)
+}
+
+fn verify_transactions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify_transactions");
+    // spends plus the coinbase fill the merkle tree exactly
+    for transactions in [7, 127, 511] {
+        let (block, utxos) = signed_block(transactions);
+        block.verify_transactions(1, &utxos).unwrap();
+
+        group.throughput(Throughput::Elements(transactions as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(transactions),
+            &transactions,
+            |b, _| b.iter(|| block.verify_transactions(1, &utxos).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+// mines a chain of the given length on the easiest target, back to back without the bootstrap pace
+fn chain(length: usize) -> Blockchain {
+    let private_key = PrivateKey::new_key();
+    let mut blockchain = Blockchain::with_params(ChainParams {
+        bootstrap_block_spacing: 0,
+        ..ChainParams::mainnet()
+    });
+
+    for _ in 0..length {
+        let mut block = blockchain
+            .build_template(&[(private_key.public_key(), 1)])
+            .unwrap();
+        while !block.header.mine(MINE_STEPS).unwrap() {}
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos().unwrap();
+    }
+
+    blockchain
+}
+
+fn rebuild_utxos(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rebuild_utxos");
+    group.sample_size(20);
+    for length in [10, 50, 100] {
+        let mut blockchain = chain(length);
+
+        group.throughput(Throughput::Elements(length as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(length), &length, |b, _| {
+            b.iter(|| blockchain.rebuild_utxos().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mine, verify_transactions, rebuild_utxos);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+
+use btclib::network::Message;
+use libfuzzer_sys::fuzz_target;
+
+// peer supplied bytes must decode or error, never panic
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::decode(data);
+});
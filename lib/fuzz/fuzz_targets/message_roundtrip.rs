@@ -0,0 +1,17 @@
+#![no_main]
+
+use btclib::network::Message;
+use libfuzzer_sys::fuzz_target;
+
+// whatever decodes must encode again and come back as the same message
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = Message::decode(data) else {
+        return;
+    };
+
+    let encoded = message.encode().expect("decoded message failed to encode");
+    let decoded = Message::decode(&encoded).expect("encoded message failed to decode");
+    let reencoded = decoded.encode().expect("decoded message failed to encode");
+
+    assert_eq!(encoded, reencoded, "message changed across a round trip");
+});
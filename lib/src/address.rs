@@ -0,0 +1,101 @@
+use bech32::{Bech32m, Hrp, primitives::decode::CheckedHrpstring};
+use ecdsa::VerifyingKey;
+
+use crate::{
+    crypto::PublicKey,
+    error::{BtcError, Result},
+    params::ChainParams,
+};
+
+// bech32m encoding of a compressed public key, prefixed with the chain's hrp
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Address(PublicKey);
+impl Address {
+    pub fn new(public_key: PublicKey) -> Self {
+        Self(public_key)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+
+    pub fn encode(&self, params: &ChainParams) -> Result<String> {
+        let hrp = Hrp::parse(&params.address_hrp).map_err(|_| BtcError::InvalidAddress)?;
+        bech32::encode::<Bech32m>(hrp, &self.0.0.to_sec1_bytes())
+            .map_err(|_| BtcError::InvalidAddress)
+    }
+
+    // rejects bad checksums and addresses belonging to a different chain
+    pub fn decode(address: &str, params: &ChainParams) -> Result<Self> {
+        let checked =
+            CheckedHrpstring::new::<Bech32m>(address).map_err(|_| BtcError::InvalidAddress)?;
+        let hrp = Hrp::parse(&params.address_hrp).map_err(|_| BtcError::InvalidAddress)?;
+        if checked.hrp() != hrp {
+            return Err(BtcError::InvalidAddress);
+        }
+
+        let bytes: Vec<u8> = checked.byte_iter().collect();
+        let public_key =
+            VerifyingKey::from_sec1_bytes(&bytes).map_err(|_| BtcError::InvalidPublicKey)?;
+        Ok(Self(PublicKey(public_key)))
+    }
+}
+
+impl From<PublicKey> for Address {
+    fn from(public_key: PublicKey) -> Self {
+        Self::new(public_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+    #[test]
+    fn address_round_trips() {
+        let public_key = PrivateKey::new_key().public_key();
+        let params = ChainParams::mainnet();
+
+        let encoded = Address::new(public_key.clone()).encode(&params).unwrap();
+        assert!(encoded.starts_with("btc1"));
+        let decoded = Address::decode(&encoded, &params).unwrap();
+        assert_eq!(decoded.public_key(), &public_key);
+    }
+
+    #[test]
+    fn any_single_character_corruption_is_rejected() {
+        let params = ChainParams::mainnet();
+        let encoded = Address::new(PrivateKey::new_key().public_key())
+            .encode(&params)
+            .unwrap();
+
+        // every character after the separator, swapped for each other valid one
+        let data_start = encoded.rfind('1').unwrap() + 1;
+        for position in data_start..encoded.len() {
+            let original = encoded.as_bytes()[position] as char;
+            for replacement in CHARSET.chars().filter(|c| *c != original) {
+                let mut corrupted = encoded.clone();
+                corrupted.replace_range(position..=position, &replacement.to_string());
+                assert!(
+                    Address::decode(&corrupted, &params).is_err(),
+                    "{corrupted} decoded"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn address_of_another_chain_is_rejected() {
+        let encoded = Address::new(PrivateKey::new_key().public_key())
+            .encode(&ChainParams::regtest())
+            .unwrap();
+
+        assert!(matches!(
+            Address::decode(&encoded, &ChainParams::mainnet()),
+            Err(BtcError::InvalidAddress)
+        ));
+    }
+}
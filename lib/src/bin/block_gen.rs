@@ -1,7 +1,7 @@
 use btclib::{
     crypto::PrivateKey,
     sha256::Hash,
-    types::{Block, BlockHeader, Transaction, TransactionOutput},
+    types::{Block, BlockHeader, Transaction},
     util::{MerkleRoot, Saveable},
 };
 use chrono::Utc;
@@ -9,7 +9,6 @@ use std::{
     env::{self},
     process::exit,
 };
-use uuid::Uuid;
 
 fn main() {
     let Some(path) = env::args().nth(1) else {
@@ -18,13 +17,11 @@ fn main() {
     };
 
     let private_key = PrivateKey::new_key();
-    let transactions = vec![Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
-        }],
+    let transactions = vec![Transaction::coinbase(
+        &private_key.public_key(),
+        btclib::INITIAL_REWARD * 10u64.pow(8),
+        0,
+        None,
     )];
     let merkle_root =
         MerkleRoot::calculate(&transactions).expect("failed to calculate merkle root");
@@ -0,0 +1,17 @@
+use btclib::{types::Blockchain, util::Saveable};
+use std::{env, fs::File, io::BufWriter, process::exit};
+
+fn main() {
+    let (Some(blockchain_path), Some(path)) = (env::args().nth(1), env::args().nth(2)) else {
+        eprintln!("Usage: bootstrap_export <blockchain_file> <bootstrap_file>");
+        exit(1);
+    };
+
+    let blockchain =
+        Blockchain::load_from_file(blockchain_path).expect("Failed to load blockchain");
+    let file = File::create(path).expect("Failed to create bootstrap file");
+    blockchain
+        .export_bootstrap(BufWriter::new(file))
+        .expect("Failed to write bootstrap file");
+    println!("exported {} blocks", blockchain.block_height());
+}
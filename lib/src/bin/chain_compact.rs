@@ -0,0 +1,28 @@
+use btclib::{types::Blockchain, util::Saveable};
+use std::{env, fs, process::exit};
+
+fn main() {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("Usage: chain_compact <blockchain_file> [<output_file>]");
+        exit(1);
+    };
+    // rewrite in place unless told otherwise, through a temporary file so a failure loses nothing
+    let output = env::args().nth(2).unwrap_or_else(|| path.clone());
+    let temporary = format!("{output}.compacting");
+
+    let before = fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+    let blockchain = Blockchain::load_from_file(&path).expect("Failed to load blockchain");
+    let compacted = blockchain
+        .compacted()
+        .expect("Blockchain failed validation, not compacting");
+    compacted
+        .save_to_file(&temporary)
+        .expect("Failed to save compacted blockchain");
+    fs::rename(&temporary, &output).expect("Failed to replace blockchain file");
+
+    let after = fs::metadata(&output).map(|m| m.len()).unwrap_or_default();
+    println!(
+        "compacted {} blocks: {before} -> {after} bytes",
+        compacted.block_height()
+    );
+}
@@ -0,0 +1,116 @@
+use btclib::{types::Block, util::difficulty};
+use serde::{
+    Deserialize,
+    de::{Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor},
+};
+use std::{
+    env, fmt,
+    fs::File,
+    io::{BufReader, Write},
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static NDJSON: AtomicBool = AtomicBool::new(false);
+
+// a saved blockchain whose blocks are printed while being read, one at a time,
+// so printing never holds more than a single block in memory
+struct PrintedChain;
+
+impl<'de> Deserialize<'de> for PrintedChain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(PrintedChain)
+    }
+}
+
+impl<'de> Visitor<'de> for PrintedChain {
+    type Value = PrintedChain;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a blockchain")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self, A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "blocks" {
+                map.next_value::<PrintedBlocks>()?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(PrintedChain)
+    }
+}
+
+struct PrintedBlocks;
+
+impl<'de> Deserialize<'de> for PrintedBlocks {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(PrintedBlocks)
+    }
+}
+
+impl<'de> Visitor<'de> for PrintedBlocks {
+    type Value = PrintedBlocks;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of blocks")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self, A::Error> {
+        let mut height = 0;
+        while let Some(block) = seq.next_element::<Block>()? {
+            print_block(height, &block);
+            height += 1;
+        }
+        Ok(PrintedBlocks)
+    }
+}
+
+fn print_block(height: u64, block: &Block) {
+    let hash = block.hash().expect("failed to hash block");
+    // without the spent outputs at hand, fees are whatever the coinbase claims over the reward
+    let coinbase_value: u64 = block
+        .transactions
+        .first()
+        .map(|coinbase| coinbase.outputs.iter().map(|output| output.value).sum())
+        .unwrap_or_default();
+    let fees = coinbase_value.saturating_sub(block.calcualte_block_reward(height));
+    let header = &block.header;
+
+    let line = if NDJSON.load(Ordering::Relaxed) {
+        format!(
+            r#"{{"height":{height},"hash":"{hash}","transactions":{},"fees":{fees},"timestamp":"{}","difficulty":{}}}"#,
+            block.transactions.len(),
+            header.timestamp.to_rfc3339(),
+            difficulty(header.target),
+        )
+    } else {
+        format!(
+            "#{height} {hash} txs: {} fees: {fees} time: {} difficulty: {:.2}",
+            block.transactions.len(),
+            header.timestamp,
+            difficulty(header.target),
+        )
+    };
+
+    // stop quietly once the reader goes away, e.g. piped into head
+    if writeln!(std::io::stdout(), "{line}").is_err() {
+        exit(0);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    NDJSON.store(args.iter().any(|arg| arg == "--ndjson"), Ordering::Relaxed);
+    let Some(path) = args.iter().find(|arg| !arg.starts_with("--")) else {
+        eprintln!("Usage: chain_print [--ndjson] <blockchain_file>");
+        exit(1);
+    };
+
+    let file = File::open(path).expect("failed to open blockchain file");
+    if let Err(e) = ciborium::from_reader::<PrintedChain, _>(BufReader::new(file)) {
+        eprintln!("failed to read blockchain: {e}");
+        exit(1);
+    }
+}
@@ -10,9 +10,15 @@ fn main() {
     let public_key_file = name.clone() + ".pub.pem";
     let private_key_file = name + ".priv.cbor";
 
-    private_key
-        .save_to_file(&private_key_file)
-        .expect("Error saving private key file");
+    // the key is only encrypted when a password is given, for compatibility with older tools
+    match env::var("BTC_KEY_PASSWORD") {
+        Ok(password) => private_key
+            .save_encrypted(&private_key_file, &password)
+            .expect("Error saving private key file"),
+        Err(_) => private_key
+            .save_to_file(&private_key_file)
+            .expect("Error saving private key file"),
+    }
     public_key
         .save_to_file(&public_key_file)
         .expect("Error saving public key file");
@@ -0,0 +1,28 @@
+use btclib::{crypto::PrivateKey, types::UnsignedTransaction, util::Saveable};
+use std::{env, fs, process::exit};
+
+fn main() {
+    let (Some(unsigned_path), Some(key_path), Some(path)) =
+        (env::args().nth(1), env::args().nth(2), env::args().nth(3))
+    else {
+        eprintln!("Usage: tx_sign <unsigned_tx_hex_file> <private_key_file> <tx_file>");
+        exit(1);
+    };
+
+    let hex = fs::read_to_string(unsigned_path).expect("Failed to read unsigned transaction");
+    let unsigned =
+        UnsignedTransaction::from_hex(&hex).expect("Failed to decode unsigned transaction");
+    // keys saved by key_gen with BTC_KEY_PASSWORD set are encrypted
+    let mut private_key = match env::var("BTC_KEY_PASSWORD") {
+        Ok(password) => {
+            PrivateKey::load_encrypted(key_path, &password).expect("Failed to load private key")
+        }
+        Err(_) => PrivateKey::load_from_file(key_path).expect("Failed to load private key"),
+    };
+
+    unsigned
+        .sign(&mut private_key)
+        .expect("Failed to sign transaction")
+        .save_to_file(path)
+        .expect("Failed to save transaction");
+}
@@ -0,0 +1,72 @@
+use btclib::{crypto::PublicKey, sha256::Hash, types::Blockchain, util::Saveable};
+use std::{
+    env,
+    io::{BufWriter, Write},
+    process::exit,
+};
+
+// outputs are identified by the hash of their key's pem, the same key hashes the same everywhere
+fn pubkey_pem_hash(pubkey: &PublicKey) -> Hash {
+    let mut pem = vec![];
+    pubkey
+        .save(&mut pem)
+        .expect("Failed to serialise public key");
+    Hash::hash(&pem).expect("Failed to hash public key")
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut min_value = 0;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--min-value" => {
+                min_value = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| {
+                        eprintln!("--min-value takes an amount");
+                        exit(1);
+                    });
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("Usage: utxodump [--min-value <amount>] <blockchain_file>");
+        exit(1);
+    };
+
+    let mut blockchain = Blockchain::load_from_file(path).expect("Failed to load blockchain");
+    blockchain.rebuild_utxos().expect("Failed to rebuild utxos");
+
+    let mut utxos: Vec<_> = blockchain
+        .utxos()
+        .iter()
+        .expect("Failed to read utxos")
+        .filter(|(_, (_, output))| output.value >= min_value)
+        .collect();
+    // sorted so dumps of the same chain diff cleanly
+    utxos.sort_unstable_by_key(|(hash, _)| hash.as_bytes());
+
+    // stop quietly once the reader goes away, e.g. piped into head
+    let mut out = BufWriter::new(std::io::stdout());
+    let mut write = |row: String| {
+        if writeln!(out, "{row}").is_err() {
+            exit(0);
+        }
+    };
+
+    write("output_hash,value,pubkey_pem_hash".to_string());
+    let mut total = 0;
+    for (hash, (_, output)) in &utxos {
+        total += output.value;
+        write(format!(
+            "{hash},{},{}",
+            output.value,
+            pubkey_pem_hash(&output.pubkey)
+        ));
+    }
+    write(format!("total,{total},"));
+}
@@ -0,0 +1,89 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::error::{BtcError, Result};
+
+// turns values into bytes and back, for the wire
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+// self-describing, what every file format uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        ciborium::into_writer(value, &mut bytes)
+            .map_err(|e| BtcError::Serialization(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        ciborium::from_reader(data).map_err(|e| BtcError::Serialization(e.to_string()))
+    }
+}
+
+// smaller and faster to encode, but both sides need the exact same types
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| BtcError::Serialization(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|e| BtcError::Serialization(e.to_string()))
+    }
+}
+
+// codec a node speaks on the wire, picked at startup
+// the node opening a connection writes in its format and the other side answers each frame
+// in the format it came in, so both peers agree without a separate handshake message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Cbor,
+    Bincode,
+}
+
+impl Codec for WireFormat {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Cbor => Cbor.encode(value),
+            WireFormat::Bincode => Bincode.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            WireFormat::Cbor => Cbor.decode(data),
+            WireFormat::Bincode => Bincode.decode(data),
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cbor" => Ok(WireFormat::Cbor),
+            "bincode" => Ok(WireFormat::Bincode),
+            _ => Err(format!("unknown wire format {s}, expected cbor or bincode")),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireFormat::Cbor => write!(f, "cbor"),
+            WireFormat::Bincode => write!(f, "bincode"),
+        }
+    }
+}
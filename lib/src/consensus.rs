@@ -0,0 +1,384 @@
+// the rules a block has to follow to join the chain, one function each
+// Blockchain::add_block and Block::verify apply them in order:
+// timestamp, bootstrap pace, proof of work, merkle root, coinbase, then signatures once inputs are resolved
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    U256,
+    crypto::PublicKey,
+    error::{BtcError, Result},
+    params::ChainParams,
+    sha256::Hash,
+    types::{Block, BlockHeader, TransactionInput, UtxoStore},
+    util::MerkleRoot,
+};
+
+// a block has to be newer than its parent
+pub fn check_timestamp(header: &BlockHeader, prev_header: &BlockHeader) -> Result<()> {
+    if header.timestamp <= prev_header.timestamp {
+        return Err(BtcError::InvalidBlock);
+    }
+
+    Ok(())
+}
+
+// until the first retarget a block has to keep the chain's bootstrap pace, and can't claim
+// a time ahead of ours to get around it
+pub fn check_bootstrap_pace(
+    header: &BlockHeader,
+    prev_header: &BlockHeader,
+    height: u64,
+    params: &ChainParams,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if height >= crate::DIFFICULTY_UPDATE_INTERVAL || params.bootstrap_block_spacing == 0 {
+        return Ok(());
+    }
+
+    let earliest = prev_header.timestamp + Duration::seconds(params.bootstrap_block_spacing as i64);
+    if header.timestamp < earliest {
+        return Err(BtcError::BlockTooSoon(earliest));
+    }
+
+    // a block from the future is fine once our clock catches up with it
+    let drift = Duration::seconds(crate::MAX_BOOTSTRAP_CLOCK_DRIFT as i64);
+    if header.timestamp > now + drift {
+        return Err(BtcError::BlockTooSoon(header.timestamp - drift));
+    }
+
+    Ok(())
+}
+
+// the target after a retarget window that started with first and ended with last
+pub fn retarget(target: U256, first: &BlockHeader, last: &BlockHeader) -> U256 {
+    let time_diff = (last.timestamp - first.timestamp).num_seconds();
+
+    // target_seconds represents the ideal duration to mine N blocks
+    let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
+
+    // a target moves at most 4x either way, bounding the time does the same and
+    // keeps clock skew (even a negative time_diff) from reaching the arithmetic
+    let time_diff =
+        U256::from_u64((time_diff.max(0) as u64).clamp(target_seconds / 4, target_seconds * 4));
+    let target_seconds = U256::from_u64(target_seconds);
+
+    // if time_diff is shorter than expected, mining is too fast, reduce target to make more difficult
+    // and vice versa
+    // target * time_diff / target_seconds, dividing first so only a result that doesn't fit
+    // in a U256 saturates, the remainder's share is small enough to multiply as is
+    let (quotient, remainder) = target.div_mod(target_seconds);
+    quotient
+        .saturating_mul(time_diff)
+        .saturating_add(remainder * time_diff / target_seconds)
+        // a zero target can never be met
+        .max(U256::one())
+        .min(crate::MIN_TARGET)
+}
+
+// the header claims the target the chain expects and its hash meets it
+pub fn check_pow(header: &BlockHeader, expected_target: U256) -> Result<()> {
+    if header.target != expected_target || !header.hash()?.matches_target(header.target) {
+        return Err(BtcError::InvalidBlock);
+    }
+
+    Ok(())
+}
+
+// the header commits to exactly the block's transactions
+pub fn check_merkle_root(block: &Block) -> Result<()> {
+    let merkle_root =
+        MerkleRoot::calculate(&block.transactions).ok_or(BtcError::InvalidMerkleRoot)?;
+    if merkle_root != block.header.merkle_root {
+        return Err(BtcError::InvalidMerkleRoot);
+    }
+
+    Ok(())
+}
+
+// the first transaction spends nothing, names the height and pays out the reward plus fees
+pub fn check_coinbase(block: &Block, height: u64, utxos: &dyn UtxoStore) -> Result<()> {
+    let Some(coinbase_transaction) = block.coinbase() else {
+        return Err(BtcError::InvalidBlock);
+    };
+
+    // coinbases create coins out of nothing, they spend no inputs
+    if !coinbase_transaction.inputs.is_empty() || coinbase_transaction.outputs.is_empty() {
+        return Err(BtcError::InvalidTransaction);
+    }
+
+    if coinbase_transaction.coinbase_height() != Some(height) {
+        return Err(BtcError::InvalidTransaction);
+    }
+
+    if coinbase_transaction
+        .tag
+        .as_ref()
+        .is_some_and(|tag| tag.len() as u64 > crate::MAX_COINBASE_TAG)
+    {
+        return Err(BtcError::InvalidTransaction);
+    }
+
+    let miner_fees = block.calculate_miner_fees(utxos)?;
+    let block_reward = block.calcualte_block_reward(height);
+    let total_coinbase_outputs: u64 = coinbase_transaction
+        .outputs
+        .iter()
+        .map(|output| output.value)
+        .sum();
+
+    if total_coinbase_outputs != block_reward + miner_fees {
+        return Err(BtcError::InvalidTransaction);
+    }
+
+    Ok(())
+}
+
+// every input signed its sighash with the key of the output it spends
+pub fn check_signatures(checks: &[(Hash, &TransactionInput, PublicKey)]) -> Result<()> {
+    if !signatures_valid(checks) {
+        return Err(BtcError::InvalidSignature);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "parallel"))]
+fn signatures_valid(checks: &[(Hash, &TransactionInput, PublicKey)]) -> bool {
+    checks
+        .iter()
+        .all(|(sighash, input, pubkey)| input.verify_signature(sighash, pubkey))
+}
+
+// signature verification dominates block validation, spread it over all cores
+#[cfg(feature = "parallel")]
+fn signatures_valid(checks: &[(Hash, &TransactionInput, PublicKey)]) -> bool {
+    use rayon::prelude::*;
+
+    checks
+        .par_iter()
+        .all(|(sighash, input, pubkey)| input.verify_signature(sighash, pubkey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::PrivateKey, types::Transaction};
+
+    // ideal duration of a retarget window
+    const WINDOW: i64 = (crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL) as i64;
+
+    fn header_at(seconds: i64) -> BlockHeader {
+        let coinbase = Transaction::coinbase(&PrivateKey::new_key().public_key(), 0, 0, None);
+        BlockHeader::new(
+            DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&[coinbase]).unwrap(),
+            U256::MAX,
+        )
+    }
+
+    fn retarget_over(target: U256, seconds: i64) -> U256 {
+        retarget(target, &header_at(0), &header_at(seconds))
+    }
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        header_at(seconds).timestamp
+    }
+
+    fn coinbase_block(coinbase: Transaction) -> Block {
+        let transactions = vec![coinbase];
+        let mut header = header_at(0);
+        header.merkle_root = MerkleRoot::calculate(&transactions).unwrap();
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn timestamps_have_to_move_forward() {
+        check_timestamp(&header_at(1), &header_at(0)).unwrap();
+        assert!(check_timestamp(&header_at(0), &header_at(0)).is_err());
+        assert!(check_timestamp(&header_at(-1), &header_at(0)).is_err());
+    }
+
+    #[test]
+    fn bootstrap_blocks_keep_the_pace() {
+        let params = ChainParams::mainnet();
+        let spacing = params.bootstrap_block_spacing as i64;
+        let prev = header_at(0);
+        let now = time(spacing);
+
+        check_bootstrap_pace(&header_at(spacing), &prev, 1, &params, now).unwrap();
+        assert!(matches!(
+            check_bootstrap_pace(&header_at(spacing - 1), &prev, 1, &params, now),
+            Err(BtcError::BlockTooSoon(earliest)) if earliest == time(spacing)
+        ));
+
+        // claiming a time ahead of ours doesn't get around it
+        let drift = crate::MAX_BOOTSTRAP_CLOCK_DRIFT as i64;
+        let ahead = header_at(spacing + drift + 1);
+        assert!(check_bootstrap_pace(&ahead, &prev, 1, &params, now).is_err());
+
+        // after the first retarget, or without a pace, anything newer goes
+        let interval = crate::DIFFICULTY_UPDATE_INTERVAL;
+        check_bootstrap_pace(&header_at(1), &prev, interval, &params, now).unwrap();
+        check_bootstrap_pace(&header_at(1), &prev, 1, &ChainParams::regtest(), now).unwrap();
+    }
+
+    #[test]
+    fn proof_of_work_has_to_meet_the_expected_target() {
+        let mut header = header_at(0);
+        check_pow(&header, U256::MAX).unwrap();
+        // claiming an easier target than the chain expects
+        assert!(check_pow(&header, crate::MIN_TARGET).is_err());
+
+        header.target = U256::zero();
+        assert!(check_pow(&header, U256::zero()).is_err());
+    }
+
+    #[test]
+    fn merkle_root_has_to_commit_to_the_transactions() {
+        let miner = PrivateKey::new_key().public_key();
+        let mut block = coinbase_block(Transaction::coinbase(&miner, 0, 0, None));
+        check_merkle_root(&block).unwrap();
+
+        block.transactions[0].outputs[0].value += 1;
+        assert!(matches!(
+            check_merkle_root(&block),
+            Err(BtcError::InvalidMerkleRoot)
+        ));
+
+        block.transactions.clear();
+        assert!(matches!(
+            check_merkle_root(&block),
+            Err(BtcError::InvalidMerkleRoot)
+        ));
+    }
+
+    #[test]
+    fn coinbase_has_to_be_well_formed() {
+        let blockchain = crate::types::Blockchain::new();
+        let utxos = blockchain.utxos();
+        let miner = PrivateKey::new_key().public_key();
+        let reward = Block::block_reward(0);
+
+        let tag = vec![0; crate::MAX_COINBASE_TAG as usize];
+        let block = coinbase_block(Transaction::coinbase(&miner, reward, 0, Some(tag)));
+        check_coinbase(&block, 0, utxos).unwrap();
+
+        let tag = vec![0; crate::MAX_COINBASE_TAG as usize + 1];
+        let block = coinbase_block(Transaction::coinbase(&miner, reward, 0, Some(tag)));
+        assert!(check_coinbase(&block, 0, utxos).is_err());
+
+        let mut block = coinbase_block(Transaction::coinbase(&miner, reward, 0, None));
+        block.transactions[0].outputs.clear();
+        assert!(check_coinbase(&block, 0, utxos).is_err());
+
+        block.transactions.clear();
+        assert!(matches!(
+            check_coinbase(&block, 0, utxos),
+            Err(BtcError::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn signatures_have_to_match_key_and_sighash() {
+        let mut key = PrivateKey::new_key();
+        let sighash = Hash::hash(&"sighash").unwrap();
+        let input = TransactionInput {
+            prev_transaction_output_hash: Hash::zero(),
+            signature: crate::crypto::Signature::sign_hash(&sighash, &mut key),
+        };
+
+        check_signatures(&[(sighash, &input, key.public_key())]).unwrap();
+        check_signatures(&[]).unwrap();
+
+        let stranger = PrivateKey::new_key().public_key();
+        let other_hash = Hash::hash(&"other").unwrap();
+        for check in [
+            (sighash, &input, stranger),
+            (other_hash, &input, key.public_key()),
+        ] {
+            assert!(matches!(
+                check_signatures(&[(sighash, &input, key.public_key()), check]),
+                Err(BtcError::InvalidSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn ideal_window_keeps_the_target() {
+        assert_eq!(
+            retarget_over(U256::from(1_000_000), WINDOW),
+            U256::from(1_000_000)
+        );
+    }
+
+    #[test]
+    fn target_moves_at_most_four_times_either_way() {
+        let target = U256::from(1_000_000);
+        assert_eq!(retarget_over(target, 1), U256::from(250_000));
+        assert_eq!(retarget_over(target, WINDOW * 100), U256::from(4_000_000));
+        // clocks running backwards count as the fastest window
+        assert_eq!(retarget_over(target, -WINDOW), U256::from(250_000));
+    }
+
+    #[test]
+    fn smallest_target_never_reaches_zero() {
+        assert_eq!(retarget_over(U256::one(), 1), U256::one());
+        assert_eq!(retarget_over(U256::from(3), 1), U256::one());
+        assert_eq!(retarget_over(U256::one(), WINDOW * 100), U256::from(4));
+    }
+
+    #[test]
+    fn largest_targets_saturate_at_the_minimum_difficulty() {
+        // U256::MAX * 4 would overflow
+        assert_eq!(retarget_over(U256::MAX, WINDOW * 100), crate::MIN_TARGET);
+        assert_eq!(retarget_over(U256::MAX - 1, WINDOW), crate::MIN_TARGET);
+        // even a quarter of U256::MAX is easier than the minimum difficulty
+        assert_eq!(retarget_over(U256::MAX, 1), crate::MIN_TARGET);
+        assert_eq!(
+            retarget_over(crate::MIN_TARGET, WINDOW * 100),
+            crate::MIN_TARGET
+        );
+        assert_eq!(
+            retarget_over(crate::MIN_TARGET / 2, WINDOW * 100),
+            crate::MIN_TARGET
+        );
+        assert_eq!(retarget_over(crate::MIN_TARGET, 1), crate::MIN_TARGET / 4);
+    }
+
+    // the retarget as it was before U256 arithmetic: scale by time_diff / WINDOW in
+    // decimal, then cut the digits after the point off the string
+    fn decimal_retarget(target: U256, seconds: i64) -> U256 {
+        // WINDOW is 2^2 * 5^3, so the ratio has at most three decimal places
+        let scaled = (target * U256::from(seconds * 1000 / WINDOW)).to_string();
+        let (whole, _fraction) = scaled.split_at(scaled.len() - 3);
+        U256::from_dec_str(whole).unwrap()
+    }
+
+    #[test]
+    fn retarget_matches_the_old_decimal_conversion() {
+        let targets = [
+            crate::MIN_TARGET / 4,
+            crate::MIN_TARGET / 7,
+            U256::from_dec_str("123456789012345678901234567890123456789").unwrap(),
+            U256::from(1_000_003),
+        ];
+        for target in targets {
+            for seconds in [
+                WINDOW / 4,
+                WINDOW / 3,
+                WINDOW - 1,
+                WINDOW,
+                WINDOW + 7,
+                WINDOW * 4,
+            ] {
+                assert_eq!(
+                    retarget_over(target, seconds),
+                    decimal_retarget(target, seconds),
+                    "target {target} over {seconds} seconds"
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{sha256::Hash, util::Saveable};
+use crate::{
+    error::{BtcError, Result},
+    sha256::Hash,
+    util::Saveable,
+};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use argon2::Argon2;
 use ecdsa::{
     Signature as ECDSASignature, SigningKey, VerifyingKey,
     signature::{SignerMut, Verifier, rand_core::OsRng},
@@ -6,22 +12,60 @@ use ecdsa::{
 use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
 use spki::{DecodePublicKey, EncodePublicKey};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+// prepended to signed messages so they can never pass as input signatures
+const MESSAGE_PREFIX: &[u8] = b"btc signed message:\n";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Signature(pub ECDSASignature<Secp256k1>);
 impl Signature {
-    pub fn sign_output(output_hash: &Hash, private_key: &mut PrivateKey) -> Self {
-        let signature = private_key.0.sign(&output_hash.as_bytes());
-        Signature(signature)
+    // sign a hash, for transaction inputs the sighash of Transaction::sighash
+    pub fn sign_hash(hash: &Hash, private_key: &mut PrivateKey) -> Self {
+        let signature: ECDSASignature<Secp256k1> = private_key.0.sign(&hash.as_bytes());
+        // s and -s both verify, always pick the low one so a spend has a single encoding
+        Signature(signature.normalize_s().unwrap_or(signature))
+    }
+
+    // high-s signatures are malleable copies of a low-s one
+    pub fn is_low_s(&self) -> bool {
+        self.0.normalize_s().is_none()
+    }
+
+    pub fn verify(&self, hash: &Hash, public_key: &PublicKey) -> bool {
+        if !self.is_low_s() {
+            return false;
+        }
+
+        public_key.0.verify(&hash.as_bytes(), &self.0).is_ok()
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
+    // message signatures follow the same low-s rule as input signatures
+    pub fn sign_message(message: &[u8], private_key: &mut PrivateKey) -> Self {
+        let signature: ECDSASignature<Secp256k1> = private_key.0.sign(&Self::prefixed(message));
+        Signature(signature.normalize_s().unwrap_or(signature))
+    }
+
+    pub fn verify_message(&self, message: &[u8], public_key: &PublicKey) -> bool {
+        if !self.is_low_s() {
+            return false;
+        }
+
         public_key
             .0
-            .verify(&output_hash.as_bytes(), &self.0)
+            .verify(&Self::prefixed(message), &self.0)
             .is_ok()
     }
+
+    fn prefixed(message: &[u8]) -> Vec<u8> {
+        [MESSAGE_PREFIX, message].concat()
+    }
 }
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct PublicKey(pub VerifyingKey<Secp256k1>);
@@ -43,7 +87,8 @@ impl Saveable for PublicKey {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// no Display and a redacted Debug, so the key never ends up in logs
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
 impl PrivateKey {
     pub fn new_key() -> Self {
@@ -53,6 +98,75 @@ impl PrivateKey {
     pub fn public_key(&self) -> PublicKey {
         PublicKey(*self.0.verifying_key())
     }
+
+    // the key encrypted under password, unlike save_to_file a stolen file doesn't give the coins away
+    pub fn save_encrypted<P: AsRef<Path>>(&self, path: P, password: &str) -> Result<()> {
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+
+        let mut plaintext = Zeroizing::new(vec![]);
+        ciborium::ser::into_writer(self, &mut *plaintext)
+            .map_err(|e| BtcError::Serialization(e.to_string()))?;
+        let ciphertext = key_cipher(password, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| BtcError::InvalidPrivateKey)?;
+
+        let encrypted = EncryptedKey {
+            salt,
+            nonce,
+            ciphertext,
+        };
+        // readable by the owner only, the password is all that's left protecting the key
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        ciborium::ser::into_writer(&encrypted, options.open(path)?)
+            .map_err(|e| BtcError::Serialization(e.to_string()))
+    }
+
+    // a key written by save_encrypted, WrongPassword if it doesn't decrypt
+    pub fn load_encrypted<P: AsRef<Path>>(path: P, password: &str) -> Result<Self> {
+        let encrypted: EncryptedKey = ciborium::de::from_reader(File::open(path)?)
+            .map_err(|e| BtcError::Serialization(e.to_string()))?;
+
+        // the tag fails to verify alike for a wrong password and a tampered file
+        let plaintext = Zeroizing::new(
+            key_cipher(password, &encrypted.salt)?
+                .decrypt(
+                    Nonce::from_slice(&encrypted.nonce),
+                    encrypted.ciphertext.as_slice(),
+                )
+                .map_err(|_| BtcError::WrongPassword)?,
+        );
+
+        ciborium::de::from_reader(plaintext.as_slice()).map_err(|_| BtcError::InvalidPrivateKey)
+    }
+}
+
+// a private key file written by save_encrypted, the key's cbor sealed with aes-256-gcm
+// under a key derived from the password and salt with argon2id
+#[derive(Serialize, Deserialize)]
+struct EncryptedKey {
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+// the derived key and the plaintext around it are wiped once dropped
+fn key_cipher(password: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut *key)
+        .map_err(|_| BtcError::InvalidPrivateKey)?;
+
+    Aes256Gcm::new_from_slice(&*key).map_err(|_| BtcError::InvalidPrivateKey)
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrivateKey(<redacted>)")
+    }
 }
 
 impl Default for PrivateKey {
@@ -61,8 +175,10 @@ impl Default for PrivateKey {
     }
 }
 
+// plaintext keys are still read and written for older files, see save_encrypted
 impl Saveable for PrivateKey {
     fn load<I: std::io::Read>(reader: I) -> IoResult<Self> {
+        println!("warning: loading an unencrypted private key");
         ciborium::de::from_reader(reader).map_err(|_| {
             IoError::new(
                 IoErrorKind::InvalidData,
@@ -72,6 +188,9 @@ impl Saveable for PrivateKey {
     }
 
     fn save<O: std::io::Write>(&self, writer: O) -> IoResult<()> {
+        println!(
+            "warning: saving the private key unencrypted, anyone who reads it can spend its coins"
+        );
         ciborium::ser::into_writer(self, writer).map_err(|_| {
             IoError::new(
                 IoErrorKind::InvalidData,
@@ -82,7 +201,8 @@ impl Saveable for PrivateKey {
 }
 
 mod signkey_serde {
-    use serde::Deserialize;
+    use serde::{Deserialize, de::Error};
+    use zeroize::{Zeroize, Zeroizing};
 
     pub fn serialize<S>(
         key: &super::SigningKey<super::Secp256k1>,
@@ -91,7 +211,10 @@ mod signkey_serde {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_bytes(&key.to_bytes())
+        let mut bytes = key.to_bytes();
+        let serialized = serializer.serialize_bytes(&bytes);
+        bytes.as_mut_slice().zeroize();
+        serialized
     }
 
     pub fn deserialize<'de, D>(
@@ -100,7 +223,126 @@ mod signkey_serde {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes = Vec::<u8>::deserialize(deserializer)?;
-        Ok(super::SigningKey::from_slice(&bytes).unwrap())
+        let bytes = Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        super::SigningKey::from_slice(&bytes)
+            .map_err(|_| D::Error::custom(super::BtcError::InvalidPrivateKey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a path in the temp dir nothing else uses, removed once the test is done with it
+    struct TempFile(std::path::PathBuf);
+    impl TempFile {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("btc-key-{}", uuid::Uuid::new_v4())))
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn encrypted_key_round_trips() {
+        let file = TempFile::new();
+        let key = PrivateKey::new_key();
+        key.save_encrypted(&file.0, "hunter2").unwrap();
+
+        let loaded = PrivateKey::load_encrypted(&file.0, "hunter2").unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+    }
+
+    #[test]
+    fn wrong_password_does_not_decrypt() {
+        let file = TempFile::new();
+        PrivateKey::new_key()
+            .save_encrypted(&file.0, "hunter2")
+            .unwrap();
+
+        assert!(matches!(
+            PrivateKey::load_encrypted(&file.0, "hunter3"),
+            Err(BtcError::WrongPassword)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encrypted_key_is_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = TempFile::new();
+        PrivateKey::new_key()
+            .save_encrypted(&file.0, "hunter2")
+            .unwrap();
+
+        let mode = std::fs::metadata(&file.0).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // the same signature with s negated, which verifies unless high-s is refused
+    fn high_s(signature: &Signature) -> Signature {
+        let (r, s) = signature.0.split_scalars();
+        Signature(ECDSASignature::from_scalars(r.to_bytes(), (-s).to_bytes()).unwrap())
+    }
+
+    #[test]
+    fn high_s_signatures_are_rejected() {
+        let mut key = PrivateKey::new_key();
+        let hash = Hash::hash(&"spend").unwrap();
+
+        let signature = Signature::sign_hash(&hash, &mut key);
+        let malleated = high_s(&signature);
+        assert!(signature.is_low_s());
+        assert!(!malleated.is_low_s());
+        assert!(signature.verify(&hash, &key.public_key()));
+        assert!(!malleated.verify(&hash, &key.public_key()));
+
+        // normalising the high-s copy gives back the signature that verifies
+        let normalized = Signature(malleated.0.normalize_s().unwrap());
+        assert!(normalized.verify(&hash, &key.public_key()));
+
+        let signature = Signature::sign_message(b"hello", &mut key);
+        assert!(signature.is_low_s());
+        assert!(signature.verify_message(b"hello", &key.public_key()));
+        assert!(!high_s(&signature).verify_message(b"hello", &key.public_key()));
+    }
+
+    #[test]
+    fn message_and_input_signatures_do_not_pass_for_each_other() {
+        let mut key = PrivateKey::new_key();
+        // a message made of exactly the bytes an input signature signs
+        let sighash = Hash::hash(&"spend").unwrap();
+        let message = sighash.as_bytes();
+
+        let message_signature = Signature::sign_message(&message, &mut key);
+        assert!(message_signature.verify_message(&message, &key.public_key()));
+        assert!(!message_signature.verify(&sighash, &key.public_key()));
+
+        let input_signature = Signature::sign_hash(&sighash, &mut key);
+        assert!(input_signature.verify(&sighash, &key.public_key()));
+        assert!(!input_signature.verify_message(&message, &key.public_key()));
+    }
+
+    #[test]
+    fn debug_output_leaves_out_the_key() {
+        let key = PrivateKey::new_key();
+        let debug = format!("{key:?}");
+
+        assert_eq!(debug, "PrivateKey(<redacted>)");
+        assert!(!debug.contains(&hex::encode(key.0.to_bytes())));
+        assert!(!format!("{:?}", Some(&key)).contains(&hex::encode(key.0.to_bytes())));
+    }
+
+    #[test]
+    fn invalid_key_bytes_are_an_error() {
+        // zero is not a valid secp256k1 scalar
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&ciborium::Value::Bytes(vec![0; 32]), &mut bytes).unwrap();
+
+        assert!(PrivateKey::load(bytes.as_slice()).is_err());
     }
 }
@@ -1,5 +1,8 @@
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
+use crate::sha256::Hash;
+
 #[derive(Error, Debug)]
 pub enum BtcError {
     #[error("Invalid transaction")]
@@ -22,6 +25,36 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid address")]
+    InvalidAddress,
+    #[error("Wrong password for the encrypted key")]
+    WrongPassword,
+    #[error("Target is zero, no hash can meet it")]
+    ZeroTarget,
+    #[error("Double spend of output {0}")]
+    DoubleSpend(Hash),
+    #[error("Output {0} already exists")]
+    DuplicateOutput(Hash),
+    #[error("Spent output {0} does not exist")]
+    UnknownOutput(Hash),
+    #[error("Coinbase output {0} is not mature yet")]
+    ImmatureCoinbase(Hash),
+    #[error("Transaction is locked until height {0}")]
+    TimeLocked(u64),
+    #[error("Outputs worth {outputs} exceed inputs worth {inputs}")]
+    Overspend { inputs: u64, outputs: u64 },
+    #[error("Fee {fee} is below the minimum relay fee {minimum}")]
+    FeeTooLow { fee: u64, minimum: u64 },
+    #[error("Block came too soon after its parent, wait until {0}")]
+    BlockTooSoon(DateTime<Utc>),
+    #[error("Mempool is disabled on this node")]
+    MempoolDisabled,
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+    #[error("Utxo store error: {0}")]
+    UtxoStore(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
@@ -17,18 +17,91 @@ pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
 // maximum transactions per block
 pub const BLOCK_TRANSACTION_CAP: u64 = 10;
+// maximum transactions returned when a client inspects the mempool
+pub const MAX_MEMPOOL_RESPONSE: u64 = 1_000;
+// maximum headers returned for a single headers request
+pub const MAX_HEADERS_RESPONSE: u64 = 2_000;
+// maximum signature verifications (transaction inputs) per block
+pub const MAX_BLOCK_SIGOPS: u64 = 1_000;
+// maximum inputs and outputs of a single transaction
+pub const MAX_TX_INPUTS: u64 = 500;
+pub const MAX_TX_OUTPUTS: u64 = 1_000;
+// largest message body in bytes read from a peer, checked before allocating for it
+pub const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+// maximum bytes a miner may tag its coinbase with
+pub const MAX_COINBASE_TAG: u64 = 100;
+// ideal block times without a new block before a node warns that the chain has stalled
+pub const STALL_BLOCK_TIMES: u64 = 30;
+// seconds a block before the first retarget may be timestamped ahead of our clock
+pub const MAX_BOOTSTRAP_CLOCK_DRIFT: u64 = 30;
 
+pub mod address;
+pub mod codec;
+pub mod consensus;
 pub mod crypto;
 pub mod error;
+pub mod mining;
 pub mod network;
+pub mod params;
 pub mod sha256;
+#[cfg(feature = "testutil")]
+pub mod testnet;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 pub mod types;
 pub mod util;
 
-use serde::{Deserialize, Serialize};
-use uint::construct_uint;
+pub use uint256::U256;
 
-construct_uint! {
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+// the generated impls trip clippy lints we can't fix at the source
+#[allow(clippy::manual_div_ceil)]
+mod uint256 {
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
+
+    construct_uint! {
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
+
+    impl U256 {
+        pub const fn from_u64(value: u64) -> Self {
+            U256([value, 0, 0, 0])
+        }
+
+        pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+            Self::from_big_endian(&bytes)
+        }
+
+        pub fn to_be_bytes(self) -> [u8; 32] {
+            self.to_big_endian()
+        }
+
+        pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+            Self::from_little_endian(&bytes)
+        }
+
+        pub fn to_le_bytes(self) -> [u8; 32] {
+            self.to_little_endian()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u256_bytes_round_trip_in_both_orders() {
+        let value = U256::from_dec_str("123456789012345678901234567890123456789").unwrap();
+        assert_eq!(U256::from_be_bytes(value.to_be_bytes()), value);
+        assert_eq!(U256::from_le_bytes(value.to_le_bytes()), value);
+
+        let mut reversed = value.to_be_bytes();
+        reversed.reverse();
+        assert_eq!(reversed, value.to_le_bytes());
+
+        assert_eq!(U256::from_u64(u64::MAX), U256::from(u64::MAX));
+        assert_eq!(U256::from_u64(1).to_be_bytes()[31], 1);
+    }
 }
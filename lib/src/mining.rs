@@ -0,0 +1,179 @@
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{U256, types::BlockHeader};
+
+// searches for a nonce that makes a header hash below a target
+// implementations may hash on the cpu, a gpu or hand the work off to external hardware
+pub trait MiningBackend: Send + Sync {
+    // a nonce in range whose header hash is at or below target, None if there isn't one
+    fn find_nonce(&self, header: &BlockHeader, target: U256, range: Range<u64>) -> Option<u64>;
+}
+
+// grinds through the range one hash at a time on the calling thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuBackend;
+
+impl MiningBackend for CpuBackend {
+    fn find_nonce(&self, header: &BlockHeader, target: U256, range: Range<u64>) -> Option<u64> {
+        let mut header = header.clone();
+        range.into_iter().find(|nonce| {
+            header.nonce = *nonce;
+            header.hash().is_ok_and(|hash| hash.matches_target(target))
+        })
+    }
+}
+
+// split a nonce range between threads, each part picking up where the previous one ends
+// so no nonce is searched twice, the first parts take one extra nonce when it doesn't divide
+pub fn partition_nonces(range: Range<u64>, parts: usize) -> Vec<Range<u64>> {
+    let parts = parts.max(1) as u64;
+    let len = range.end.saturating_sub(range.start);
+    let (size, extra) = (len / parts, len % parts);
+
+    let mut start = range.start;
+    let partition: Vec<Range<u64>> = (0..parts)
+        .map(|part| {
+            let end = start + size + u64::from(part < extra);
+            let part = start..end;
+            start = end;
+            part
+        })
+        .collect();
+
+    debug_assert!(
+        partition
+            .windows(2)
+            .all(|pair| pair[0].end == pair[1].start),
+        "nonce partition overlaps or leaves gaps"
+    );
+    debug_assert_eq!(
+        start,
+        range.start.max(range.end),
+        "nonce partition misses the range end"
+    );
+    partition
+}
+
+// nonces each mining thread has tried, for hashrate reporting
+#[derive(Debug, Default)]
+pub struct NonceCounters(Vec<AtomicU64>);
+
+impl NonceCounters {
+    pub fn new(threads: usize) -> Self {
+        Self((0..threads).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    pub fn record(&self, thread: usize, nonces: u64) {
+        if let Some(counter) = self.0.get(thread) {
+            counter.fetch_add(nonces, Ordering::Relaxed);
+        }
+    }
+
+    pub fn per_thread(&self) -> Vec<u64> {
+        self.0
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.per_thread().iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> BlockHeader {
+        let miner = crate::crypto::PrivateKey::new_key().public_key();
+        let coinbase = [crate::types::Transaction::coinbase(&miner, 0, 0, None)];
+        BlockHeader::new(
+            chrono::Utc::now(),
+            0,
+            crate::sha256::Hash::zero(),
+            crate::util::MerkleRoot::calculate(&coinbase).unwrap(),
+            U256::MAX,
+        )
+    }
+
+    #[test]
+    fn cpu_backend_finds_the_first_nonce_meeting_the_target() {
+        let header = header();
+        assert_eq!(CpuBackend.find_nonce(&header, U256::MAX, 10..20), Some(10));
+
+        // a little harder, the nonce found has to be the first that meets it
+        let target = U256::MAX >> 3;
+        let nonce = CpuBackend.find_nonce(&header, target, 0..1_000).unwrap();
+        let meets = |nonce| {
+            let mut header = header.clone();
+            header.nonce = nonce;
+            header.hash().unwrap().matches_target(target)
+        };
+        assert!(meets(nonce));
+        assert!((0..nonce).all(|nonce| !meets(nonce)));
+    }
+
+    #[test]
+    fn cpu_backend_gives_up_at_the_end_of_the_range() {
+        let header = header();
+        assert_eq!(CpuBackend.find_nonce(&header, U256::zero(), 0..100), None);
+        assert_eq!(CpuBackend.find_nonce(&header, U256::MAX, 5..5), None);
+    }
+
+    #[test]
+    fn partitions_cover_the_budget_without_overlap() {
+        for budget in [0, 1, 7, 1_000, 2_000_003] {
+            for threads in 1..=16 {
+                let start = 42;
+                let parts = partition_nonces(start..start + budget, threads);
+                assert_eq!(parts.len(), threads);
+
+                // sorted by start, so any overlap shows up between neighbours
+                let mut sorted = parts.clone();
+                sorted.sort_by_key(|part| part.start);
+                assert!(sorted.windows(2).all(|pair| pair[0].end <= pair[1].start));
+
+                let searched: u64 = parts.iter().map(|part| part.end - part.start).sum();
+                assert_eq!(searched, budget);
+                assert_eq!(sorted.first().unwrap().start, start);
+                assert_eq!(sorted.last().unwrap().end, start + budget);
+
+                // no thread gets more than one nonce over another
+                let sizes = parts.iter().map(|part| part.end - part.start);
+                assert!(sizes.clone().max().unwrap() - sizes.min().unwrap() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn partition_stops_at_the_end_of_the_nonce_space() {
+        let parts = partition_nonces(u64::MAX - 10..u64::MAX, 4);
+        assert_eq!(parts.last().unwrap().end, u64::MAX);
+        assert_eq!(
+            parts.iter().map(|part| part.end - part.start).sum::<u64>(),
+            10
+        );
+    }
+
+    #[test]
+    fn zero_threads_search_as_one() {
+        assert_eq!(partition_nonces(0..100, 0), vec![0..100]);
+    }
+
+    #[test]
+    fn counters_are_kept_per_thread() {
+        let counters = NonceCounters::new(3);
+        counters.record(0, 5);
+        counters.record(2, 7);
+        counters.record(2, 1);
+        // a thread the counters don't know about is ignored
+        counters.record(3, 100);
+
+        assert_eq!(counters.per_thread(), vec![5, 0, 8]);
+        assert_eq!(counters.total(), 13);
+    }
+}
@@ -1,12 +1,21 @@
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, Read, Write};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
+    codec::{Codec, WireFormat},
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    error::BtcError,
+    sha256::Hash,
+    types::{Block, BlockHeader, CompactBlock, Transaction, TransactionOutput, UtxoProof},
+    util::Saveable,
 };
 
+// set in a frame's length prefix when the body is bincode rather than cbor
+// replies carry the flag of the request, which is how peers agree on a format
+const BINCODE_FRAME: u64 = 1 << 63;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 // Messages exist in three forms:
 // 1. Request (1-1)
@@ -18,10 +27,48 @@ pub enum Message {
     // Response: List of UTXOs belonging to the public key, true if marked
     UTXOs(Vec<(TransactionOutput, bool)>),
 
+    // Request: Look up a single output by hash
+    GetUtxo(Hash),
+    // Response: The output and whether it is marked, None if it is spent or unknown
+    UtxoResponse(Option<(TransactionOutput, bool)>),
+
+    // Request: Hash of the utxo set, to compare nodes at the same height
+    GetUtxoCommitment,
+    // Response: The height and the utxo set hash there
+    UtxoCommitment {
+        height: u64,
+        commitment: Hash,
+    },
+
+    // Request: Push NewTransaction for mempool transactions paying or spending from the key
+    WatchAddress(PublicKey),
+    // Request: Stop pushing transactions for the key
+    Unwatch(PublicKey),
+
+    // Request: Scan every block for outputs to the public key and their spends
+    Rescan(PublicKey),
+    // Response: Unspent outputs found by the rescan and their total value
+    Rescanned {
+        utxos: Vec<TransactionOutput>,
+        balance: u64,
+    },
+
+    // Request: Transactions holding unspent outputs to the public key, with merkle proofs
+    FetchUtxoProofs(PublicKey),
+    // Response: The proven transactions, for light clients to check against their headers
+    UtxoProofs(Vec<UtxoProof>),
+
     // Request: Send a transaction to the network
     SubmitTransaction(Transaction),
     // Broadcast: A new transaction
     NewTransaction(Transaction),
+    // Broadcast: A mempool transaction was replaced by one spending the same utxos, so it won't confirm
+    ReplacedTransaction(Hash),
+
+    // Request: Send transactions that are only accepted together, parents before children
+    SubmitPackage(Vec<Transaction>),
+    // Broadcast: A new package of transactions
+    NewPackage(Vec<Transaction>),
 
     // Request: Node should prepate optimal block template with coinbase tx paying the public key
     FetchTemplate(PublicKey),
@@ -35,25 +82,118 @@ pub enum Message {
 
     // Request: Submit a mined block to the node
     SubmitTemplate(Block),
+    // Response: The submitted block is the new tip, Error if it was rejected
+    BlockAccepted(Hash),
+
+    // Request: Reply once the tip is no longer this hash, so a miner can drop its stale template
+    WaitForTip(Hash),
+    // Response to WaitForTip: NewBlock with the new tip
+
+    // Request: Fetch unconfirmed transactions, optionally only those paying the public key
+    FetchMempool(Option<PublicKey>),
+    // Response: Mempool transactions, highest fee first and bounded in count
+    MempoolContents(Vec<Transaction>),
 
     // Request: Ask for all nodes that a node is connected to
     DiscoverNodes,
+    // Request: The sender listens on this port, connect back to relay to it too
+    Announce(u16),
     // Request: List of nodes
     NodeList(Vec<String>),
 
     // Request: Ask for difference between self and target height
-    AskDifference(u32),
+    AskDifference(u64),
     // Request: Difference in height
-    Difference(i32),
+    Difference(i64),
+
+    // Request: Headers after the first locator hash the node shares with us, up to stop
+    FetchHeaders {
+        locator: Vec<Hash>,
+        stop: Option<Hash>,
+    },
+    // Response: Block headers in chain order
+    Headers(Vec<BlockHeader>),
+
+    // Request: Headers of the blocks with heights in from..to, for auditing the chain's work
+    FetchHeaderChain {
+        from: u64,
+        to: u64,
+    },
+    // Response: The headers in that range, capped in count
+    HeaderChain(Vec<BlockHeader>),
 
     // Reuest: Ask node to send a block with specific height
-    FetchBlock(usize),
+    FetchBlock(u64),
+    // Request: Ask node to send the block with this hash
+    FetchBlockByHash(Hash),
+    // Response: The requested block
+    Block(Block),
 
     // Broadcast: A new block
     NewBlock(Block),
+
+    // Broadcast: A new block as header and short transaction ids
+    CompactBlock(CompactBlock),
+    // Request: Transactions of the block with this header hash, by short id
+    GetData(Hash, Vec<u64>),
+    // Response: The requested block transactions
+    BlockTransactions(Vec<Transaction>),
+
+    // Response: The request failed, code is one of the ERROR_ constants
+    Error {
+        code: u32,
+        message: String,
+    },
 }
 
+// codes of Message::Error, stable so clients can tell rejections apart
+pub const ERROR_INVALID_TRANSACTION: u32 = 1;
+pub const ERROR_DOUBLE_SPEND: u32 = 2;
+pub const ERROR_UNKNOWN_OUTPUT: u32 = 3;
+pub const ERROR_FEE_TOO_LOW: u32 = 4;
+pub const ERROR_IMMATURE_COINBASE: u32 = 5;
+pub const ERROR_TIME_LOCKED: u32 = 6;
+pub const ERROR_INVALID_BLOCK: u32 = 7;
+pub const ERROR_NOT_FOUND: u32 = 8;
+pub const ERROR_MEMPOOL_DISABLED: u32 = 9;
+pub const ERROR_INVALID_REQUEST: u32 = 10;
+pub const ERROR_INTERNAL: u32 = 11;
+
 impl Message {
+    // the Error reply to a request that failed with e
+    pub fn error(e: &BtcError) -> Self {
+        let code = match e {
+            BtcError::InvalidTransaction
+            | BtcError::InvalidTransactionInput
+            | BtcError::InvalidTransactionOutput
+            | BtcError::InvalidSignature
+            | BtcError::DuplicateOutput(_)
+            | BtcError::Overspend { .. } => ERROR_INVALID_TRANSACTION,
+            BtcError::DoubleSpend(_) => ERROR_DOUBLE_SPEND,
+            BtcError::UnknownOutput(_) => ERROR_UNKNOWN_OUTPUT,
+            BtcError::FeeTooLow { .. } => ERROR_FEE_TOO_LOW,
+            BtcError::ImmatureCoinbase(_) => ERROR_IMMATURE_COINBASE,
+            BtcError::TimeLocked(_) => ERROR_TIME_LOCKED,
+            BtcError::InvalidBlock
+            | BtcError::InvalidBlockHeader
+            | BtcError::InvalidMerkleRoot
+            | BtcError::InvalidHash
+            | BtcError::ZeroTarget
+            | BtcError::BlockTooSoon(_) => ERROR_INVALID_BLOCK,
+            BtcError::MempoolDisabled => ERROR_MEMPOOL_DISABLED,
+            BtcError::InvalidPublicKey
+            | BtcError::InvalidPrivateKey
+            | BtcError::InvalidAddress
+            | BtcError::WrongPassword => ERROR_INVALID_REQUEST,
+            BtcError::Serialization(_) | BtcError::UtxoStore(_) | BtcError::Io(_) => ERROR_INTERNAL,
+        };
+
+        Message::Error {
+            code,
+            message: e.to_string(),
+        }
+    }
+
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
         ciborium::into_writer(self, &mut bytes)?;
@@ -75,12 +215,18 @@ impl Message {
         Ok(())
     }
 
-    pub fn receive(&self, stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
+    pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut length_bytes = [0u8; 8];
         stream.read_exact(&mut length_bytes)?;
-        let length = u64::from_be_bytes(length_bytes) as usize;
+        let length = u64::from_be_bytes(length_bytes);
+        if length > crate::MAX_MESSAGE_SIZE {
+            return Err(ciborium::de::Error::Io(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("message of {length} bytes is over the size limit"),
+            )));
+        }
 
-        let mut data = vec![0u8; length];
+        let mut data = vec![0u8; length as usize];
         stream.read_exact(&mut data)?;
 
         Self::decode(&data)
@@ -90,8 +236,22 @@ impl Message {
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
     ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let length = bytes.len() as u64;
+        Ok(self.send_async_with(stream, WireFormat::Cbor).await?)
+    }
+
+    // the frame is flagged with its format, so the receiver can answer in kind
+    pub async fn send_async_with(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        format: WireFormat,
+    ) -> std::io::Result<()> {
+        let bytes = format
+            .encode(self)
+            .map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+        let mut length = bytes.len() as u64;
+        if format == WireFormat::Bincode {
+            length |= BINCODE_FRAME;
+        }
 
         stream.write_all(&length.to_be_bytes()).await?;
         stream.write_all(&bytes).await?;
@@ -101,13 +261,218 @@ impl Message {
 
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
+    ) -> Result<Self, ReceiveError> {
+        Ok(Self::receive_async_with_format(stream).await?.0)
+    }
+
+    // a message in either format, along with the format the peer used
+    pub async fn receive_async_with_format(
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<(Self, WireFormat), ReceiveError> {
         let mut length_bytes = [0u8; 8];
-        stream.read_exact(&mut length_bytes).await?;
-        let length = u64::from_be_bytes(length_bytes) as usize;
+        let mut filled = 0;
+        while filled < length_bytes.len() {
+            match stream.read(&mut length_bytes[filled..]).await? {
+                // nothing read at a frame boundary means the peer hung up between messages
+                0 if filled == 0 => return Err(ReceiveError::Closed),
+                0 => return Err(ReceiveError::Truncated),
+                read => filled += read,
+            }
+        }
+        let length = u64::from_be_bytes(length_bytes);
+        let format = if length & BINCODE_FRAME != 0 {
+            WireFormat::Bincode
+        } else {
+            WireFormat::Cbor
+        };
 
-        let mut data = vec![0u8; length];
-        stream.read_exact(&mut data).await?;
-        Self::decode(&data)
+        // the length is the peer's word, don't allocate for it unchecked
+        let length = length & !BINCODE_FRAME;
+        if length > crate::MAX_MESSAGE_SIZE {
+            return Err(ReceiveError::Oversized(length));
+        }
+
+        let mut data = vec![0u8; length as usize];
+        stream
+            .read_exact(&mut data)
+            .await
+            .map_err(|e| match e.kind() {
+                IoErrorKind::UnexpectedEof => ReceiveError::Truncated,
+                _ => ReceiveError::Io(e),
+            })?;
+        let message = format.decode(&data).map_err(ReceiveError::Malformed)?;
+
+        Ok((message, format))
+    }
+}
+
+// why receiving a message failed, a clean close is not the peer's fault the others may be
+#[derive(Error, Debug)]
+pub enum ReceiveError {
+    #[error("Connection closed")]
+    Closed,
+    #[error("Connection closed in the middle of a message")]
+    Truncated,
+    #[error("I/O error: {0}")]
+    Io(#[from] IoError),
+    #[error("Malformed message: {0}")]
+    Malformed(BtcError),
+    #[error("Message of {0} bytes is over the size limit")]
+    Oversized(u64),
+}
+
+// addresses of peers seen in earlier runs, so a restarted node can find them again
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PeerBook(pub Vec<String>);
+
+impl Saveable for PeerBook {
+    fn load<I: Read>(reader: I) -> std::io::Result<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialise peer book"))
+    }
+    fn save<O: Write>(&self, writer: O) -> std::io::Result<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialise peer book"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::PrivateKey, types::UnsignedTransaction, util::MerkleRoot};
+
+    // a frame header announcing length bytes, with nothing after it
+    fn frame_of(length: u64) -> Vec<u8> {
+        length.to_be_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_reading_them() {
+        // allocating either of these would abort the process
+        for length in [1 << 62, (1 << 62) | BINCODE_FRAME] {
+            let frame = frame_of(length);
+            assert!(matches!(
+                Message::receive_async_with_format(&mut frame.as_slice()).await,
+                Err(ReceiveError::Oversized(size)) if size == 1 << 62
+            ));
+        }
+
+        let frame = frame_of(crate::MAX_MESSAGE_SIZE + 1);
+        assert!(Message::receive(&mut frame.as_slice()).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_up_to_the_limit_are_read() {
+        for format in [WireFormat::Cbor, WireFormat::Bincode] {
+            let mut frame = vec![];
+            Message::DiscoverNodes
+                .send_async_with(&mut frame, format)
+                .await
+                .unwrap();
+
+            let (message, received_format) =
+                Message::receive_async_with_format(&mut frame.as_slice())
+                    .await
+                    .unwrap();
+            assert!(matches!(message, Message::DiscoverNodes));
+            assert_eq!(received_format, format);
+        }
+
+        // announcing the largest allowed size gets past the check, then runs out of bytes
+        let frame = frame_of(crate::MAX_MESSAGE_SIZE);
+        assert!(matches!(
+            Message::receive_async_with_format(&mut frame.as_slice()).await,
+            Err(ReceiveError::Truncated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn receive_errors_tell_a_clean_close_from_a_broken_frame() {
+        let mut frame = vec![];
+        Message::DiscoverNodes.send_async(&mut frame).await.unwrap();
+
+        // hanging up between messages is a clean close
+        let mut stream = frame.as_slice();
+        assert!(Message::receive_async(&mut stream).await.is_ok());
+        assert!(matches!(
+            Message::receive_async(&mut stream).await,
+            Err(ReceiveError::Closed)
+        ));
+
+        // hanging up in the length or in the body is not
+        for cut in [3, frame.len() - 1] {
+            assert!(matches!(
+                Message::receive_async(&mut &frame[..cut]).await,
+                Err(ReceiveError::Truncated)
+            ));
+        }
+
+        // a complete frame whose body is not a message
+        let mut garbage = 4u64.to_be_bytes().to_vec();
+        garbage.extend([0xff; 4]);
+        assert!(matches!(
+            Message::receive_async(&mut garbage.as_slice()).await,
+            Err(ReceiveError::Malformed(_))
+        ));
+    }
+
+    // a coinbase with every optional field set, and a spend of it with a locktime
+    fn block_and_spend() -> (Block, Transaction) {
+        let mut key = PrivateKey::new_key();
+        let coinbase = Transaction::coinbase(&key.public_key(), 50, 3, Some(b"tag".to_vec()));
+        let spend = UnsignedTransaction {
+            inputs: coinbase.outputs.clone(),
+            outputs: coinbase.outputs.clone(),
+            locktime: Some(7),
+        }
+        .sign(&mut key)
+        .unwrap();
+
+        let transactions = vec![coinbase, spend.clone()];
+        let block = Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                42,
+                Hash::hash(&"parent").unwrap(),
+                MerkleRoot::calculate(&transactions).unwrap(),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        );
+        (block, spend)
+    }
+
+    #[tokio::test]
+    async fn blocks_and_transactions_round_trip_through_both_formats() {
+        let (block, spend) = block_and_spend();
+
+        for format in [WireFormat::Cbor, WireFormat::Bincode] {
+            let mut frames = vec![];
+            for message in [
+                Message::NewBlock(block.clone()),
+                Message::NewTransaction(spend.clone()),
+            ] {
+                message.send_async_with(&mut frames, format).await.unwrap();
+            }
+
+            let mut frames = frames.as_slice();
+            let Message::NewBlock(received) = Message::receive_async(&mut frames).await.unwrap()
+            else {
+                panic!("expected the block back");
+            };
+            assert_eq!(received.hash().unwrap(), block.hash().unwrap());
+            assert_eq!(received.header.target, crate::MIN_TARGET);
+            assert_eq!(received.transactions[0].coinbase_height(), Some(3));
+            assert_eq!(received.transactions[0].tag.as_deref(), Some(&b"tag"[..]));
+
+            let Message::NewTransaction(received) =
+                Message::receive_async(&mut frames).await.unwrap()
+            else {
+                panic!("expected the transaction back");
+            };
+            assert_eq!(received.hash().unwrap(), spend.hash().unwrap());
+            assert_eq!(received.locktime, Some(7));
+            assert_eq!(received.height, None);
+        }
     }
 }
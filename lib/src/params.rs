@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+use crate::U256;
+
+// network specific settings, so regtest can differ from mainnet
+// fields missing from older saved chains take their mainnet values
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChainParams {
+    // human-readable prefix of bech32m addresses on this chain
+    pub address_hrp: String,
+    // blocks that must be built on a coinbase before its outputs can be spent
+    pub coinbase_maturity: u64,
+    // change below this value in satoshis is dust, left to the miner as fee instead
+    pub dust_threshold: u64,
+    // lowest fee in satoshis the mempool accepts a transaction with
+    pub min_relay_fee: u64,
+    // maximum transactions per block, coinbase included
+    pub max_block_transactions: u64,
+    // maximum inputs and outputs of a single transaction
+    pub max_tx_inputs: u64,
+    pub max_tx_outputs: u64,
+    // deepest reorganisation a node will follow
+    pub max_reorg_depth: u64,
+    // target of the first blocks, until the first retarget
+    // retargets never go easier than MIN_TARGET, whatever this is
+    pub starting_target: U256,
+    // seconds between a block and its parent until the first retarget, 0 to allow any pace
+    // the starting target doesn't move before then, so this keeps a new chain from being
+    // mined out in a burst at trivial difficulty
+    pub bootstrap_block_spacing: u64,
+    // archival nodes turn the mempool off, they never relay transactions
+    pub mempool_enabled: bool,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        Self {
+            address_hrp: "btc".to_string(),
+            coinbase_maturity: 100,
+            dust_threshold: 546,
+            min_relay_fee: 1_000,
+            max_block_transactions: crate::BLOCK_TRANSACTION_CAP,
+            max_tx_inputs: crate::MAX_TX_INPUTS,
+            max_tx_outputs: crate::MAX_TX_OUTPUTS,
+            max_reorg_depth: 100,
+            starting_target: crate::MIN_TARGET,
+            bootstrap_block_spacing: crate::IDEAL_BLOCK_TIME / 2,
+            mempool_enabled: true,
+        }
+    }
+
+    pub fn regtest() -> Self {
+        // relaxed so tests can spend and relay anything right away
+        Self {
+            address_hrp: "rtc".to_string(),
+            coinbase_maturity: 0,
+            dust_threshold: 0,
+            min_relay_fee: 0,
+            max_block_transactions: crate::BLOCK_TRANSACTION_CAP,
+            max_tx_inputs: crate::MAX_TX_INPUTS,
+            max_tx_outputs: crate::MAX_TX_OUTPUTS,
+            max_reorg_depth: 1_000,
+            // any hash will do, blocks are mined instantly
+            starting_target: U256::MAX,
+            bootstrap_block_spacing: 0,
+            mempool_enabled: true,
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
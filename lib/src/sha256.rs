@@ -1,7 +1,7 @@
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
-use sha256::digest;
+use sha2::{Digest, Sha256};
 
 use crate::{
     U256,
@@ -11,24 +11,19 @@ use crate::{
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub struct Hash(U256);
 impl Hash {
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Result<Self> {
-        let mut serialized: Vec<u8> = vec![];
-
-        if let Err(e) = ciborium::into_writer(data, &mut serialized) {
-            panic!("Failed to serialise data: {e:?}")
-        }
+        Ok(Hash(U256::from_le_bytes(Self::hash_bytes(data)?)))
+    }
 
-        let hash = digest(&serialized);
-        let Ok(hash_bytes) = hex::decode(hash) else {
-            return Err(BtcError::InvalidHash);
-        };
+    // raw sha256 digest of the cbor encoding of data
+    pub fn hash_bytes<T: serde::Serialize>(data: &T) -> Result<[u8; 32]> {
+        let mut serialized: Vec<u8> = vec![];
 
-        let hash_array: [u8; 32] = hash_bytes
-            .as_slice()
-            .try_into()
-            .map_err(|_| BtcError::InvalidHash)?;
+        ciborium::into_writer(data, &mut serialized)
+            .map_err(|e| BtcError::Serialization(e.to_string()))?;
 
-        Ok(Hash(U256::from_little_endian(&hash_array)))
+        Ok(Sha256::digest(&serialized).into())
     }
 
     pub fn matches_target(&self, target: U256) -> bool {
@@ -39,8 +34,12 @@ impl Hash {
         Hash(U256::zero())
     }
 
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_le_bytes(bytes))
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
-        self.0.to_little_endian()
+        self.0.to_le_bytes()
     }
 }
 
@@ -49,3 +48,31 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a value whose serialization always fails
+    struct Unserializable;
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> std::result::Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not today"))
+        }
+    }
+
+    #[test]
+    fn unserializable_data_is_an_error() {
+        assert!(matches!(
+            Hash::hash(&Unserializable),
+            Err(BtcError::Serialization(message)) if message.contains("not today")
+        ));
+    }
+
+    #[test]
+    fn hash_is_the_digest_of_the_cbor_encoding() {
+        let hash = Hash::hash(&"btc").unwrap();
+        assert_eq!(hash, Hash::from_bytes(Hash::hash_bytes(&"btc").unwrap()));
+        assert_ne!(hash, Hash::hash(&"btd").unwrap());
+    }
+}
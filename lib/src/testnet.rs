@@ -0,0 +1,244 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{Error as IoError, Result as IoResult},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{crypto::PublicKey, network::Message, sha256::Hash, types::Transaction};
+
+// how long a node gets to start listening
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+// pause between polls while waiting on nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// nonces tried between target checks while mining
+const MINE_STEPS: usize = 1_000;
+
+// regtest nodes running as child processes on free local ports, for tests of gossip and sync
+// each node is started with the earlier ones as peers and announces itself back, so every
+// node relays to every other one
+//
+// the node binary is BTC_NODE_BIN if set, otherwise the workspace's debug build
+pub struct TestNetwork {
+    nodes: Vec<TestNode>,
+}
+
+impl TestNetwork {
+    pub fn start(count: usize) -> IoResult<Self> {
+        Self::start_with(default_binary(), count)
+    }
+
+    // like start, running the given node binary, e.g. CARGO_BIN_EXE_node in the node's own tests
+    pub fn start_with(binary: impl AsRef<Path>, count: usize) -> IoResult<Self> {
+        let mut nodes: Vec<TestNode> = vec![];
+        for _ in 0..count {
+            let peers: Vec<String> = nodes.iter().map(TestNode::address).collect();
+            nodes.push(TestNode::start_with(binary.as_ref(), &peers, &[])?);
+        }
+
+        // wait for the announcements, so a block mined anywhere reaches everyone
+        let network = Self { nodes };
+        network.wait_until(START_TIMEOUT, |network| {
+            Ok(network
+                .nodes
+                .iter()
+                .map(|node| node.peers())
+                .collect::<IoResult<Vec<_>>>()?
+                .iter()
+                .all(|peers| peers.len() + 1 >= count))
+        })?;
+
+        Ok(network)
+    }
+
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    // the tip every node agrees on, once they do
+    pub fn wait_for_convergence(&self, timeout: Duration) -> IoResult<Option<Hash>> {
+        self.wait_until(timeout, |network| {
+            let tips = network
+                .nodes
+                .iter()
+                .map(TestNode::tip)
+                .collect::<IoResult<Vec<_>>>()?;
+            Ok(tips.windows(2).all(|pair| pair[0] == pair[1]))
+        })?;
+
+        self.nodes[0].tip()
+    }
+
+    fn wait_until(
+        &self,
+        timeout: Duration,
+        mut done: impl FnMut(&Self) -> IoResult<bool>,
+    ) -> IoResult<()> {
+        let start = Instant::now();
+        while !done(self)? {
+            if start.elapsed() > timeout {
+                return Err(IoError::other("test network did not settle in time"));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+// a node process, killed and its data directory removed when dropped
+pub struct TestNode {
+    child: Child,
+    port: u16,
+    datadir: PathBuf,
+}
+
+impl TestNode {
+    pub fn start(peers: &[String]) -> IoResult<Self> {
+        Self::start_with(&default_binary(), peers, &[])
+    }
+
+    // like start, running the given node binary with extra arguments such as --mine
+    pub fn start_with(binary: &Path, peers: &[String], args: &[&str]) -> IoResult<Self> {
+        // the os picks a free port, the node takes it over right after
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let datadir = env::temp_dir().join(format!("btc-testnet-{}-{port}", std::process::id()));
+        fs::create_dir_all(&datadir)?;
+        let log = File::create(datadir.join("node.log"))?;
+
+        let child = Command::new(binary)
+            .arg("--regtest")
+            .args(["--port", &port.to_string()])
+            .arg("--datadir")
+            .arg(&datadir)
+            .args(args)
+            .args(peers)
+            .stdout(Stdio::from(log.try_clone()?))
+            .stderr(Stdio::from(log))
+            .spawn()?;
+
+        let node = Self {
+            child,
+            port,
+            datadir,
+        };
+        let start = Instant::now();
+        while TcpStream::connect(node.address()).is_err() {
+            if start.elapsed() > START_TIMEOUT {
+                return Err(IoError::other(format!("node on port {port} did not start")));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(node)
+    }
+
+    pub fn address(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    // send a request on a fresh connection and wait for the reply
+    pub fn request(&self, message: &Message) -> IoResult<Message> {
+        let mut stream = TcpStream::connect(self.address())?;
+        message.send(&mut stream).map_err(IoError::other)?;
+        Message::receive(&mut stream).map_err(IoError::other)
+    }
+
+    // send a message that has no reply
+    pub fn send(&self, message: &Message) -> IoResult<()> {
+        let mut stream = TcpStream::connect(self.address())?;
+        message.send(&mut stream).map_err(IoError::other)
+    }
+
+    pub fn submit_transaction(&self, transaction: Transaction) -> IoResult<()> {
+        self.send(&Message::SubmitTransaction(transaction))
+    }
+
+    // mine the node's template paying miner and submit it, returns the accepted block's hash
+    pub fn mine_block(&self, miner: &PublicKey) -> IoResult<Hash> {
+        let mut block = match self.request(&Message::FetchTemplate(miner.clone()))? {
+            Message::Template(block) => block,
+            m => {
+                return Err(IoError::other(format!(
+                    "unexpected reply to template: {m:?}"
+                )));
+            }
+        };
+        while !block.header.mine(MINE_STEPS).map_err(IoError::other)? {}
+
+        match self.request(&Message::SubmitTemplate(block))? {
+            Message::BlockAccepted(hash) => Ok(hash),
+            m => Err(IoError::other(format!("block not accepted: {m:?}"))),
+        }
+    }
+
+    pub fn height(&self) -> IoResult<u64> {
+        match self.request(&Message::AskDifference(0))? {
+            Message::Difference(height) => u64::try_from(height).map_err(IoError::other),
+            m => Err(IoError::other(format!("unexpected reply to height: {m:?}"))),
+        }
+    }
+
+    // poll until the node's chain is at least height blocks long
+    pub fn wait_for_height(&self, height: u64, timeout: Duration) -> IoResult<()> {
+        let start = Instant::now();
+        while self.height()? < height {
+            if start.elapsed() > timeout {
+                return Err(IoError::other(format!(
+                    "node on port {} did not reach height {height} in time",
+                    self.port
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    // hash of the node's tip block, None on an empty chain
+    pub fn tip(&self) -> IoResult<Option<Hash>> {
+        let height = self.height()?;
+        if height == 0 {
+            return Ok(None);
+        }
+
+        match self.request(&Message::FetchBlock(height - 1))? {
+            Message::NewBlock(block) => block.hash().map(Some).map_err(IoError::other),
+            m => Err(IoError::other(format!("unexpected reply to tip: {m:?}"))),
+        }
+    }
+
+    // nodes this one relays to
+    pub fn peers(&self) -> IoResult<Vec<String>> {
+        match self.request(&Message::DiscoverNodes)? {
+            Message::NodeList(nodes) => Ok(nodes),
+            m => Err(IoError::other(format!("unexpected reply to peers: {m:?}"))),
+        }
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.datadir);
+    }
+}
+
+fn default_binary() -> PathBuf {
+    env::var_os("BTC_NODE_BIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../target/debug/node"))
+}
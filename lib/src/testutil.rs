@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, Utc};
+use ecdsa::SigningKey;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use uuid::Uuid;
+
+use crate::{
+    crypto::{PrivateKey, PublicKey},
+    error::{BtcError, Result},
+    params::ChainParams,
+    sha256::Hash,
+    types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput, UnsignedTransaction},
+    util::MerkleRoot,
+};
+
+// timestamp of the first block a builder mines
+const GENESIS_TIME: i64 = 1_700_000_000;
+// nonces tried between target checks while mining
+const MINE_STEPS: usize = 1_000;
+
+// builds regtest chains for tests, everything including keys and output ids comes
+// from the seed, so the same calls with the same seed give the same chain
+//
+// keys are referred to by index and created on first use, key 0 mines by default
+// spends wait for the next mined block, those still waiting at build end up in the mempool
+pub struct ChainBuilder {
+    rng: StdRng,
+    blockchain: Blockchain,
+    keys: Vec<PrivateKey>,
+    pending: Vec<Transaction>,
+    // outputs spent by pending transactions
+    pending_spent: HashSet<Hash>,
+}
+
+impl ChainBuilder {
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            blockchain: Blockchain::with_params(ChainParams::regtest()),
+            keys: vec![],
+            pending: vec![],
+            pending_spent: HashSet::new(),
+        }
+    }
+
+    pub fn private_key(&mut self, index: usize) -> PrivateKey {
+        while self.keys.len() <= index {
+            let key = loop {
+                // almost every 32 bytes are a valid scalar, retry the few that aren't
+                let bytes: [u8; 32] = self.rng.random();
+                if let Ok(key) = SigningKey::from_slice(&bytes) {
+                    break key;
+                }
+            };
+            self.keys.push(PrivateKey(key));
+        }
+        self.keys[index].clone()
+    }
+
+    pub fn public_key(&mut self, index: usize) -> PublicKey {
+        self.private_key(index).public_key()
+    }
+
+    // mine count blocks paying key 0
+    pub fn mine(self, count: usize) -> Self {
+        self.mine_to(0, count)
+    }
+
+    // mine count blocks paying the key, pending spends go into the first one
+    pub fn mine_to(mut self, key: usize, count: usize) -> Self {
+        for _ in 0..count {
+            self.try_mine_block(key)
+                .expect("builder mined an invalid block");
+        }
+        self
+    }
+
+    // pay value from one key to another, change goes back to the sender
+    pub fn spend(mut self, from: usize, to: usize, value: u64) -> Self {
+        self.try_spend(from, to, value)
+            .expect("sender can't cover the spend");
+        self
+    }
+
+    pub fn build(self) -> Blockchain {
+        self.build_with_keys().0
+    }
+
+    // the chain and every key the builder created, by index
+    pub fn build_with_keys(mut self) -> (Blockchain, Vec<PrivateKey>) {
+        for transaction in self.pending.drain(..) {
+            self.blockchain
+                .add_to_mempool(transaction)
+                .expect("pending spend rejected by the mempool");
+        }
+        (self.blockchain, self.keys)
+    }
+
+    fn try_mine_block(&mut self, key: usize) -> Result<()> {
+        let miner = self.public_key(key);
+        let spends: Vec<Transaction> = self.pending.drain(..).collect();
+        self.pending_spent.clear();
+
+        let unique_id = self.unique_id();
+        mine_block(&mut self.blockchain, &miner, spends, unique_id)
+    }
+
+    fn try_spend(&mut self, from: usize, to: usize, value: u64) -> Result<()> {
+        let mut sender = self.private_key(from);
+        let sender_pubkey = sender.public_key();
+        let recipient = self.public_key(to);
+
+        // sorted by hash so selection doesn't depend on map order
+        let mut available: Vec<(Hash, TransactionOutput)> = self
+            .blockchain
+            .utxos()
+            .iter_for_pubkey(&sender_pubkey)?
+            .filter(|(hash, (marked, _))| !marked && !self.pending_spent.contains(hash))
+            .map(|(hash, (_, output))| (hash, output))
+            .collect();
+        available.sort_by_key(|(hash, _)| hash.as_bytes());
+
+        let mut inputs = vec![];
+        let mut input_value = 0;
+        for (hash, output) in available {
+            if input_value >= value {
+                break;
+            }
+            input_value += output.value;
+            inputs.push((hash, output));
+        }
+        if input_value < value {
+            return Err(BtcError::InvalidTransaction);
+        }
+
+        let mut outputs = vec![TransactionOutput {
+            value,
+            unique_id: self.unique_id(),
+            pubkey: recipient,
+        }];
+        if input_value > value {
+            outputs.push(TransactionOutput {
+                value: input_value - value,
+                unique_id: self.unique_id(),
+                pubkey: sender_pubkey,
+            });
+        }
+
+        for (hash, _) in &inputs {
+            self.pending_spent.insert(*hash);
+        }
+        let transaction = UnsignedTransaction {
+            inputs: inputs.into_iter().map(|(_, output)| output).collect(),
+            outputs,
+            locktime: None,
+        }
+        .sign(&mut sender)?;
+        self.pending.push(transaction);
+
+        Ok(())
+    }
+
+    fn unique_id(&mut self) -> Uuid {
+        uuid::Builder::from_random_bytes(self.rng.random()).into_uuid()
+    }
+}
+
+impl Default for ChainBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Blockchain {
+    // a regtest chain of the given number of blocks whose rewards go to the keys in turn,
+    // starting with the first at genesis, for tests that just need funded keys
+    pub fn new_test_chain(keys: &[PublicKey], blocks: u64) -> Blockchain {
+        assert!(!keys.is_empty(), "a test chain needs keys to pay");
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut blockchain = Blockchain::with_params(ChainParams::regtest());
+        for height in 0..blocks {
+            let miner = &keys[(height % keys.len() as u64) as usize];
+            let unique_id = uuid::Builder::from_random_bytes(rng.random()).into_uuid();
+            mine_block(&mut blockchain, miner, vec![], unique_id)
+                .expect("test chain mined an invalid block");
+        }
+        blockchain
+    }
+}
+
+// mine the spends into a block on the tip paying the reward to miner, a block interval
+// after the previous one so the timestamps only depend on the height
+fn mine_block(
+    blockchain: &mut Blockchain,
+    miner: &PublicKey,
+    spends: Vec<Transaction>,
+    unique_id: Uuid,
+) -> Result<()> {
+    let height = blockchain.block_height();
+    let prev_block_hash = match blockchain.tip_header() {
+        Some(header) => header.hash()?,
+        None => Hash::zero(),
+    };
+    let timestamp = DateTime::<Utc>::from_timestamp(GENESIS_TIME, 0)
+        .ok_or(BtcError::InvalidBlock)?
+        + Duration::seconds((height * crate::IDEAL_BLOCK_TIME) as i64);
+
+    let mut coinbase = Transaction::coinbase(miner, 0, height, None);
+    coinbase.outputs[0].unique_id = unique_id;
+    let mut transactions = vec![coinbase];
+    transactions.extend(spends);
+
+    // fees are always zero, so the coinbase is just the reward
+    let mut block = Block::new(
+        BlockHeader::new(
+            timestamp,
+            0,
+            prev_block_hash,
+            MerkleRoot::calculate(&transactions).ok_or(BtcError::InvalidMerkleRoot)?,
+            blockchain.target(),
+        ),
+        transactions,
+    );
+    block.transactions[0].outputs[0].value = block.calcualte_block_reward(height);
+    block.header.merkle_root =
+        MerkleRoot::calculate(&block.transactions).ok_or(BtcError::InvalidMerkleRoot)?;
+
+    while !block.header.mine(MINE_STEPS)? {}
+
+    blockchain.add_block(block)?;
+    blockchain.rebuild_utxos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tip(builder: ChainBuilder) -> Hash {
+        builder.build().tip_hash().unwrap()
+    }
+
+    #[test]
+    fn same_seed_builds_the_same_chain() {
+        let build = || ChainBuilder::with_seed(7).mine(5).spend(0, 1, 10).mine(2);
+        assert_eq!(tip(build()), tip(build()));
+    }
+
+    #[test]
+    fn different_seeds_build_different_chains() {
+        assert_ne!(
+            tip(ChainBuilder::with_seed(1).mine(3)),
+            tip(ChainBuilder::with_seed(2).mine(3))
+        );
+    }
+
+    #[test]
+    fn spends_are_mined_or_left_in_the_mempool() {
+        let (blockchain, keys) = ChainBuilder::new()
+            .mine(2)
+            .spend(0, 1, 10)
+            .mine(1)
+            .spend(0, 2, 20)
+            .build_with_keys();
+        assert_eq!(blockchain.block_height(), 3);
+
+        let balance = |key: &PrivateKey| -> u64 {
+            blockchain
+                .utxos()
+                .iter_for_pubkey(&key.public_key())
+                .unwrap()
+                .map(|(_, (_, output))| output.value)
+                .sum()
+        };
+        assert_eq!(balance(&keys[1]), 10);
+        assert_eq!(balance(&keys[2]), 0);
+
+        let pending = blockchain.mempool_transactions(Some(&keys[2].public_key()));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].outputs[0].value, 20);
+    }
+}
@@ -1,7 +1,15 @@
 mod block;
 mod blockchain;
+mod header_chain;
 mod transaction;
+mod utxo_store;
 
-pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+pub use block::{Block, BlockHeader, CompactBlock};
+pub use blockchain::{Blockchain, Connection, MempoolAccept, SavedMempool};
+pub use header_chain::{HeaderChain, UtxoProof};
+pub use transaction::{
+    Transaction, TransactionBuilder, TransactionInput, TransactionOutput, UnsignedTransaction,
+};
+#[cfg(feature = "redb")]
+pub use utxo_store::RedbUtxoStore;
+pub use utxo_store::{Utxo, UtxoDelta, UtxoStore};
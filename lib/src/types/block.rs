@@ -1,15 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 
 use crate::{
-    U256,
+    U256, consensus,
+    crypto::PublicKey,
     error::{BtcError, Result},
     sha256::Hash,
-    types::transaction::{Transaction, TransactionOutput},
-    util::{MerkleRoot, Saveable},
+    types::{
+        UtxoStore,
+        transaction::{Transaction, TransactionInput, TransactionOutput},
+    },
+    util::{self, MerkleRoot, Saveable},
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -26,52 +30,135 @@ impl Block {
         }
     }
 
+    // blocks are identified by their header, which commits to the transactions through
+    // the merkle root, so header-only clients can follow the chain
     pub fn hash(&self) -> Result<Hash> {
-        Hash::hash(self)
+        self.header.hash()
+    }
+
+    // the first transaction, which pays the miner, None only for a malformed empty block
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first()
+    }
+
+    // every transaction but the coinbase, the ones spending existing outputs
+    pub fn spends(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.iter().skip(1)
+    }
+
+    // size of the encoded block, as sent to peers
+    pub fn serialized_size(&self) -> usize {
+        util::encoded_len(self)
+    }
+
+    // everything a node checks of a block building on prev_hash at height, with the utxos as
+    // they were before it, short of what needs the rest of the chain (timestamps, maturity)
+    pub fn verify(
+        &self,
+        height: u64,
+        utxos: &dyn UtxoStore,
+        expected_target: U256,
+        prev_hash: Hash,
+    ) -> Result<()> {
+        if self.header.prev_block_hash != prev_hash {
+            return Err(BtcError::InvalidHash);
+        }
+
+        consensus::check_pow(&self.header, expected_target)?;
+        consensus::check_merkle_root(self)?;
+        self.verify_transactions(height, utxos)
     }
 
     pub fn verify_transactions(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &dyn UtxoStore,
     ) -> Result<()> {
-        if self.transactions.is_empty() {
+        let Some(coinbase) = self.coinbase() else {
+            return Err(BtcError::InvalidTransaction);
+        };
+
+        // every non-coinbase input costs one signature verification
+        let sigops: usize = self
+            .spends()
+            .map(|transaction| transaction.inputs.len())
+            .sum();
+        if sigops as u64 > crate::MAX_BLOCK_SIGOPS {
+            return Err(BtcError::InvalidBlock);
+        }
+
+        if !self
+            .transactions
+            .iter()
+            .all(|transaction| transaction.is_final(predicted_block_height))
+        {
             return Err(BtcError::InvalidTransaction);
         }
 
-        let _ = self.verify_coinbase_transaction(predicted_block_height, utxos);
+        // a coinbase matures once it is buried, never in its own block
+        let coinbase_outputs = coinbase
+            .outputs
+            .iter()
+            .map(|output| output.hash())
+            .collect::<Result<HashSet<_>>>()?;
+        if let Some(input) = self
+            .spends()
+            .flat_map(|transaction| &transaction.inputs)
+            .find(|input| coinbase_outputs.contains(&input.prev_transaction_output_hash))
+        {
+            return Err(BtcError::ImmatureCoinbase(
+                input.prev_transaction_output_hash,
+            ));
+        }
+
+        consensus::check_coinbase(self, predicted_block_height, utxos)?;
 
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        // outputs of earlier transactions in this block are spendable by later ones
+        let mut created: HashMap<Hash, TransactionOutput> = HashMap::new();
+        // signatures are checked once every input is resolved, they don't depend on each other
+        let mut signature_checks: Vec<(Hash, &TransactionInput, PublicKey)> = vec![];
+
+        for output in &coinbase.outputs {
+            add_created_output(&mut created, output, utxos)?;
+        }
 
-        for transaction in self.transactions.iter().skip(1) {
+        for transaction in self.spends() {
             let input_value: u64 = transaction
                 .inputs
                 .iter()
-                .map(|input| {
-                    // error if input does not come from some previous utxo
-                    let Some(prev_output) = utxos.get(&input.prev_transaction_output_hash) else {
+                .enumerate()
+                .map(|(i, input)| {
+                    // error if input does not come from some previous utxo or earlier in-block output
+                    let Some(prev_output) = utxos
+                        .get(&input.prev_transaction_output_hash)?
+                        .map(|(_, output)| output)
+                        .or_else(|| created.get(&input.prev_transaction_output_hash).cloned())
+                    else {
                         return Err(BtcError::InvalidTransaction);
                     };
 
                     // error on double spend
                     if inputs.contains_key(&input.prev_transaction_output_hash) {
-                        return Err(BtcError::InvalidTransaction);
+                        return Err(BtcError::DoubleSpend(input.prev_transaction_output_hash));
                     }
 
-                    if !input
-                        .signature
-                        .verify(&input.prev_transaction_output_hash, &prev_output.1.pubkey)
-                    {
-                        return Err(BtcError::InvalidSignature);
-                    }
-
-                    inputs.insert(input.prev_transaction_output_hash, prev_output.1.clone());
-                    Ok(prev_output.1.value)
+                    signature_checks.push((
+                        transaction.sighash(i, &prev_output)?,
+                        input,
+                        prev_output.pubkey.clone(),
+                    ));
+                    inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                    Ok(prev_output.value)
                 })
                 .collect::<Result<Vec<_>>>()?
                 .iter()
                 .sum();
 
+            for output in &transaction.outputs {
+                add_created_output(&mut created, output, utxos)?;
+            }
+
             let output_value = transaction.outputs.iter().map(|output| output.value).sum();
 
             if input_value < output_value {
@@ -79,62 +166,44 @@ impl Block {
             }
         }
 
-        Ok(())
+        consensus::check_signatures(&signature_checks)
     }
 
-    fn verify_coinbase_transaction(
-        &self,
-        predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<()> {
-        let Some(coinbase_transaction) = self.transactions.first() else {
-            return Err(BtcError::InvalidBlock);
-        };
-
-        if coinbase_transaction.inputs.is_empty() || coinbase_transaction.outputs.is_empty() {
-            return Err(BtcError::InvalidTransaction);
-        }
-
-        let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = self.calcualte_block_reward(predicted_block_height);
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
-        }
-
-        Ok(())
+    pub fn calcualte_block_reward(&self, predicted_block_height: u64) -> u64 {
+        Self::block_reward(predicted_block_height)
     }
 
-    fn calcualte_block_reward(&self, predicted_block_height: u64) -> u64 {
+    // the new coins a block at height may pay itself, before fees
+    pub fn block_reward(height: u64) -> u64 {
         // * 10 ^ 8 converts BTC to satoshies
-        crate::INITIAL_REWARD * 10u64.pow(8)
-        // block rewards halve on every halving interval
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32)
+        let initial_reward = crate::INITIAL_REWARD * 10u64.pow(8);
+        // block rewards halve on every halving interval, nothing is left after 64 halvings
+        u32::try_from(height / crate::HALVING_INTERVAL)
+            .ok()
+            .and_then(|halvings| initial_reward.checked_shr(halvings))
+            .unwrap_or(0)
     }
 
-    fn calculate_miner_fees(
-        &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
+    pub(crate) fn calculate_miner_fees(&self, utxos: &dyn UtxoStore) -> Result<u64> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
 
-        for transction in self.transactions.iter().skip(1) {
+        for transction in self.spends() {
             for input in &transction.inputs {
-                let Some(prev_output) = utxos.get(&input.prev_transaction_output_hash) else {
+                // outputs of earlier transactions in this block count as inputs too
+                let Some(prev_output) = utxos
+                    .get(&input.prev_transaction_output_hash)?
+                    .map(|(_, output)| output)
+                    .or_else(|| outputs.get(&input.prev_transaction_output_hash).cloned())
+                else {
                     return Err(BtcError::InvalidTransaction);
                 };
 
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                    return Err(BtcError::DoubleSpend(input.prev_transaction_output_hash));
                 }
 
-                inputs.insert(input.prev_transaction_output_hash, prev_output.1.clone());
+                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
 
             for output in &transction.outputs {
@@ -150,10 +219,26 @@ impl Block {
         let input_value: u64 = inputs.values().map(|input| input.value).sum();
         let output_value: u64 = outputs.values().map(|output| output.value).sum();
 
-        Ok(input_value - output_value)
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InvalidTransaction)
     }
 }
 
+// utxos are keyed by output hash, one already in use would overwrite that utxo and destroy its coins
+fn add_created_output(
+    created: &mut HashMap<Hash, TransactionOutput>,
+    output: &TransactionOutput,
+    utxos: &dyn UtxoStore,
+) -> Result<()> {
+    let hash = output.hash()?;
+    if utxos.contains(&hash)? || created.insert(hash, output.clone()).is_some() {
+        return Err(BtcError::DuplicateOutput(hash));
+    }
+
+    Ok(())
+}
+
 impl Saveable for Block {
     fn load<I: std::io::Read>(reader: I) -> std::io::Result<Self> {
         ciborium::de::from_reader(reader)
@@ -165,8 +250,81 @@ impl Saveable for Block {
     }
 }
 
+// a block announced by its header and short transaction ids,
+// receivers fill in the transactions they already hold in their mempool
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub coinbase: Transaction,
+    pub short_txids: Vec<u64>,
+}
+
+impl CompactBlock {
+    pub fn from_block(block: &Block) -> Result<Self> {
+        let coinbase = block.coinbase().ok_or(BtcError::InvalidBlock)?.clone();
+        let short_txids = block
+            .spends()
+            .map(|transaction| Ok(Self::short_txid(&transaction.hash()?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            header: block.header.clone(),
+            coinbase,
+            short_txids,
+        })
+    }
+
+    // first 8 bytes of the transaction hash
+    pub fn short_txid(hash: &Hash) -> u64 {
+        let mut short_txid = [0u8; 8];
+        short_txid.copy_from_slice(&hash.as_bytes()[..8]);
+        u64::from_le_bytes(short_txid)
+    }
+
+    // short ids of the transactions not found among the candidates
+    pub fn missing(&self, candidates: &[Transaction]) -> Result<Vec<u64>> {
+        let known = Self::index(candidates)?;
+        Ok(self
+            .short_txids
+            .iter()
+            .filter(|short_txid| !known.contains_key(short_txid))
+            .copied()
+            .collect())
+    }
+
+    // rebuild the full block, None if a transaction is missing or the merkle root doesn't match
+    pub fn reconstruct(&self, candidates: &[Transaction]) -> Result<Option<Block>> {
+        let known = Self::index(candidates)?;
+
+        let mut transactions = vec![self.coinbase.clone()];
+        for short_txid in &self.short_txids {
+            let Some(transaction) = known.get(short_txid) else {
+                return Ok(None);
+            };
+            transactions.push((*transaction).clone());
+        }
+
+        // short ids can collide, the merkle root catches a wrong pick
+        if MerkleRoot::calculate(&transactions) != Some(self.header.merkle_root) {
+            return Ok(None);
+        }
+
+        Ok(Some(Block::new(self.header.clone(), transactions)))
+    }
+
+    fn index(candidates: &[Transaction]) -> Result<HashMap<u64, &Transaction>> {
+        candidates
+            .iter()
+            .map(|transaction| Ok((Self::short_txid(&transaction.hash()?), transaction)))
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
+    // version bits miners set to signal readiness for rule changes
+    #[serde(default)]
+    pub version: u32,
     pub timestamp: DateTime<Utc>,
     pub nonce: u64,
     pub prev_block_hash: Hash,
@@ -174,6 +332,22 @@ pub struct BlockHeader {
     pub target: U256,
 }
 
+// what a header's hash commits to, a zero version is left out so older headers keep their hashes
+#[derive(Serialize)]
+struct HeaderHashView<'a> {
+    #[serde(skip_serializing_if = "is_zero")]
+    version: u32,
+    timestamp: &'a DateTime<Utc>,
+    nonce: u64,
+    prev_block_hash: &'a Hash,
+    merkle_root: &'a MerkleRoot,
+    target: &'a U256,
+}
+
+fn is_zero(version: &u32) -> bool {
+    *version == 0
+}
+
 impl BlockHeader {
     pub fn new(
         timestamp: DateTime<Utc>,
@@ -183,6 +357,7 @@ impl BlockHeader {
         target: U256,
     ) -> Self {
         Self {
+            version: 0,
             timestamp,
             nonce,
             prev_block_hash,
@@ -192,10 +367,28 @@ impl BlockHeader {
     }
 
     pub fn hash(&self) -> Result<Hash> {
-        Hash::hash(self)
+        Hash::hash(&HeaderHashView {
+            version: self.version,
+            timestamp: &self.timestamp,
+            nonce: self.nonce,
+            prev_block_hash: &self.prev_block_hash,
+            merkle_root: &self.merkle_root,
+            target: &self.target,
+        })
+    }
+
+    // expected number of hashes needed to meet the target
+    pub fn work(&self) -> U256 {
+        U256::MAX / self.target.saturating_add(U256::one())
     }
 
+    // try up to steps nonces, true once the header meets its target
     pub fn mine(&mut self, steps: usize) -> Result<bool> {
+        // a malformed template would otherwise grind forever
+        if self.target.is_zero() {
+            return Err(BtcError::ZeroTarget);
+        }
+
         if self.hash()?.matches_target(self.target) {
             return Ok(true);
         }
@@ -214,6 +407,337 @@ impl BlockHeader {
             }
         }
 
-        Ok(true)
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{PrivateKey, Signature},
+        testutil::ChainBuilder,
+        types::{Blockchain, UnsignedTransaction},
+    };
+
+    // the coinbase of the block after the tip, paying the key
+    fn next_coinbase(blockchain: &Blockchain, key: &PrivateKey) -> Transaction {
+        let height = blockchain.block_height();
+        Transaction::coinbase(&key.public_key(), Block::block_reward(height), height, None)
+    }
+
+    // spend the output back to its own key
+    fn pass_on(key: &mut PrivateKey, spent: TransactionOutput) -> Transaction {
+        UnsignedTransaction {
+            outputs: vec![TransactionOutput {
+                value: spent.value,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey: key.public_key(),
+            }],
+            inputs: vec![spent],
+            locktime: None,
+        }
+        .sign(key)
+        .unwrap()
+    }
+
+    // verify_transactions doesn't look at the header, so it only needs to be well formed
+    fn block_of(transactions: Vec<Transaction>) -> Block {
+        let merkle_root = MerkleRoot::calculate(&transactions[..1]).unwrap();
+        Block::new(
+            BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, U256::MAX),
+            transactions,
+        )
+    }
+
+    // a funded key 0 and one of its coinbase outputs
+    fn funded_chain() -> (Blockchain, PrivateKey, TransactionOutput) {
+        let (blockchain, keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let key = keys[0].clone();
+        let (_, (_, output)) = blockchain
+            .utxos()
+            .iter_for_pubkey(&key.public_key())
+            .unwrap()
+            .next()
+            .unwrap();
+        (blockchain, key, output)
+    }
+
+    // a block with a spend per entry of spends, each with that many inputs of outputs that don't exist
+    fn block_with_inputs(blockchain: &Blockchain, spends: &[usize]) -> Block {
+        let mut key = PrivateKey::new_key();
+        let signature = Signature::sign_hash(&Hash::zero(), &mut key);
+
+        let mut transactions = vec![next_coinbase(blockchain, &key)];
+        for (i, inputs) in spends.iter().enumerate() {
+            let inputs = (0..*inputs)
+                .map(|input| TransactionInput {
+                    prev_transaction_output_hash: Hash::hash(&(i, input)).unwrap(),
+                    signature: signature.clone(),
+                })
+                .collect();
+            transactions.push(Transaction::new(inputs, vec![]));
+        }
+        block_of(transactions)
+    }
+
+    #[test]
+    fn block_over_the_sigops_limit_is_rejected() {
+        let blockchain = ChainBuilder::new().mine(1).build();
+        let half = crate::MAX_BLOCK_SIGOPS as usize / 2;
+
+        let block = block_with_inputs(&blockchain, &[half, half + 1]);
+        assert!(matches!(
+            block.verify_transactions(blockchain.block_height(), blockchain.utxos()),
+            Err(BtcError::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn block_at_the_sigops_limit_gets_past_the_count() {
+        let blockchain = ChainBuilder::new().mine(1).build();
+        let half = crate::MAX_BLOCK_SIGOPS as usize / 2;
+
+        // the inputs don't exist, so it fails on them instead
+        let block = block_with_inputs(&blockchain, &[half, half]);
+        assert!(matches!(
+            block.verify_transactions(blockchain.block_height(), blockchain.utxos()),
+            Err(BtcError::InvalidTransaction)
+        ));
+    }
+
+    #[test]
+    fn spending_an_earlier_coinbase_verifies() {
+        let (blockchain, mut key, earlier) = funded_chain();
+
+        let block = block_of(vec![
+            next_coinbase(&blockchain, &key),
+            pass_on(&mut key, earlier),
+        ]);
+        block
+            .verify_transactions(blockchain.block_height(), blockchain.utxos())
+            .unwrap();
+    }
+
+    #[test]
+    fn spending_an_output_of_an_earlier_transaction_in_the_block_verifies() {
+        let (blockchain, mut key, earlier) = funded_chain();
+        let first = pass_on(&mut key, earlier);
+        let second = pass_on(&mut key, first.outputs[0].clone());
+
+        let block = block_of(vec![next_coinbase(&blockchain, &key), first, second]);
+        block
+            .verify_transactions(blockchain.block_height(), blockchain.utxos())
+            .unwrap();
+    }
+
+    #[test]
+    fn spending_an_output_of_a_later_transaction_in_the_block_is_rejected() {
+        let (blockchain, mut key, earlier) = funded_chain();
+        let first = pass_on(&mut key, earlier);
+        let second = pass_on(&mut key, first.outputs[0].clone());
+
+        let block = block_of(vec![next_coinbase(&blockchain, &key), second, first]);
+        assert!(matches!(
+            block.verify_transactions(blockchain.block_height(), blockchain.utxos()),
+            Err(BtcError::InvalidTransaction)
+        ));
+    }
+
+    #[test]
+    fn locked_transaction_is_only_valid_from_its_locktime() {
+        let (blockchain, mut key, earlier) = funded_chain();
+        let height = blockchain.block_height();
+
+        for (locktime, valid) in [(height + 1, false), (height, true)] {
+            let locked = UnsignedTransaction {
+                inputs: vec![earlier.clone()],
+                outputs: pass_on(&mut key, earlier.clone()).outputs,
+                locktime: Some(locktime),
+            }
+            .sign(&mut key)
+            .unwrap();
+
+            let block = block_of(vec![next_coinbase(&blockchain, &key), locked]);
+            let verified = block.verify_transactions(height, blockchain.utxos());
+            assert_eq!(verified.is_ok(), valid, "locktime {locktime}: {verified:?}");
+        }
+    }
+
+    #[test]
+    fn spending_a_utxo_twice_names_it() {
+        let (blockchain, mut key, earlier) = funded_chain();
+        let spent = earlier.hash().unwrap();
+        let first = pass_on(&mut key, earlier.clone());
+        let second = pass_on(&mut key, earlier);
+
+        let block = block_of(vec![next_coinbase(&blockchain, &key), first, second]);
+        assert!(matches!(
+            block.verify_transactions(blockchain.block_height(), blockchain.utxos()),
+            Err(BtcError::DoubleSpend(hash)) if hash == spent
+        ));
+    }
+
+    // a block with a coinbase and three spends, merkle root over all of them
+    fn block_with_spends() -> Block {
+        let (blockchain, mut key, earlier) = funded_chain();
+        let first = pass_on(&mut key, earlier);
+        let second = pass_on(&mut key, first.outputs[0].clone());
+        let third = pass_on(&mut key, second.outputs[0].clone());
+        let transactions = vec![next_coinbase(&blockchain, &key), first, second, third];
+        let merkle_root = MerkleRoot::calculate(&transactions).unwrap();
+        Block::new(
+            BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, U256::MAX),
+            transactions,
+        )
+    }
+
+    #[test]
+    fn compact_block_is_rebuilt_from_the_mempool() {
+        let block = block_with_spends();
+        let compact_block = CompactBlock::from_block(&block).unwrap();
+        assert_eq!(compact_block.short_txids.len(), 3);
+
+        // the mempool holds the spends, in any order, along with unrelated transactions
+        let unrelated = next_coinbase(&ChainBuilder::new().mine(1).build(), &PrivateKey::new_key());
+        let mempool = vec![
+            block.transactions[3].clone(),
+            unrelated,
+            block.transactions[1].clone(),
+            block.transactions[2].clone(),
+        ];
+        assert!(compact_block.missing(&mempool).unwrap().is_empty());
+
+        let rebuilt = compact_block.reconstruct(&mempool).unwrap().unwrap();
+        assert_eq!(rebuilt.hash().unwrap(), block.hash().unwrap());
+        assert_eq!(
+            MerkleRoot::calculate(&rebuilt.transactions),
+            Some(block.header.merkle_root)
+        );
+    }
+
+    #[test]
+    fn compact_block_missing_a_transaction_is_not_rebuilt() {
+        let block = block_with_spends();
+        let compact_block = CompactBlock::from_block(&block).unwrap();
+        let mempool = vec![block.transactions[1].clone(), block.transactions[3].clone()];
+
+        // the caller asks peers for the missing ids, or downloads the whole block
+        let missing_id = CompactBlock::short_txid(&block.transactions[2].hash().unwrap());
+        assert_eq!(compact_block.missing(&mempool).unwrap(), vec![missing_id]);
+        assert!(compact_block.reconstruct(&mempool).unwrap().is_none());
+    }
+
+    // a mined template on the chain's tip, with what verify checks it against
+    fn next_block(blockchain: &Blockchain) -> (Block, u64, U256, Hash) {
+        let miner = PrivateKey::new_key().public_key();
+        let mut block = blockchain.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+        let tip = blockchain.tip_hash().unwrap();
+        (block, blockchain.block_height(), blockchain.target(), tip)
+    }
+
+    #[test]
+    fn good_block_verifies_on_its_own() {
+        let (mut blockchain, mut key, earlier) = funded_chain();
+        blockchain
+            .add_to_mempool(pass_on(&mut key, earlier))
+            .unwrap();
+
+        let (block, height, target, tip) = next_block(&blockchain);
+        assert_eq!(block.transactions.len(), 2);
+        block
+            .verify(height, blockchain.utxos(), target, tip)
+            .unwrap();
+    }
+
+    #[test]
+    fn block_with_a_wrong_merkle_root_fails_verify() {
+        let blockchain = ChainBuilder::new().mine(2).build();
+        let (mut block, height, target, tip) = next_block(&blockchain);
+
+        // a second coinbase output the header doesn't commit to
+        let extra = block.transactions[0].outputs[0].clone();
+        block.transactions[0].outputs.push(TransactionOutput {
+            unique_id: uuid::Uuid::new_v4(),
+            ..extra
+        });
+        while !block.header.mine(1_000).unwrap() {}
+        assert!(matches!(
+            block.verify(height, blockchain.utxos(), target, tip),
+            Err(BtcError::InvalidMerkleRoot)
+        ));
+    }
+
+    #[test]
+    fn block_on_another_parent_fails_verify() {
+        let blockchain = ChainBuilder::new().mine(2).build();
+        let (block, height, target, _) = next_block(&blockchain);
+        let other = blockchain.blocks().next().unwrap().hash().unwrap();
+
+        assert!(matches!(
+            block.verify(height, blockchain.utxos(), target, other),
+            Err(BtcError::InvalidHash)
+        ));
+    }
+
+    #[test]
+    fn mining_a_zero_target_is_an_error() {
+        let mut header = BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&[Transaction::new(vec![], vec![])]).unwrap(),
+            U256::zero(),
+        );
+
+        assert!(matches!(header.mine(1_000), Err(BtcError::ZeroTarget)));
+        assert_eq!(header.nonce, 0);
+    }
+
+    #[test]
+    fn spending_the_coinbase_of_the_same_block_is_immature() {
+        let (blockchain, mut key, _) = funded_chain();
+        let coinbase = next_coinbase(&blockchain, &key);
+        let spend = pass_on(&mut key, coinbase.outputs[0].clone());
+        let coinbase_output = coinbase.outputs[0].hash().unwrap();
+
+        let block = block_of(vec![coinbase, spend]);
+        assert!(matches!(
+            block.verify_transactions(blockchain.block_height(), blockchain.utxos()),
+            Err(BtcError::ImmatureCoinbase(hash)) if hash == coinbase_output
+        ));
+    }
+
+    #[test]
+    fn version_zero_headers_keep_their_old_hash() {
+        // the header as it was encoded before it had a version
+        #[derive(Serialize)]
+        struct Unversioned<'a> {
+            timestamp: &'a DateTime<Utc>,
+            nonce: u64,
+            prev_block_hash: &'a Hash,
+            merkle_root: &'a MerkleRoot,
+            target: &'a U256,
+        }
+
+        let mut header = ChainBuilder::new()
+            .mine(1)
+            .build()
+            .tip_block()
+            .unwrap()
+            .header;
+        let old_hash = Hash::hash(&Unversioned {
+            timestamp: &header.timestamp,
+            nonce: header.nonce,
+            prev_block_hash: &header.prev_block_hash,
+            merkle_root: &header.merkle_root,
+            target: &header.target,
+        })
+        .unwrap();
+        assert_eq!(header.hash().unwrap(), old_hash);
+
+        header.version = 1;
+        assert_ne!(header.hash().unwrap(), old_hash);
     }
 }
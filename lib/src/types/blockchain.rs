@@ -1,315 +1,2963 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::Error as _};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Write},
+    path::Path,
+};
+use uuid::Uuid;
 
 use crate::{
-    U256,
+    U256, consensus,
+    crypto::PublicKey,
     error::{BtcError, Result},
+    params::ChainParams,
     sha256::Hash,
-    types::{Block, Transaction, TransactionOutput},
+    types::{
+        Block, BlockHeader, Transaction, TransactionOutput, Utxo, UtxoDelta, UtxoProof, UtxoStore,
+    },
     util::{MerkleRoot, Saveable},
 };
 
-const UNEXPECTED_BUG: &str = "uh oh";
+// search steps branch and bound coin selection may take before giving up
+const BNB_MAX_TRIES: u32 = 100_000;
+// on-disk format version, bump on incompatible changes to the saved blockchain
+const FORMAT_VERSION: u32 = 2;
+// first format identifying blocks by their header hash, earlier ones saved
+// prev_block_hash links to the full block hash and are relinked on load
+const HEADER_HASH_FORMAT_VERSION: u32 = 2;
+
+// mempool transactions and when they were accepted, saved apart from the chain
+// so they can be offered to the mempool again after a restart
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SavedMempool(pub Vec<(DateTime<Utc>, Transaction)>);
+
+// outcome of offering a transaction to the mempool
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MempoolAccept {
+    Added,
+    // the transaction evicted earlier ones spending the same utxos, carries their hashes
+    Replaced(Vec<Hash>),
+    AlreadyPresent,
+}
+
+// where a header's parent sits relative to our chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connection {
+    // builds on the tip
+    Extends,
+    // builds on an earlier block, carries the height of that block
+    Forks(u64),
+    // builds on a block further back than max_reorg_depth, never followed
+    TooDeep(u64),
+    // the parent isn't one of our blocks
+    Orphan,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    // files written before versioning have no tag and load as version 0
+    #[serde(default)]
+    format_version: u32,
+    #[serde(
+        serialize_with = "serialize_utxos",
+        deserialize_with = "deserialize_utxos"
+    )]
+    utxos: Box<dyn UtxoStore>,
     target: U256,
     blocks: Vec<Block>,
     #[serde(default, skip_serializing)]
     // bitcoin's eviction policy is 72 hours, but we'll use 600 seconds here
     mempool: Vec<(DateTime<Utc>, Transaction)>,
+    // spent output hash -> (height, hash) of the spending transaction, rebuilt with the utxos
+    #[serde(default, skip)]
+    spenders: HashMap<Hash, (u64, Hash)>,
+    // coinbase output hash -> height of its block, for the maturity rule
+    #[serde(default, skip)]
+    coinbase_heights: HashMap<Hash, u64>,
+    // transaction hash -> height of the block holding it
+    #[serde(default, skip)]
+    transaction_heights: HashMap<Hash, u64>,
+    // block hash -> its height
+    #[serde(default, skip)]
+    block_heights: HashMap<Hash, u64>,
+    #[serde(default)]
+    params: ChainParams,
+    // blocks below this height are already in the block log, see save_appending
+    #[serde(default, skip)]
+    persisted_height: u64,
 }
 
 impl Blockchain {
     pub fn new() -> Self {
+        Self::with_params(ChainParams::default())
+    }
+
+    pub fn with_params(params: ChainParams) -> Self {
         Self {
-            utxos: HashMap::new(),
+            format_version: FORMAT_VERSION,
+            utxos: Box::default(),
             blocks: vec![],
             mempool: vec![],
-            target: crate::MIN_TARGET,
+            target: params.starting_target,
+            spenders: HashMap::new(),
+            coinbase_heights: HashMap::new(),
+            transaction_heights: HashMap::new(),
+            block_heights: HashMap::new(),
+            params,
+            persisted_height: 0,
         }
     }
 
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
-        &self.utxos
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // an empty chain also takes the new starting target
+    pub fn set_params(&mut self, params: ChainParams) {
+        if self.blocks.is_empty() {
+            self.target = params.starting_target;
+        }
+        self.params = params;
+    }
+
+    pub fn utxos(&self) -> &dyn UtxoStore {
+        &*self.utxos
+    }
+
+    // swap in a different utxo store, e.g. a disk-backed one, then rebuild_utxos to fill it
+    pub fn set_utxo_store(&mut self, utxos: Box<dyn UtxoStore>) {
+        self.utxos = utxos;
     }
 
+    // an empty chain has the starting target until its first retarget,
+    // so mainnet reports the minimum difficulty of 1.0 and regtest less
     pub fn target(&self) -> U256 {
         self.target
     }
 
+    pub fn difficulty(&self) -> f64 {
+        crate::util::difficulty(self.target)
+    }
+
+    // height, time and difficulty of every block, for charting retargets over the chain
+    pub fn difficulty_series(&self) -> Vec<(u64, DateTime<Utc>, f64)> {
+        self.blocks
+            .iter()
+            .zip(0..)
+            .map(|(block, height)| {
+                (
+                    height,
+                    block.header.timestamp,
+                    crate::util::difficulty(block.header.target),
+                )
+            })
+            .collect()
+    }
+
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 
+    // blocks from the tip back to genesis
+    pub fn blocks_rev(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter().rev()
+    }
+
+    // the last n blocks (fewer on a short chain), oldest first
+    pub fn recent_blocks(&self, n: usize) -> &[Block] {
+        &self.blocks[self.blocks.len().saturating_sub(n)..]
+    }
+
+    // headers of the last n blocks, oldest first
+    pub fn recent_headers(&self, n: usize) -> impl DoubleEndedIterator<Item = &BlockHeader> {
+        self.recent_blocks(n).iter().map(|block| &block.header)
+    }
+
     pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
         &self.mempool
     }
 
+    // unconfirmed transactions, highest fee first, optionally only those paying pubkey
+    pub fn mempool_transactions(&self, pubkey: Option<&PublicKey>) -> Vec<Transaction> {
+        self.mempool
+            .iter()
+            .rev()
+            .map(|(_, transaction)| transaction)
+            .filter(|transaction| {
+                pubkey.is_none_or(|pubkey| {
+                    transaction
+                        .outputs
+                        .iter()
+                        .any(|output| output.pubkey == *pubkey)
+                })
+            })
+            .take(crate::MAX_MEMPOOL_RESPONSE as usize)
+            .cloned()
+            .collect()
+    }
+
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
     }
 
+    pub fn tip_header(&self) -> Option<&BlockHeader> {
+        self.blocks.last().map(|block| &block.header)
+    }
+
+    // owned copies, so a caller can release its lock before sending the block somewhere
+    pub fn tip_block(&self) -> Option<Block> {
+        self.blocks.last().cloned()
+    }
+
+    pub fn block_at(&self, height: u64) -> Option<Block> {
+        self.blocks.get(usize::try_from(height).ok()?).cloned()
+    }
+
+    // the block with this header hash, heights differ between forks but hashes don't
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<Block> {
+        self.block_at(*self.block_heights.get(hash)?)
+    }
+
+    // hash of the tip block, or the zero hash a genesis block builds on
+    pub fn tip_hash(&self) -> Result<Hash> {
+        match self.blocks.last() {
+            Some(block) => block.hash(),
+            None => Ok(Hash::zero()),
+        }
+    }
+
+    // age of the tip block, 0 before there is one
+    pub fn seconds_since_tip(&self) -> i64 {
+        self.tip_header()
+            .map(|header| (Utc::now() - header.timestamp).num_seconds())
+            .unwrap_or_default()
+    }
+
+    pub fn connects_to(&self, header: &BlockHeader) -> Result<Connection> {
+        if header.prev_block_hash == self.tip_hash()? {
+            return Ok(Connection::Extends);
+        }
+
+        // the tip is ruled out already, search back from the block before it
+        for (height, block) in self.blocks.iter().enumerate().rev().skip(1) {
+            if block.hash()? == header.prev_block_hash {
+                // following the fork would disconnect every block above its parent
+                let depth = self.block_height() - 1 - height as u64;
+                if depth > self.params.max_reorg_depth {
+                    return Ok(Connection::TooDeep(height as u64));
+                }
+                return Ok(Connection::Forks(height as u64));
+            }
+        }
+
+        Ok(Connection::Orphan)
+    }
+
     pub fn add_block(&mut self, block: Block) -> Result<()> {
         if self.blocks.is_empty() {
             if block.header.prev_block_hash != Hash::zero() {
                 println!("zero hash");
                 return Err(BtcError::InvalidBlock);
             }
+
+            // genesis has no parent to be checked against, but it still needs a coinbase
+            // at height 0 paying no more than the reward and a merkle root committing to it
+            consensus::check_coinbase(&block, 0, &*self.utxos)?;
+            consensus::check_merkle_root(&block)?;
         } else {
             let prev_block = self.blocks.last().ok_or(BtcError::InvalidBlock)?;
+            // a block building on another tip is stale rather than invalid, say so before
+            // its other fields get compared against a parent that isn't theirs
             if block.header.prev_block_hash != prev_block.hash()? {
-                println!("prev hash does not match");
                 return Err(BtcError::InvalidHash);
             }
+            consensus::check_timestamp(&block.header, &prev_block.header)?;
+            consensus::check_bootstrap_pace(
+                &block.header,
+                &prev_block.header,
+                self.block_height(),
+                &self.params,
+                Utc::now(),
+            )?;
 
-            if !block.header.hash()?.matches_target(block.header.target) {
-                println!("target does not match");
+            if block.transactions.len() as u64 > self.params.max_block_transactions {
                 return Err(BtcError::InvalidBlock);
             }
 
-            let merkle_root =
-                MerkleRoot::calculate(&block.transactions).ok_or(BtcError::InvalidMerkleRoot)?;
-            if merkle_root != block.header.merkle_root {
-                println!("invalid merkle root");
-                return Err(BtcError::InvalidMerkleRoot);
+            for transaction in &block.transactions {
+                transaction.check_limits(&self.params)?;
             }
 
-            if block.header.timestamp <= prev_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            for transaction in block.spends() {
+                self.check_maturity(transaction, self.block_height())?;
             }
 
-            block.verify_transactions(self.block_height(), &self.utxos)?;
+            block.verify(
+                self.block_height(),
+                &*self.utxos,
+                self.target,
+                prev_block.hash()?,
+            )?;
         }
 
-        let block_transactions: HashSet<_> = block
-            .transactions
-            .iter()
-            .map(|transaction| transaction.hash())
-            .collect::<Result<HashSet<_>>>()?;
+        if self.params.mempool_enabled {
+            let block_transactions: HashSet<_> = block
+                .transactions
+                .iter()
+                .map(|transaction| transaction.hash())
+                .collect::<Result<HashSet<_>>>()?;
 
-        // hard to use retain with the result type :(
-        let mut new_mempool: Vec<(DateTime<Utc>, Transaction)> = vec![];
-        for (datetime, transaction) in self.mempool() {
-            let hash = transaction.hash()?;
-            if !block_transactions.contains(&hash) {
-                new_mempool.push((*datetime, transaction.clone()));
+            // hard to use retain with the result type :(
+            let mut new_mempool: Vec<(DateTime<Utc>, Transaction)> = vec![];
+            for (datetime, transaction) in self.mempool() {
+                let hash = transaction.hash()?;
+                if !block_transactions.contains(&hash) {
+                    new_mempool.push((*datetime, transaction.clone()));
+                }
             }
+            self.mempool = new_mempool;
+
+            // whatever else in the mempool spends the block's inputs can never confirm now
+            let spent: HashSet<Hash> = block
+                .transactions
+                .iter()
+                .flat_map(|transaction| &transaction.inputs)
+                .map(|input| input.prev_transaction_output_hash)
+                .collect();
+            self.evict_conflicts(spent)?;
         }
-        self.mempool = new_mempool;
 
+        let height = self.block_height();
+        index_spenders(&mut self.spenders, height, &block)?;
+        index_coinbase(&mut self.coinbase_heights, height, &block)?;
+        index_transactions(&mut self.transaction_heights, height, &block)?;
+        self.block_heights.insert(block.hash()?, height);
         self.try_adjust_target();
         self.blocks.push(block);
 
         Ok(())
     }
 
-    pub fn rebuild_utxos(&mut self) -> Result<()> {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                // old utxos have been spent
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
+    // add_block for one of a run of blocks: only this block's outputs are connected,
+    // so the next one validates without a full rebuild_utxos, callers rebuild once at the end
+    pub fn add_block_connecting(&mut self, block: Block) -> Result<()> {
+        self.add_block(block)?;
+        if let Some(block) = self.blocks.last() {
+            connect_utxos(&mut *self.utxos, block)?;
+        }
 
-                // create new utxos
-                for output in &transaction.outputs {
-                    self.utxos
-                        .insert(transaction.hash()?, (false, output.clone()));
-                }
+        Ok(())
+    }
+
+    // what build_template would put in the next block after its coinbase, best paying first
+    // blocks are limited by count rather than size, so the fee per transaction is the rate that matters
+    pub fn next_block_transactions(&self) -> Result<Vec<Transaction>> {
+        let cap = self.params.max_block_transactions.saturating_sub(1) as usize;
+        let parents = self.mempool_parents()?;
+        let mut by_hash = HashMap::new();
+        for (_, transaction) in &self.mempool {
+            by_hash.insert(transaction.hash()?, transaction);
+        }
+
+        // mempool is sorted by ascending fee, take the best paying transactions from the back,
+        // each right after the mempool parents it spends from so the block stays valid
+        let mut picked = HashSet::new();
+        let mut transactions = vec![];
+        for (_, transaction) in self.mempool.iter().rev() {
+            let hash = transaction.hash()?;
+            if picked.contains(&hash) {
+                continue;
+            }
+
+            let mut package = vec![];
+            mempool_ancestors(&hash, &parents, &mut package);
+            package.retain(|ancestor| !picked.contains(ancestor));
+            package.push(hash);
+            if transactions.len() + package.len() > cap {
+                continue;
+            }
+
+            for hash in package {
+                picked.insert(hash);
+                transactions.push(by_hash[&hash].clone());
             }
         }
-        Ok(())
+
+        Ok(transactions)
     }
 
-    pub fn try_adjust_target(&mut self) {
-        // let N = block count interval to update difficulty
-        // return early if N blocks have not passed
-        if self.blocks.is_empty() {
-            return;
+    // what the coinbase of build_template pays in total, the next block's reward plus the fees
+    // of the transactions it would include, so a miner can tell what a block is worth
+    pub fn expected_coinbase_value(&self) -> Result<u64> {
+        self.coinbase_value(&self.next_block_transactions()?)
+    }
+
+    fn coinbase_value(&self, transactions: &[Transaction]) -> Result<u64> {
+        let mut fees = 0;
+        for transaction in transactions {
+            let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+            fees += self
+                .input_value(transaction)?
+                .checked_sub(outputs)
+                .ok_or(BtcError::InvalidTransaction)?;
+        }
+
+        Ok(Block::block_reward(self.block_height()) + fees)
+    }
+
+    // assemble a block on top of the current tip paying the reward and fees to the payouts,
+    // split by weight with the rounding remainder going to the first payout
+    // on an empty chain this is a genesis template at height 0 building on the zero hash
+    pub fn build_template(&self, payouts: &[(PublicKey, u64)]) -> Result<Block> {
+        let total_weight: u64 = payouts.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
+            return Err(BtcError::InvalidTransactionOutput);
         }
 
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
-            return;
+        let mut transactions = self.next_block_transactions()?;
+        let total = self.coinbase_value(&transactions)?;
+
+        // coinbase values are filled in once the block is assembled
+        let mut coinbase = Transaction::coinbase(&payouts[0].0, 0, self.block_height(), None);
+        coinbase
+            .outputs
+            .extend(payouts[1..].iter().map(|(pubkey, _)| TransactionOutput {
+                value: 0,
+                unique_id: Uuid::new_v4(),
+                pubkey: pubkey.clone(),
+            }));
+        transactions.insert(0, coinbase);
+
+        // before the first retarget a template is dated no sooner than the bootstrap pace allows
+        let mut timestamp = Utc::now();
+        if let Some(tip) = self.tip_header()
+            && self.block_height() < crate::DIFFICULTY_UPDATE_INTERVAL
+        {
+            let spacing = chrono::Duration::seconds(self.params.bootstrap_block_spacing as i64);
+            timestamp = timestamp.max(tip.timestamp + spacing);
         }
 
-        let start_time = self.blocks
-            [self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = (end_time - start_time).num_seconds();
+        let prev_block_hash = self.tip_hash()?;
+        let merkle_root =
+            MerkleRoot::calculate(&transactions).ok_or(BtcError::InvalidMerkleRoot)?;
+        let mut block = Block::new(
+            BlockHeader::new(timestamp, 0, prev_block_hash, merkle_root, self.target),
+            transactions,
+        );
 
-        // target_seconds represents the ideal duration to mine N blocks
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
-        let target =
-            BigDecimal::parse_bytes(self.target.to_string().as_bytes(), 10).expect(UNEXPECTED_BUG);
+        let coinbase_outputs = &mut block.transactions[0].outputs;
+        for (output, (_, weight)) in coinbase_outputs.iter_mut().zip(payouts) {
+            output.value = (total as u128 * *weight as u128 / total_weight as u128) as u64;
+        }
+        let paid: u64 = coinbase_outputs.iter().map(|output| output.value).sum();
+        coinbase_outputs[0].value += total - paid;
 
-        // if time_diff is shorter than expected, mining is too fast, reduce target to make more difficult
-        // and vice versa
-        let new_target = target * (BigDecimal::from(time_diff) / BigDecimal::from(target_seconds));
-        let new_target_str = new_target
-            .to_string()
-            .split(".")
-            .next()
-            .expect(UNEXPECTED_BUG)
-            .to_string();
-        let new_target = U256::from_str_radix(&new_target_str, 10).expect(UNEXPECTED_BUG);
-        let new_target = new_target.clamp(self.target / 4, self.target * 4);
+        block.header.merkle_root =
+            MerkleRoot::calculate(&block.transactions).ok_or(BtcError::InvalidMerkleRoot)?;
 
-        self.target = new_target.min(crate::MIN_TARGET);
+        Ok(block)
     }
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // validate inputs
-        // input must come from a know utxo and be unique to prevent double spends
-        let mut inputs = HashSet::new();
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                println!("UTXO not found");
-                dbg!(&self.utxos());
-                return Err(BtcError::InvalidTransaction);
-            };
+    // block hashes from the tip back to genesis, dense near the tip and exponentially sparser
+    // after the first ten, so a peer can find where our chains diverge without knowing heights
+    pub fn block_locator(&self) -> Result<Vec<Hash>> {
+        locator_indices(self.blocks.len())
+            .into_iter()
+            .map(|index| self.blocks[index].hash())
+            .collect()
+    }
 
-            if inputs.contains(&input.prev_transaction_output_hash) {
-                println!("non-unique input");
-                return Err(BtcError::InvalidTransaction);
+    // headers after the first locator hash we know, up to and including stop
+    // starts from genesis when none of the locator is on our chain
+    pub fn headers_after_locator(
+        &self,
+        locator: &[Hash],
+        stop: Option<Hash>,
+    ) -> Result<Vec<BlockHeader>> {
+        let hashes = self
+            .blocks
+            .iter()
+            .map(|block| block.hash())
+            .collect::<Result<Vec<_>>>()?;
+
+        let start = locator
+            .iter()
+            .find_map(|known| hashes.iter().position(|hash| hash == known))
+            .map_or(0, |fork_point| fork_point + 1);
+
+        let mut headers = vec![];
+        for (block, hash) in self.blocks.iter().zip(&hashes).skip(start) {
+            headers.push(block.header.clone());
+            if headers.len() as u64 >= crate::MAX_HEADERS_RESPONSE || stop == Some(*hash) {
+                break;
             }
+        }
+
+        Ok(headers)
+    }
 
-            inputs.insert(input.prev_transaction_output_hash);
+    // headers of the blocks with heights in from..to, at most MAX_HEADERS_RESPONSE of them
+    pub fn headers_in_range(&self, from: u64, to: u64) -> Vec<BlockHeader> {
+        let to = to
+            .min(self.block_height())
+            .min(from.saturating_add(crate::MAX_HEADERS_RESPONSE));
+        if from >= to {
+            return vec![];
         }
 
-        // when more than one mempool transaction references the same utxo, let the latest one win, and evict the previous one
-        for input in &transaction.inputs {
-            // utxo is marked as true when it is being spent my some transaction in the mempool
-            if let Some((true, _)) = self.utxos().get(&input.prev_transaction_output_hash) {
-                // Find transaction that has an output matching our input's hash
-                let referencing_transaction =
-                    self.mempool()
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction.outputs.iter().any(|output| {
-                                output
-                                    .hash()
-                                    .is_ok_and(|hash| hash == input.prev_transaction_output_hash)
-                            })
-                        });
-
-                if let Some((i, (_, transaction))) = referencing_transaction {
-                    // remove the earlier transaction, mark all of its utxo outputs as unused
-                    // clone to resolve borrow checker :(
-                    for input in transaction.inputs.clone() {
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| *marked = false);
+        self.blocks[from as usize..to as usize]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    // expected hashes it took to build the whole chain
+    pub fn total_work(&self) -> U256 {
+        self.blocks.iter().fold(U256::zero(), |work, block| {
+            work.saturating_add(block.header.work())
+        })
+    }
+
+    // fraction of the last window blocks signalling the given version bit
+    pub fn version_bit_support(&self, bit: u8, window: u64) -> f64 {
+        let Some(mask) = 1u32.checked_shl(bit as u32) else {
+            return 0.0;
+        };
+
+        let recent = self.recent_blocks(usize::try_from(window).unwrap_or(usize::MAX));
+        if recent.is_empty() {
+            return 0.0;
+        }
+
+        let signalling = recent
+            .iter()
+            .filter(|block| block.header.version & mask != 0)
+            .count();
+        signalling as f64 / recent.len() as f64
+    }
+
+    // pick unspent, unmarked outputs of pubkey covering target plus fee
+    // tries for a set needing no change output first, then falls back to largest first
+    pub fn select_coins(
+        &self,
+        pubkey: &PublicKey,
+        target: u64,
+        fee: u64,
+    ) -> Option<Vec<(Hash, TransactionOutput)>> {
+        let needed = target.checked_add(fee)?;
+
+        let mut candidates: Vec<(Hash, TransactionOutput)> = self
+            .utxos
+            .iter_for_pubkey(pubkey)
+            .ok()?
+            .filter(|(_, (marked, _))| !marked)
+            .map(|(hash, (_, output))| (hash, output))
+            .collect();
+        // largest first lets both searches reach the target quickly
+        candidates.sort_by_key(|(_, output)| std::cmp::Reverse(output.value));
+
+        let values: Vec<u64> = candidates.iter().map(|(_, output)| output.value).collect();
+        let mut picked = vec![];
+        let mut tries = BNB_MAX_TRIES;
+        // anything within the dust threshold over the target is cheaper left as fee than as change
+        let upper = needed.saturating_add(self.params.dust_threshold);
+        if branch_and_bound(
+            &values,
+            0,
+            0,
+            values.iter().sum(),
+            needed,
+            upper,
+            &mut picked,
+            &mut tries,
+        ) {
+            return Some(
+                picked
+                    .into_iter()
+                    .map(|index| candidates[index].clone())
+                    .collect(),
+            );
+        }
+
+        let mut selected = vec![];
+        let mut total: u64 = 0;
+        for candidate in candidates {
+            if total >= needed {
+                break;
+            }
+            total += candidate.1.value;
+            selected.push(candidate);
+        }
+
+        (total >= needed).then_some(selected)
+    }
+
+    // (height, transaction hash, net value change) for every transaction touching pubkey
+    pub fn address_history(&self, pubkey: &PublicKey) -> Vec<(u64, Hash, i64)> {
+        // every output seen so far, spent inputs are resolved against these
+        let mut outputs: HashMap<Hash, &TransactionOutput> = HashMap::new();
+        let mut history = vec![];
+
+        for (height, block) in self.blocks.iter().enumerate() {
+            for transaction in &block.transactions {
+                let mut change: i64 = 0;
+                let mut touched = false;
+
+                for input in &transaction.inputs {
+                    if let Some(output) = outputs.get(&input.prev_transaction_output_hash)
+                        && output.pubkey == *pubkey
+                    {
+                        change -= output.value as i64;
+                        touched = true;
                     }
-                    self.mempool.remove(i);
-                } else {
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| *marked = false);
+                }
+
+                for output in &transaction.outputs {
+                    if let Ok(hash) = output.hash() {
+                        outputs.insert(hash, output);
+                    }
+
+                    if output.pubkey == *pubkey {
+                        change += output.value as i64;
+                        touched = true;
+                    }
+                }
+
+                if touched && let Ok(hash) = transaction.hash() {
+                    history.push((height as u64, hash, change));
                 }
             }
         }
 
-        let inputs: u64 = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect(UNEXPECTED_BUG)
-                    .1
-                    .value
-            })
-            .sum();
-        let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        history
+    }
 
-        if inputs < outputs {
-            println!("inputs lower than outputs");
-            return Err(BtcError::InvalidTransaction);
+    // unspent outputs paying pubkey and their total, found by walking every block
+    // rather than trusting the utxo set, for keys imported after the fact
+    pub fn rescan(&self, pubkey: &PublicKey) -> (Vec<TransactionOutput>, u64) {
+        let mut unspent: HashMap<Hash, &TransactionOutput> = HashMap::new();
+
+        for block in &self.blocks {
+            for transaction in &block.transactions {
+                for input in &transaction.inputs {
+                    unspent.remove(&input.prev_transaction_output_hash);
+                }
+
+                for output in &transaction.outputs {
+                    if output.pubkey == *pubkey
+                        && let Ok(hash) = output.hash()
+                    {
+                        unspent.insert(hash, output);
+                    }
+                }
+            }
         }
 
-        // mark utxos referenced by transactions as used
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
+        let utxos = unspent.into_values().cloned().collect::<Vec<_>>();
+        let balance = utxos.iter().map(|output| output.value).sum();
+        (utxos, balance)
+    }
+
+    // transactions holding unspent outputs to pubkey, with proofs a light client checks
+    // against its headers, one per transaction however many of the outputs are the key's
+    pub fn utxo_proofs(&self, pubkey: &PublicKey) -> Result<Vec<UtxoProof>> {
+        let mut proofs = vec![];
+        for (block, height) in self.blocks.iter().zip(0..) {
+            for (index, transaction) in block.transactions.iter().enumerate() {
+                let mut outputs = vec![];
+                for (output, position) in transaction.outputs.iter().zip(0..) {
+                    if output.pubkey == *pubkey && self.utxos.contains(&output.hash()?)? {
+                        outputs.push(position);
+                    }
+                }
+                if outputs.is_empty() {
+                    continue;
+                }
+
+                let proof = MerkleRoot::prove(&block.transactions, index)
+                    .ok_or(BtcError::InvalidMerkleRoot)?;
+                proofs.push(UtxoProof {
+                    height,
+                    transaction: transaction.clone(),
+                    outputs,
+                    proof,
                 });
+            }
         }
 
-        self.mempool.push((Utc::now(), transaction));
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let inputs: u64 = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect(UNEXPECTED_BUG)
-                        .1
-                        .value
-                })
-                .sum();
+        Ok(proofs)
+    }
 
-            let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+    // height of the block holding the transaction, None if it isn't in the chain
+    pub fn transaction_height(&self, hash: &Hash) -> Option<u64> {
+        self.transaction_heights.get(hash).copied()
+    }
 
-            #[allow(clippy::let_and_return)]
-            let miner_fee = inputs - outputs;
-            miner_fee
-        });
-        Ok(())
+    // blocks holding or built on the transaction, 0 while it waits in the mempool
+    // and None for a transaction we don't know
+    pub fn confirmations(&self, hash: &Hash) -> Option<u64> {
+        if let Some(height) = self.transaction_height(hash) {
+            return Some(self.block_height() - height);
+        }
+
+        self.mempool
+            .iter()
+            .any(|(_, transaction)| {
+                transaction
+                    .hash()
+                    .is_ok_and(|mempool_hash| mempool_hash == *hash)
+            })
+            .then_some(0)
     }
 
-    pub fn cleanup_mempool(&mut self) -> Result<()> {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+    // hash over the utxo set sorted by output hash, equal on nodes that agree on it
+    // mempool marks are local to a node and left out
+    pub fn utxo_commitment(&self) -> Result<Hash> {
+        let mut utxos: Vec<(Hash, TransactionOutput)> = self
+            .utxos
+            .iter()?
+            .map(|(hash, (_, output))| (hash, output))
+            .collect();
+        utxos.sort_unstable_by_key(|(hash, _)| hash.as_bytes());
 
-        self.mempool.retain(|(datetime, transaction)| {
-            if now - datetime > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
-                utxo_hashes_to_unmark.extend(
-                    transaction
-                        .inputs
-                        .iter()
-                        .map(|input| input.prev_transaction_output_hash),
-                );
-                false
-            } else {
-                true
+        Hash::hash(&utxos)
+    }
+
+    // coins in existence, the value of every unspent output
+    pub fn circulating_supply(&self) -> Result<u64> {
+        Ok(self
+            .utxos
+            .iter()?
+            .map(|(_, (_, output))| output.value)
+            .sum())
+    }
+
+    // height and hash of the transaction that spent output_hash, None while unspent
+    pub fn find_spender(&self, output_hash: &Hash) -> Option<(u64, Hash)> {
+        self.spenders.get(output_hash).copied()
+    }
+
+    // the utxo changes block would make on top of the current set, without applying it
+    // the block isn't validated, only its inputs have to exist
+    pub fn simulate_block(&self, block: &Block) -> Result<UtxoDelta> {
+        let mut delta = UtxoDelta::default();
+
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                let hash = input.prev_transaction_output_hash;
+                if let Some(index) = delta.added.iter().position(|(added, _)| *added == hash) {
+                    delta.added.remove(index);
+                    continue;
+                }
+
+                let (_, output) = self
+                    .utxos
+                    .get(&hash)?
+                    .ok_or(BtcError::UnknownOutput(hash))?;
+                delta.removed.push((hash, output));
             }
-        });
 
-        for hash in utxo_hashes_to_unmark {
-            self.utxos
-                .entry(hash)
-                .and_modify(|(marked, _)| *marked = false);
+            for output in &transaction.outputs {
+                delta.added.push((output.hash()?, output.clone()));
+            }
+        }
+
+        let added: u64 = delta.added.iter().map(|(_, output)| output.value).sum();
+        let removed: u64 = delta.removed.iter().map(|(_, output)| output.value).sum();
+        delta.supply_change = added as i64 - removed as i64;
+
+        Ok(delta)
+    }
+
+    // a copy with only what a chain file needs, the blocks and the target they lead to
+    // every block is validated again on the way, utxos are left for rebuild_utxos
+    pub fn compacted(&self) -> Result<Self> {
+        let mut compacted = Self::with_params(self.params.clone());
+        for block in &self.blocks {
+            compacted.add_block(block.clone())?;
+            if let Some(block) = compacted.blocks.last() {
+                connect_utxos(&mut *compacted.utxos, block)?;
+            }
+        }
+
+        compacted.utxos.clear()?;
+        compacted.spenders.clear();
+        compacted.coinbase_heights.clear();
+        compacted.transaction_heights.clear();
+        compacted.block_heights.clear();
+        Ok(compacted)
+    }
+
+    pub fn rebuild_utxos(&mut self) -> Result<()> {
+        // start from scratch so nothing stale survives, every utxo starts unmarked
+        self.utxos.clear()?;
+        self.spenders.clear();
+        self.coinbase_heights.clear();
+        self.transaction_heights.clear();
+        self.block_heights.clear();
+
+        for (height, block) in self.blocks.iter().enumerate() {
+            index_spenders(&mut self.spenders, height as u64, block)?;
+            index_coinbase(&mut self.coinbase_heights, height as u64, block)?;
+            index_transactions(&mut self.transaction_heights, height as u64, block)?;
+            self.block_heights.insert(block.hash()?, height as u64);
+        }
+
+        for block in &self.blocks {
+            connect_utxos(&mut *self.utxos, block)?;
+        }
+
+        // only utxos spent by a transaction still in the mempool stay marked
+        for (_, transaction) in &self.mempool {
+            for input in &transaction.inputs {
+                self.utxos
+                    .set_marked(&input.prev_transaction_output_hash, true)?;
+            }
         }
 
         Ok(())
     }
-}
 
-impl Default for Blockchain {
-    fn default() -> Self {
-        Self::new()
+    // point every block's prev_block_hash at its parent's header hash, in order since
+    // each relinked header hashes differently, for chains saved before header hashes
+    fn relink_blocks(&mut self) -> Result<()> {
+        let mut prev_block_hash = Hash::zero();
+        for block in &mut self.blocks {
+            block.header.prev_block_hash = prev_block_hash;
+            prev_block_hash = block.hash()?;
+        }
+
+        Ok(())
     }
-}
 
-impl Saveable for Blockchain {
-    fn load<I: std::io::Read>(reader: I) -> std::io::Result<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialise blockchain"))
+    // write every block as a length-prefixed cbor frame, the same framing as on the wire
+    pub fn export_bootstrap(&self, mut writer: impl Write) -> std::io::Result<()> {
+        for block in &self.blocks {
+            write_block_frame(&mut writer, block)?;
+        }
+
+        writer.flush()
+    }
+
+    // apply blocks written by export_bootstrap in order, returns how many were added
+    // a block that is cut off, doesn't decode or doesn't validate ends the import,
+    // the ones before it stay
+    pub fn import_bootstrap(&mut self, mut reader: impl Read) -> std::io::Result<u64> {
+        let mut imported = 0;
+
+        loop {
+            let data = match read_frame(&mut reader) {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e) if e.kind() == IoErrorKind::UnexpectedEof => {
+                    println!("bootstrap file ends in the middle of block {imported}");
+                    break;
+                }
+                Err(e) if e.kind() == IoErrorKind::InvalidData => {
+                    println!("bootstrap block {imported} is malformed: {e}");
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let block: Block = match ciborium::from_reader(data.as_slice()) {
+                Ok(block) => block,
+                Err(e) => {
+                    println!("bootstrap block {imported} is malformed: {e}");
+                    break;
+                }
+            };
+            if let Err(e) = self.add_block(block) {
+                println!("bootstrap block {imported} rejected: {e}");
+                break;
+            }
+
+            // the next block is verified against these, rebuilding every time would be quadratic
+            if let Some(block) = self.blocks.last() {
+                connect_utxos(&mut *self.utxos, block).map_err(IoError::other)?;
+            }
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    // append the blocks the block log at path doesn't have yet, returns how many were written
+    // blocks are never removed, so only new ones need writing instead of the whole chain
+    pub fn save_appending(&mut self, path: impl AsRef<Path>) -> std::io::Result<u64> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+
+        let new_blocks = &self.blocks[self.persisted_height as usize..];
+        for block in new_blocks {
+            write_block_frame(&mut writer, block)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+
+        let written = new_blocks.len() as u64;
+        self.persisted_height = self.block_height();
+        Ok(written)
+    }
+
+    // read a block log written by save_appending, trusting it like load_from_file does
+    // a block cut off by a crash mid-write is dropped from the file, so appending carries on cleanly
+    pub fn load_appended(path: impl AsRef<Path>, params: ChainParams) -> std::io::Result<Self> {
+        let mut blockchain = Self::with_params(params);
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut valid_length = 0;
+
+        loop {
+            let data = match read_frame(&mut reader) {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e) if e.kind() == IoErrorKind::UnexpectedEof => {
+                    println!("block log ends in the middle of a block, dropping it");
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(valid_length)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let block: Block = ciborium::from_reader(data.as_slice()).map_err(|_| {
+                IoError::new(IoErrorKind::InvalidData, "Failed to deserialise block")
+            })?;
+            valid_length += 8 + data.len() as u64;
+
+            // same order as add_block, so the target ends up where it was
+            blockchain.try_adjust_target();
+            blockchain.blocks.push(block);
+        }
+
+        blockchain.persisted_height = blockchain.block_height();
+        Ok(blockchain)
+    }
+
+    // every marked utxo must be spent by some mempool transaction
+    pub fn mempool_is_consistent(&self) -> bool {
+        let spent_by_mempool: HashSet<Hash> = self
+            .mempool
+            .iter()
+            .flat_map(|(_, transaction)| &transaction.inputs)
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+
+        self.utxos.iter().is_ok_and(|utxos| {
+            utxos
+                .filter(|(_, (marked, _))| *marked)
+                .all(|(hash, _)| spent_by_mempool.contains(&hash))
+        })
+    }
+
+    // returns the old and new target when the target actually changed
+    pub fn try_adjust_target(&mut self) -> Option<(U256, U256)> {
+        // let N = block count interval to update difficulty
+        // return early if N blocks have not passed
+        let end_header = self.tip_header()?;
+
+        if !self
+            .blocks
+            .len()
+            .is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL as usize)
+        {
+            return None;
+        }
+
+        let start_header =
+            &self.blocks[self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize].header;
+        let new_target = consensus::retarget(self.target, start_header, end_header);
+
+        let old_target = self.target;
+        self.target = new_target;
+
+        (self.target != old_target).then_some((old_target, self.target))
+    }
+
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<MempoolAccept> {
+        if !self.params.mempool_enabled {
+            return Err(BtcError::MempoolDisabled);
+        }
+
+        let hash = transaction.hash()?;
+        if self
+            .mempool
+            .iter()
+            .any(|(_, existing)| existing.hash().is_ok_and(|existing| existing == hash))
+        {
+            return Ok(MempoolAccept::AlreadyPresent);
+        }
+
+        // the next block is the earliest a mempool transaction can be mined in
+        if let Some(locktime) = transaction.locktime
+            && !transaction.is_final(self.block_height())
+        {
+            return Err(BtcError::TimeLocked(locktime));
+        }
+
+        transaction.check_limits(&self.params)?;
+        self.check_maturity(&transaction, self.block_height())?;
+
+        // validate inputs
+        // input must come from a know utxo and be unique to prevent double spends
+        let mut prev_outputs = HashMap::new();
+        for input in &transaction.inputs {
+            let Some((_, output)) = self.utxos.get(&input.prev_transaction_output_hash)? else {
+                return Err(BtcError::UnknownOutput(input.prev_transaction_output_hash));
+            };
+
+            if prev_outputs
+                .insert(input.prev_transaction_output_hash, output)
+                .is_some()
+            {
+                return Err(BtcError::DoubleSpend(input.prev_transaction_output_hash));
+            }
+        }
+
+        // an output hash already in use would overwrite that utxo once mined
+        let mut output_hashes = HashSet::new();
+        for output in &transaction.outputs {
+            let hash = output.hash()?;
+            if self.utxos.contains(&hash)? || !output_hashes.insert(hash) {
+                return Err(BtcError::DuplicateOutput(hash));
+            }
+        }
+
+        let inputs: u64 = prev_outputs.values().map(|output| output.value).sum();
+        let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+
+        if inputs < outputs {
+            return Err(BtcError::Overspend { inputs, outputs });
+        }
+
+        if inputs - outputs < self.params.min_relay_fee {
+            return Err(BtcError::FeeTooLow {
+                fee: inputs - outputs,
+                minimum: self.params.min_relay_fee,
+            });
+        }
+
+        // last, it's the expensive check, and what keeps others from replacing our transactions
+        transaction.verify_signatures(&prev_outputs)?;
+
+        // when more than one mempool transaction references the same utxo, let the latest one win, and evict the previous one
+        // nothing is touched before this point, a rejected replacement leaves the mempool as it was
+        let mut replaced = vec![];
+        for input in &transaction.inputs {
+            // utxo is marked as true when it is being spent my some transaction in the mempool
+            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash)? {
+                // find the transaction spending the same utxo
+                let referencing_transaction = self.mempool.iter().position(|(_, transaction)| {
+                    transaction.inputs.iter().any(|other| {
+                        other.prev_transaction_output_hash == input.prev_transaction_output_hash
+                    })
+                });
+
+                if let Some(i) = referencing_transaction {
+                    // remove the earlier transaction, mark all of its utxo outputs as unused
+                    let (_, transaction) = self.mempool.remove(i);
+                    for input in &transaction.inputs {
+                        self.utxos
+                            .set_marked(&input.prev_transaction_output_hash, false)?;
+                    }
+                    replaced.push(transaction.hash()?);
+                } else {
+                    self.utxos
+                        .set_marked(&input.prev_transaction_output_hash, false)?;
+                }
+            }
+        }
+
+        // mark utxos referenced by transactions as used
+        for input in &transaction.inputs {
+            self.utxos
+                .set_marked(&input.prev_transaction_output_hash, true)?;
+        }
+
+        self.mempool.push((Utc::now(), transaction));
+        if !replaced.is_empty() {
+            // children of the evicted transaction can no longer be mined
+            self.drop_orphaned_transactions()?;
+        }
+        self.sort_mempool()?;
+
+        Ok(if replaced.is_empty() {
+            MempoolAccept::Added
+        } else {
+            MempoolAccept::Replaced(replaced)
+        })
+    }
+
+    // offer saved transactions to the mempool again, validated against the current utxos
+    // those mined, conflicting or expired since are dropped, returns how many were kept
+    pub fn restore_mempool(&mut self, saved: SavedMempool) -> Result<usize> {
+        let SavedMempool(mut saved) = saved;
+        let now = Utc::now();
+        let max_age = chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        saved.retain(|(accepted, _)| now - *accepted <= max_age);
+        saved.sort_by_key(|(accepted, _)| *accepted);
+
+        // a package is accepted all at once, so transactions sharing a time go back in together
+        let before = self.mempool.len();
+        for package in saved.chunk_by(|(a, _), (b, _)| a == b) {
+            let accepted = package[0].0;
+            let package = dependency_order(package.iter().map(|(_, tx)| tx.clone()).collect())?;
+            let mut hashes = HashSet::new();
+            for transaction in &package {
+                hashes.insert(transaction.hash()?);
+            }
+
+            if self.add_package_to_mempool(package).is_err() {
+                continue;
+            }
+            // keep their age, so they still expire when they would have
+            for (time, transaction) in &mut self.mempool {
+                if hashes.contains(&transaction.hash()?) {
+                    *time = accepted;
+                }
+            }
+        }
+
+        Ok(self.mempool.len() - before)
+    }
+
+    // accept transactions that only pay enough together, e.g. a parent below the relay fee
+    // with a child paying for both, as long as their combined fee covers one relay fee each
+    // the package is in dependency order, later transactions may spend outputs of earlier ones
+    // or of the mempool, and unlike a single transaction it never replaces mempool transactions
+    pub fn add_package_to_mempool(&mut self, package: Vec<Transaction>) -> Result<MempoolAccept> {
+        if !self.params.mempool_enabled {
+            return Err(BtcError::MempoolDisabled);
+        }
+
+        let mut known = HashSet::new();
+        for (_, transaction) in &self.mempool {
+            known.insert(transaction.hash()?);
+        }
+        let mut new = vec![];
+        for transaction in package {
+            if !known.contains(&transaction.hash()?) {
+                new.push(transaction);
+            }
+        }
+        if new.is_empty() {
+            return Ok(MempoolAccept::AlreadyPresent);
+        }
+
+        // outputs the package may spend besides the utxos, and everything already spent
+        let mut available = HashMap::new();
+        let mut spent = HashSet::new();
+        for (_, transaction) in &self.mempool {
+            for output in &transaction.outputs {
+                available.insert(output.hash()?, output.clone());
+            }
+            spent.extend(
+                transaction
+                    .inputs
+                    .iter()
+                    .map(|input| input.prev_transaction_output_hash),
+            );
+        }
+
+        let mut fee = 0;
+        for transaction in &new {
+            if let Some(locktime) = transaction.locktime
+                && !transaction.is_final(self.block_height())
+            {
+                return Err(BtcError::TimeLocked(locktime));
+            }
+
+            transaction.check_limits(&self.params)?;
+            self.check_maturity(transaction, self.block_height())?;
+
+            let mut inputs = 0;
+            let mut prev_outputs = HashMap::new();
+            for input in &transaction.inputs {
+                let hash = input.prev_transaction_output_hash;
+                let output = match self.utxos.get(&hash)? {
+                    Some((_, output)) => output,
+                    None => available
+                        .get(&hash)
+                        .cloned()
+                        .ok_or(BtcError::UnknownOutput(hash))?,
+                };
+                if !spent.insert(hash) {
+                    return Err(BtcError::DoubleSpend(hash));
+                }
+                inputs += output.value;
+                prev_outputs.insert(hash, output);
+            }
+            transaction.verify_signatures(&prev_outputs)?;
+
+            for output in &transaction.outputs {
+                let hash = output.hash()?;
+                if self.utxos.contains(&hash)? || available.insert(hash, output.clone()).is_some() {
+                    return Err(BtcError::DuplicateOutput(hash));
+                }
+            }
+
+            let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+            if inputs < outputs {
+                return Err(BtcError::Overspend { inputs, outputs });
+            }
+            fee += inputs - outputs;
+        }
+
+        let minimum = self.params.min_relay_fee * new.len() as u64;
+        if fee < minimum {
+            return Err(BtcError::FeeTooLow { fee, minimum });
+        }
+
+        // only confirmed utxos are marked, outputs of mempool transactions aren't in the store
+        let now = Utc::now();
+        for transaction in new {
+            for input in &transaction.inputs {
+                self.utxos
+                    .set_marked(&input.prev_transaction_output_hash, true)?;
+            }
+            self.mempool.push((now, transaction));
+        }
+        self.sort_mempool()?;
+
+        Ok(MempoolAccept::Added)
+    }
+
+    // ascending by fee, where a transaction spending mempool outputs counts the average fee
+    // of itself and its mempool ancestors, so a well paying child lifts its parents
+    fn sort_mempool(&mut self) -> Result<()> {
+        // fees are looked up front, the store can fail and sort keys can't
+        let mut fees = HashMap::new();
+        for (_, transaction) in &self.mempool {
+            let inputs = self.input_value(transaction)?;
+            let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+            fees.insert(transaction.hash()?, inputs.saturating_sub(outputs));
+        }
+
+        let parents = self.mempool_parents()?;
+        let mut scores = HashMap::new();
+        for (hash, fee) in &fees {
+            let mut ancestors = vec![];
+            mempool_ancestors(hash, &parents, &mut ancestors);
+            let total: u64 = fee + ancestors.iter().map(|ancestor| fees[ancestor]).sum::<u64>();
+            scores.insert(*hash, total / (ancestors.len() as u64 + 1));
+        }
+
+        self.mempool.sort_by_key(|(_, transaction)| {
+            transaction
+                .hash()
+                .ok()
+                .and_then(|hash| scores.get(&hash).copied())
+                .unwrap_or_default()
+        });
+
+        Ok(())
+    }
+
+    // mempool transaction hash -> hashes of the mempool transactions it spends outputs of
+    fn mempool_parents(&self) -> Result<HashMap<Hash, Vec<Hash>>> {
+        let mut creators = HashMap::new();
+        for (_, transaction) in &self.mempool {
+            let hash = transaction.hash()?;
+            for output in &transaction.outputs {
+                creators.insert(output.hash()?, hash);
+            }
+        }
+
+        let mut parents = HashMap::new();
+        for (_, transaction) in &self.mempool {
+            let spends = transaction
+                .inputs
+                .iter()
+                .filter_map(|input| creators.get(&input.prev_transaction_output_hash).copied())
+                .collect();
+            parents.insert(transaction.hash()?, spends);
+        }
+
+        Ok(parents)
+    }
+
+    // remove mempool transactions spending outputs that are neither utxos nor created
+    // in the mempool, until none are left, unmarking the utxos they held
+    fn drop_orphaned_transactions(&mut self) -> Result<()> {
+        loop {
+            let mut created = HashSet::new();
+            for (_, transaction) in &self.mempool {
+                for output in &transaction.outputs {
+                    created.insert(output.hash()?);
+                }
+            }
+
+            let mut orphaned = None;
+            for (i, (_, transaction)) in self.mempool.iter().enumerate() {
+                for input in &transaction.inputs {
+                    let hash = input.prev_transaction_output_hash;
+                    if !created.contains(&hash) && !self.utxos.contains(&hash)? {
+                        orphaned = Some(i);
+                    }
+                }
+            }
+
+            let Some(i) = orphaned else {
+                return Ok(());
+            };
+            let (_, transaction) = self.mempool.remove(i);
+            for input in &transaction.inputs {
+                self.utxos
+                    .set_marked(&input.prev_transaction_output_hash, false)?;
+            }
+        }
+    }
+
+    // drop mempool transactions spending any of these outputs, along with their descendants,
+    // and unmark what they spent
+    fn evict_conflicts(&mut self, mut spent: HashSet<Hash>) -> Result<()> {
+        loop {
+            let Some(i) = self.mempool.iter().position(|(_, transaction)| {
+                transaction
+                    .inputs
+                    .iter()
+                    .any(|input| spent.contains(&input.prev_transaction_output_hash))
+            }) else {
+                return Ok(());
+            };
+
+            let (_, transaction) = self.mempool.remove(i);
+            for input in &transaction.inputs {
+                self.utxos
+                    .set_marked(&input.prev_transaction_output_hash, false)?;
+            }
+            // children spending its outputs go with it
+            for output in &transaction.outputs {
+                spent.insert(output.hash()?);
+            }
+        }
+    }
+
+    // coinbase outputs can't be spent until enough blocks are built on top of them
+    fn check_maturity(&self, transaction: &Transaction, height: u64) -> Result<()> {
+        for input in &transaction.inputs {
+            if let Some(coinbase_height) = self
+                .coinbase_heights
+                .get(&input.prev_transaction_output_hash)
+                && height.saturating_sub(*coinbase_height) < self.params.coinbase_maturity
+            {
+                return Err(BtcError::ImmatureCoinbase(
+                    input.prev_transaction_output_hash,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // mempool bytes per fee rate bucket, in satoshis per byte, lowest rate first
+    // a bucket starts at a power of two and holds rates up to the next one, 0 holds free transactions
+    pub fn mempool_fee_histogram(&self) -> Result<Vec<(u64, u64)>> {
+        let mut buckets: BTreeMap<u64, u64> = BTreeMap::new();
+        for (_, transaction) in &self.mempool {
+            let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+            let fee = self.input_value(transaction)?.saturating_sub(outputs);
+            let size = transaction.serialized_size().max(1) as u64;
+
+            let rate = fee / size;
+            let bucket = match rate {
+                0 => 0,
+                rate => 1 << rate.ilog2(),
+            };
+            *buckets.entry(bucket).or_default() += size;
+        }
+
+        Ok(buckets.into_iter().collect())
+    }
+
+    // total value of the outputs a transaction spends, utxos or outputs of mempool transactions
+    fn input_value(&self, transaction: &Transaction) -> Result<u64> {
+        transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                let hash = input.prev_transaction_output_hash;
+                if let Some((_, output)) = self.utxos.get(&hash)? {
+                    return Ok(output.value);
+                }
+
+                self.mempool
+                    .iter()
+                    .flat_map(|(_, transaction)| &transaction.outputs)
+                    .find(|output| output.hash().is_ok_and(|output| output == hash))
+                    .map(|output| output.value)
+                    .ok_or(BtcError::InvalidTransaction)
+            })
+            .sum()
+    }
+
+    pub fn cleanup_mempool(&mut self) -> Result<()> {
+        if !self.params.mempool_enabled {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+
+        self.mempool.retain(|(datetime, transaction)| {
+            if now - datetime > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
+            {
+                utxo_hashes_to_unmark.extend(
+                    transaction
+                        .inputs
+                        .iter()
+                        .map(|input| input.prev_transaction_output_hash),
+                );
+                false
+            } else {
+                true
+            }
+        });
+
+        for hash in utxo_hashes_to_unmark {
+            self.utxos.set_marked(&hash, false)?;
+        }
+
+        // a parent can expire before the children spending it
+        self.drop_orphaned_transactions()
+    }
+}
+
+// parents before the children spending their outputs
+fn dependency_order(mut transactions: Vec<Transaction>) -> Result<Vec<Transaction>> {
+    let mut ordered = vec![];
+    while !transactions.is_empty() {
+        let mut pending = HashSet::new();
+        for transaction in &transactions {
+            for output in &transaction.outputs {
+                pending.insert(output.hash()?);
+            }
+        }
+
+        // without a transaction free of pending parents the rest is kept as it is
+        let next = transactions
+            .iter()
+            .position(|transaction| {
+                transaction
+                    .inputs
+                    .iter()
+                    .all(|input| !pending.contains(&input.prev_transaction_output_hash))
+            })
+            .unwrap_or_default();
+        ordered.push(transactions.remove(next));
+    }
+
+    Ok(ordered)
+}
+
+fn write_block_frame(writer: &mut impl Write, block: &Block) -> std::io::Result<()> {
+    let mut bytes = vec![];
+    ciborium::into_writer(block, &mut bytes)
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialise block"))?;
+    writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    writer.write_all(&bytes)
+}
+
+// body of the next length-prefixed frame, None at a clean end between frames
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0u8; 8];
+    let mut filled = 0;
+    while filled < length_bytes.len() {
+        match reader.read(&mut length_bytes[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(IoErrorKind::UnexpectedEof.into()),
+            read => filled += read,
+        }
+    }
+
+    // a corrupt length would otherwise have us allocate whatever it says
+    let length = u64::from_be_bytes(length_bytes);
+    if length > crate::MAX_MESSAGE_SIZE {
+        return Err(IoError::new(
+            IoErrorKind::InvalidData,
+            format!("Block frame of {length} bytes is over the size limit"),
+        ));
+    }
+
+    let mut data = vec![0u8; length as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+fn connect_utxos(utxos: &mut dyn UtxoStore, block: &Block) -> Result<()> {
+    for transaction in &block.transactions {
+        // old utxos have been spent
+        for input in &transaction.inputs {
+            utxos.remove(&input.prev_transaction_output_hash)?;
+        }
+
+        // create new utxos, keyed by the output hash inputs refer to
+        for output in &transaction.outputs {
+            utxos.insert(output.hash()?, (false, output.clone()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn index_coinbase(
+    coinbase_heights: &mut HashMap<Hash, u64>,
+    height: u64,
+    block: &Block,
+) -> Result<()> {
+    if let Some(coinbase) = block.coinbase() {
+        for output in &coinbase.outputs {
+            coinbase_heights.insert(output.hash()?, height);
+        }
+    }
+
+    Ok(())
+}
+
+// the utxo set is saved as a plain map whatever store holds it
+#[allow(clippy::borrowed_box)]
+fn serialize_utxos<S: Serializer>(
+    utxos: &Box<dyn UtxoStore>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let utxos: HashMap<Hash, Utxo> = utxos.iter().map_err(S::Error::custom)?.collect();
+    utxos.serialize(serializer)
+}
+
+fn deserialize_utxos<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Box<dyn UtxoStore>, D::Error> {
+    Ok(Box::new(HashMap::<Hash, Utxo>::deserialize(deserializer)?))
+}
+
+fn index_transactions(
+    transaction_heights: &mut HashMap<Hash, u64>,
+    height: u64,
+    block: &Block,
+) -> Result<()> {
+    for transaction in &block.transactions {
+        transaction_heights.insert(transaction.hash()?, height);
+    }
+
+    Ok(())
+}
+
+// mempool ancestors of a transaction, each pushed after its own ancestors
+fn mempool_ancestors(hash: &Hash, parents: &HashMap<Hash, Vec<Hash>>, found: &mut Vec<Hash>) {
+    for parent in parents.get(hash).into_iter().flatten() {
+        if !found.contains(parent) {
+            mempool_ancestors(parent, parents, found);
+            found.push(*parent);
+        }
+    }
+}
+
+fn index_spenders(
+    spenders: &mut HashMap<Hash, (u64, Hash)>,
+    height: u64,
+    block: &Block,
+) -> Result<()> {
+    for transaction in &block.transactions {
+        let hash = transaction.hash()?;
+        for input in &transaction.inputs {
+            spenders.insert(input.prev_transaction_output_hash, (height, hash));
+        }
+    }
+
+    Ok(())
+}
+
+// depth first search over include/exclude choices for a subset summing into needed..=upper
+#[allow(clippy::too_many_arguments)]
+fn branch_and_bound(
+    values: &[u64],
+    index: usize,
+    selected: u64,
+    remaining: u64,
+    needed: u64,
+    upper: u64,
+    picked: &mut Vec<usize>,
+    tries: &mut u32,
+) -> bool {
+    if selected >= needed {
+        return selected <= upper;
+    }
+
+    // out of candidates, out of budget, or can't reach the target anymore
+    if index == values.len() || *tries == 0 || selected + remaining < needed {
+        return false;
+    }
+    *tries -= 1;
+
+    let value = values[index];
+    picked.push(index);
+    if branch_and_bound(
+        values,
+        index + 1,
+        selected + value,
+        remaining - value,
+        needed,
+        upper,
+        picked,
+        tries,
+    ) {
+        return true;
+    }
+    picked.pop();
+
+    branch_and_bound(
+        values,
+        index + 1,
+        selected,
+        remaining - value,
+        needed,
+        upper,
+        picked,
+        tries,
+    )
+}
+
+// indices for a block locator over a chain of the given length, tip first
+pub(crate) fn locator_indices(len: usize) -> Vec<usize> {
+    let mut indices = vec![];
+    let Some(mut index) = len.checked_sub(1) else {
+        return indices;
+    };
+    let mut step = 1;
+
+    loop {
+        indices.push(index);
+        if index == 0 {
+            break;
+        }
+
+        if indices.len() >= 10 {
+            step *= 2;
+        }
+        index = index.saturating_sub(step);
+    }
+
+    indices
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Saveable for SavedMempool {
+    fn load<I: std::io::Read>(reader: I) -> std::io::Result<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialise mempool"))
+    }
+    fn save<O: std::io::Write>(&self, writer: O) -> std::io::Result<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialise mempool"))
+    }
+}
+
+impl Saveable for Blockchain {
+    fn load<I: std::io::Read>(reader: I) -> std::io::Result<Self> {
+        let mut blockchain: Self = ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialise blockchain")
+        })?;
+
+        if blockchain.format_version > FORMAT_VERSION {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!(
+                    "Unsupported blockchain format version {} (newest supported is {FORMAT_VERSION})",
+                    blockchain.format_version
+                ),
+            ));
+        }
+
+        if blockchain.format_version < HEADER_HASH_FORMAT_VERSION {
+            blockchain.relink_blocks().map_err(|e| {
+                IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("Failed to relink blocks by header hash: {e}"),
+                )
+            })?;
+        }
+
+        // older formats otherwise only lack fields that have defaults, upgrade them in place
+        blockchain.format_version = FORMAT_VERSION;
+        Ok(blockchain)
     }
     fn save<O: std::io::Write>(&self, writer: O) -> std::io::Result<()> {
         ciborium::ser::into_writer(self, writer)
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialise blockchain"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::PrivateKey, testutil::ChainBuilder, types::UnsignedTransaction};
+
+    // the key's unspent outputs, in a fixed order
+    fn outputs_of(blockchain: &Blockchain, key: &PrivateKey) -> Vec<TransactionOutput> {
+        let mut outputs: Vec<_> = blockchain
+            .utxos()
+            .iter_for_pubkey(&key.public_key())
+            .unwrap()
+            .map(|(hash, (_, output))| (hash, output))
+            .collect();
+        outputs.sort_by_key(|(hash, _)| hash.as_bytes());
+        outputs.into_iter().map(|(_, output)| output).collect()
+    }
+
+    // the inputs paid back to the key less fee
+    fn spend(
+        key: &mut PrivateKey,
+        inputs: &[TransactionOutput],
+        fee: u64,
+        locktime: Option<u64>,
+    ) -> Transaction {
+        let value: u64 = inputs.iter().map(|input| input.value).sum();
+        UnsignedTransaction {
+            inputs: inputs.to_vec(),
+            outputs: vec![TransactionOutput {
+                value: value - fee,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey: key.public_key(),
+            }],
+            locktime,
+        }
+        .sign(key)
+        .unwrap()
+    }
+
+    #[test]
+    fn mempool_rejects_transactions_locked_past_the_next_block() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(5).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+
+        let locked = spend(&mut keys[0], &inputs[..1], 0, Some(6));
+        assert!(matches!(
+            blockchain.add_to_mempool(locked),
+            Err(BtcError::TimeLocked(6))
+        ));
+        assert!(blockchain.mempool().is_empty());
+    }
+
+    #[test]
+    fn mempool_accepts_transactions_locked_to_the_next_block() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(5).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+
+        // the chain holds heights 0 to 4, the next block is at height 5
+        let locked = spend(&mut keys[0], &inputs[..1], 0, Some(5));
+        assert_eq!(
+            blockchain.add_to_mempool(locked).unwrap(),
+            MempoolAccept::Added
+        );
+    }
+
+    fn mempool_hashes(blockchain: &Blockchain) -> HashSet<Hash> {
+        blockchain
+            .mempool()
+            .iter()
+            .map(|(_, transaction)| transaction.hash().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn mempool_reports_added_and_already_present() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let transaction = spend(&mut keys[0], &inputs[..1], 10, None);
+
+        assert_eq!(
+            blockchain.add_to_mempool(transaction.clone()).unwrap(),
+            MempoolAccept::Added
+        );
+        assert_eq!(
+            blockchain.add_to_mempool(transaction).unwrap(),
+            MempoolAccept::AlreadyPresent
+        );
+        assert_eq!(blockchain.mempool().len(), 1);
+    }
+
+    #[test]
+    fn mempool_reports_the_replaced_transaction() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let first = spend(&mut keys[0], &inputs[..1], 10, None);
+        let second = spend(&mut keys[0], &inputs[..1], 20, None);
+        blockchain.add_to_mempool(first.clone()).unwrap();
+
+        assert_eq!(
+            blockchain.add_to_mempool(second.clone()).unwrap(),
+            MempoolAccept::Replaced(vec![first.hash().unwrap()])
+        );
+        assert_eq!(
+            mempool_hashes(&blockchain),
+            HashSet::from([second.hash().unwrap()])
+        );
+    }
+
+    #[test]
+    fn mempool_reports_every_replaced_transaction() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let first = spend(&mut keys[0], &inputs[..1], 10, None);
+        let second = spend(&mut keys[0], &inputs[1..], 10, None);
+        let both = spend(&mut keys[0], &inputs, 10, None);
+        blockchain.add_to_mempool(first.clone()).unwrap();
+        blockchain.add_to_mempool(second.clone()).unwrap();
+
+        assert_eq!(
+            blockchain.add_to_mempool(both.clone()).unwrap(),
+            MempoolAccept::Replaced(vec![first.hash().unwrap(), second.hash().unwrap()])
+        );
+        assert_eq!(
+            mempool_hashes(&blockchain),
+            HashSet::from([both.hash().unwrap()])
+        );
+    }
+
+    #[test]
+    fn rejected_replacements_leave_the_mempool_alone() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let original = spend(&mut keys[0], &inputs[..1], 10, None);
+        blockchain.add_to_mempool(original.clone()).unwrap();
+
+        // signed by someone who doesn't own the output
+        let mut thief = PrivateKey::new_key();
+        let forged = spend(&mut thief, &inputs[..1], 20, None);
+        assert!(matches!(
+            blockchain.add_to_mempool(forged),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        let mut overspend = spend(&mut keys[0], &inputs[..1], 0, None);
+        overspend.outputs[0].value += 1;
+        assert!(matches!(
+            blockchain.add_to_mempool(overspend),
+            Err(BtcError::Overspend { .. })
+        ));
+
+        assert_eq!(
+            mempool_hashes(&blockchain),
+            HashSet::from([original.hash().unwrap()])
+        );
+        let input = inputs[0].hash().unwrap();
+        assert!(matches!(
+            blockchain.utxos().get(&input).unwrap(),
+            Some((true, _))
+        ));
+    }
+
+    #[test]
+    fn block_whose_coinbase_commits_another_height_is_rejected() {
+        let mut blockchain = ChainBuilder::new().mine(2).build();
+        let miner = PrivateKey::new_key().public_key();
+
+        for height in [1, 3] {
+            let mut template = blockchain.build_template(&[(miner.clone(), 1)]).unwrap();
+            template.transactions[0].height = Some(height);
+            template.header.merkle_root = MerkleRoot::calculate(&template.transactions).unwrap();
+            while !template.header.mine(1_000).unwrap() {}
+
+            assert!(matches!(
+                blockchain.add_block(template),
+                Err(BtcError::InvalidTransaction)
+            ));
+        }
+
+        let mut template = blockchain.build_template(&[(miner, 1)]).unwrap();
+        while !template.header.mine(1_000).unwrap() {}
+        blockchain.add_block(template).unwrap();
+    }
+
+    // the newest coinbase of a chain, mined in the block before the next one
+    fn newest_coinbase(blockchain: &Blockchain) -> TransactionOutput {
+        blockchain.blocks_rev().next().unwrap().transactions[0].outputs[0].clone()
+    }
+
+    #[test]
+    fn regtest_spends_a_coinbase_in_the_next_block() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(3).build_with_keys();
+        let coinbase = newest_coinbase(&blockchain);
+
+        let spend = spend(&mut keys[0], &[coinbase], 0, None);
+        assert_eq!(
+            blockchain.add_to_mempool(spend).unwrap(),
+            MempoolAccept::Added
+        );
+    }
+
+    #[test]
+    fn mainnet_rules_hold_coinbases_and_require_fees() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(3).build_with_keys();
+        // the same chain, judged by mainnet rules
+        blockchain.params = ChainParams::mainnet();
+        let coinbase = newest_coinbase(&blockchain);
+
+        let young = spend(&mut keys[0], &[coinbase], 1_000, None);
+        assert!(matches!(
+            blockchain.add_to_mempool(young),
+            Err(BtcError::ImmatureCoinbase(_))
+        ));
+
+        blockchain.params.coinbase_maturity = 0;
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let free = spend(&mut keys[0], &inputs[..1], 0, None);
+        assert!(matches!(
+            blockchain.add_to_mempool(free),
+            Err(BtcError::FeeTooLow {
+                fee: 0,
+                minimum: 1_000
+            })
+        ));
+    }
+
+    #[test]
+    fn full_block_takes_the_best_paying_transactions() {
+        let cap = ChainParams::regtest().max_block_transactions;
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(cap as usize).build_with_keys();
+
+        // one more than fits next to the coinbase, each paying its own fee
+        let mut by_fee = vec![];
+        for (fee, input) in (1..).zip(outputs_of(&blockchain, &keys[0])) {
+            let transaction = spend(&mut keys[0], &[input], fee * 100, None);
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+            by_fee.push(transaction);
+        }
+        assert_eq!(by_fee.len() as u64, cap);
+
+        let next = blockchain.next_block_transactions().unwrap();
+        let hashes = |transactions: &[Transaction]| -> Vec<Hash> {
+            transactions.iter().map(|tx| tx.hash().unwrap()).collect()
+        };
+        // best paying first, the cheapest left for a later block
+        by_fee.reverse();
+        assert_eq!(hashes(&next), hashes(&by_fee[..by_fee.len() - 1]));
+
+        let fees: u64 = (2..=cap).map(|fee| fee * 100).sum();
+        assert_eq!(
+            blockchain.expected_coinbase_value().unwrap(),
+            Block::block_reward(blockchain.block_height()) + fees
+        );
+    }
+
+    // the input split into parts outputs back to the key
+    fn split(key: &mut PrivateKey, input: &TransactionOutput, parts: u64) -> Transaction {
+        UnsignedTransaction {
+            inputs: vec![input.clone()],
+            outputs: (0..parts)
+                .map(|_| TransactionOutput {
+                    value: input.value / parts,
+                    unique_id: uuid::Uuid::new_v4(),
+                    pubkey: key.public_key(),
+                })
+                .collect(),
+            locktime: None,
+        }
+        .sign(key)
+        .unwrap()
+    }
+
+    #[test]
+    fn mempool_holds_transactions_to_the_input_and_output_limits() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(3).build_with_keys();
+        blockchain.params.max_tx_inputs = 2;
+        blockchain.params.max_tx_outputs = 3;
+        let inputs = outputs_of(&blockchain, &keys[0]);
+
+        let too_many_inputs = spend(&mut keys[0], &inputs, 0, None);
+        assert!(matches!(
+            blockchain.add_to_mempool(too_many_inputs),
+            Err(BtcError::InvalidTransactionInput)
+        ));
+        let too_many_outputs = split(&mut keys[0], &inputs[0], 4);
+        assert!(matches!(
+            blockchain.add_to_mempool(too_many_outputs),
+            Err(BtcError::InvalidTransactionOutput)
+        ));
+
+        let at_the_limits = spend(&mut keys[0], &inputs[..2], 0, None);
+        blockchain.add_to_mempool(at_the_limits).unwrap();
+        blockchain
+            .add_to_mempool(split(&mut keys[0], &inputs[2], 3))
+            .unwrap();
+    }
+
+    #[test]
+    fn blocks_are_held_to_the_output_limit() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let input = outputs_of(&blockchain, &keys[0]).remove(0);
+        blockchain
+            .add_to_mempool(split(&mut keys[0], &input, 3))
+            .unwrap();
+
+        let mut block = blockchain
+            .build_template(&[(keys[0].public_key(), 1)])
+            .unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+
+        blockchain.params.max_tx_outputs = 2;
+        assert!(matches!(
+            blockchain.add_block(block.clone()),
+            Err(BtcError::InvalidTransactionOutput)
+        ));
+        blockchain.params.max_tx_outputs = 3;
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn empty_chain_builds_a_genesis_template() {
+        let mut blockchain = Blockchain::with_params(ChainParams::regtest());
+        let miner = PrivateKey::new_key().public_key();
+        assert!(blockchain.tip_header().is_none());
+
+        let mut template = blockchain.build_template(&[(miner, 1)]).unwrap();
+        assert_eq!(template.header.prev_block_hash, Hash::zero());
+        assert_eq!(template.transactions[0].coinbase_height(), Some(0));
+
+        while !template.header.mine(1_000).unwrap() {}
+        blockchain.add_block(template).unwrap();
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    // a header whose parent is the block at height
+    fn header_on(blockchain: &Blockchain, height: usize) -> BlockHeader {
+        let mut header = blockchain.tip_header().unwrap().clone();
+        header.prev_block_hash = blockchain.blocks[height].hash().unwrap();
+        header
+    }
+
+    #[test]
+    fn forks_deeper_than_the_reorg_limit_are_not_followed() {
+        let mut blockchain = ChainBuilder::new().mine(5).build();
+        blockchain.params.max_reorg_depth = 2;
+
+        // a fork on height 2 would disconnect the blocks at 3 and 4
+        assert_eq!(
+            blockchain.connects_to(&header_on(&blockchain, 2)).unwrap(),
+            Connection::Forks(2)
+        );
+        assert_eq!(
+            blockchain.connects_to(&header_on(&blockchain, 1)).unwrap(),
+            Connection::TooDeep(1)
+        );
+    }
+
+    // offer a genesis template with its transactions changed, merkle root kept in step
+    fn add_genesis_with(change: impl FnOnce(&mut Vec<Transaction>)) -> Result<()> {
+        let mut blockchain = Blockchain::with_params(ChainParams::regtest());
+        let miner = PrivateKey::new_key().public_key();
+        let mut genesis = blockchain.build_template(&[(miner, 1)]).unwrap();
+        change(&mut genesis.transactions);
+        if let Some(merkle_root) = MerkleRoot::calculate(&genesis.transactions) {
+            genesis.header.merkle_root = merkle_root;
+        }
+
+        blockchain.add_block(genesis)
+    }
+
+    #[test]
+    fn genesis_without_transactions_is_rejected() {
+        assert!(matches!(
+            add_genesis_with(|transactions| transactions.clear()),
+            Err(BtcError::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn genesis_coinbase_must_spend_nothing_name_height_zero_and_pay_the_reward() {
+        let spent = TransactionOutput {
+            value: 1,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: PrivateKey::new_key().public_key(),
+        };
+        type Change = Box<dyn FnOnce(&mut Vec<Transaction>)>;
+        let changes: [Change; 4] = [
+            Box::new(|transactions| {
+                let mut key = PrivateKey::new_key();
+                let input = spend(&mut key, &[spent], 0, None).inputs.remove(0);
+                transactions[0].inputs.push(input);
+            }),
+            Box::new(|transactions| transactions[0].height = None),
+            Box::new(|transactions| transactions[0].height = Some(1)),
+            Box::new(|transactions| transactions[0].outputs[0].value += 1),
+        ];
+
+        for change in changes {
+            assert!(matches!(
+                add_genesis_with(change),
+                Err(BtcError::InvalidTransaction)
+            ));
+        }
+        add_genesis_with(|_| {}).unwrap();
+    }
+
+    // a saved chain with its format version replaced, or removed as files before versioning
+    fn saved_with_format_version(blockchain: &Blockchain, version: Option<u32>) -> Vec<u8> {
+        let mut saved = vec![];
+        blockchain.save(&mut saved).unwrap();
+        let ciborium::Value::Map(mut fields) = ciborium::de::from_reader(saved.as_slice()).unwrap()
+        else {
+            panic!("a blockchain saves as a map");
+        };
+
+        fields.retain(|(key, _)| key.as_text() != Some("format_version"));
+        if let Some(version) = version {
+            fields.push(("format_version".into(), version.into()));
+        }
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&ciborium::Value::Map(fields), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn saved_chain_loads_at_the_current_format() {
+        let blockchain = ChainBuilder::new().mine(3).build();
+        let saved = saved_with_format_version(&blockchain, Some(FORMAT_VERSION));
+
+        let loaded = Blockchain::load(saved.as_slice()).unwrap();
+        assert_eq!(loaded.format_version, FORMAT_VERSION);
+        assert_eq!(loaded.block_height(), 3);
+        assert_eq!(loaded.tip_hash().unwrap(), blockchain.tip_hash().unwrap());
+    }
+
+    #[test]
+    fn newer_format_is_rejected() {
+        let blockchain = ChainBuilder::new().mine(1).build();
+        let saved = saved_with_format_version(&blockchain, Some(FORMAT_VERSION + 1));
+
+        let error = Blockchain::load(saved.as_slice()).unwrap_err();
+        assert!(error.to_string().contains("newest supported"));
+    }
+
+    #[test]
+    fn unversioned_and_full_hash_formats_are_upgraded() {
+        let blockchain = ChainBuilder::new().mine(3).build();
+        // before version 2 blocks linked to the hash of their whole parent block
+        let mut old = blockchain.clone();
+        for height in 1..old.blocks.len() {
+            old.blocks[height].header.prev_block_hash =
+                Hash::hash(&old.blocks[height - 1]).unwrap();
+        }
+
+        // files from before versioning load as version 0
+        for version in [None, Some(1)] {
+            let saved = saved_with_format_version(&old, version);
+            let mut loaded = Blockchain::load(saved.as_slice()).unwrap();
+            assert_eq!(loaded.format_version, FORMAT_VERSION);
+            assert_eq!(loaded.block_height(), 3);
+            for pair in loaded.blocks.windows(2) {
+                assert_eq!(pair[1].header.prev_block_hash, pair[0].hash().unwrap());
+            }
+
+            // the upgraded chain takes new blocks on its tip
+            loaded.rebuild_utxos().unwrap();
+            let mut block = loaded
+                .build_template(&[(PrivateKey::new_key().public_key(), 1)])
+                .unwrap();
+            while !block.header.mine(1_000).unwrap() {}
+            loaded.add_block(block).unwrap();
+            assert_eq!(loaded.block_height(), 4);
+        }
+    }
+
+    fn exported(blockchain: &Blockchain) -> Vec<u8> {
+        let mut bootstrap = vec![];
+        blockchain.export_bootstrap(&mut bootstrap).unwrap();
+        bootstrap
+    }
+
+    #[test]
+    fn bootstrap_round_trips() {
+        let blockchain = ChainBuilder::new().mine(4).build();
+
+        let mut imported = Blockchain::with_params(ChainParams::regtest());
+        let count = imported
+            .import_bootstrap(exported(&blockchain).as_slice())
+            .unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(imported.tip_hash().unwrap(), blockchain.tip_hash().unwrap());
+        assert_eq!(
+            imported.utxo_commitment().unwrap(),
+            blockchain.utxo_commitment().unwrap()
+        );
+    }
+
+    #[test]
+    fn truncated_bootstrap_keeps_the_blocks_before_the_cut() {
+        let blockchain = ChainBuilder::new().mine(3).build();
+        let mut bootstrap = exported(&blockchain);
+        bootstrap.truncate(bootstrap.len() - 1);
+
+        let mut imported = Blockchain::with_params(ChainParams::regtest());
+        assert_eq!(imported.import_bootstrap(bootstrap.as_slice()).unwrap(), 2);
+        assert_eq!(imported.block_height(), 2);
+    }
+
+    #[test]
+    fn corrupt_bootstrap_ends_the_import() {
+        let blockchain = ChainBuilder::new().mine(2).build();
+
+        // a length prefix far past the size limit, then one that isn't a block
+        let mut oversized = exported(&blockchain);
+        oversized.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut garbage = exported(&blockchain);
+        garbage.extend_from_slice(&3u64.to_be_bytes());
+        garbage.extend_from_slice(&[0xff; 3]);
+
+        for bootstrap in [oversized, garbage] {
+            let mut imported = Blockchain::with_params(ChainParams::regtest());
+            assert_eq!(imported.import_bootstrap(bootstrap.as_slice()).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_allocating() {
+        let frame = (crate::MAX_MESSAGE_SIZE + 1).to_be_bytes();
+        let error = read_frame(&mut frame.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+    }
+
+    struct TempFile(std::path::PathBuf);
+    impl TempFile {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("btc-log-{}", uuid::Uuid::new_v4())))
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn block_log_round_trips_and_appends_only_new_blocks() {
+        let log = TempFile::new();
+        let mut blockchain = ChainBuilder::new().mine(3).build();
+        assert_eq!(blockchain.save_appending(&log.0).unwrap(), 3);
+        assert_eq!(blockchain.save_appending(&log.0).unwrap(), 0);
+
+        let mut loaded = Blockchain::load_appended(&log.0, ChainParams::regtest()).unwrap();
+        assert_eq!(loaded.block_height(), 3);
+        assert_eq!(loaded.tip_hash().unwrap(), blockchain.tip_hash().unwrap());
+        assert_eq!(loaded.target(), blockchain.target());
+        loaded.rebuild_utxos().unwrap();
+        assert_eq!(
+            loaded.utxo_commitment().unwrap(),
+            blockchain.utxo_commitment().unwrap()
+        );
+    }
+
+    #[test]
+    fn torn_last_block_is_dropped_from_the_log() {
+        let log = TempFile::new();
+        let mut blockchain = ChainBuilder::new().mine(2).build();
+        blockchain.save_appending(&log.0).unwrap();
+        let intact = std::fs::metadata(&log.0).unwrap().len();
+
+        // a crash part way through writing the next frame
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        file.write_all(&100u64.to_be_bytes()).unwrap();
+        file.write_all(&[0; 10]).unwrap();
+        drop(file);
+
+        let mut loaded = Blockchain::load_appended(&log.0, ChainParams::regtest()).unwrap();
+        assert_eq!(loaded.block_height(), 2);
+        assert_eq!(std::fs::metadata(&log.0).unwrap().len(), intact);
+
+        // appending carries on from the last whole block
+        loaded.rebuild_utxos().unwrap();
+        let miner = PrivateKey::new_key().public_key();
+        let mut block = loaded.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+        loaded.add_block(block).unwrap();
+        assert_eq!(loaded.save_appending(&log.0).unwrap(), 1);
+        let reloaded = Blockchain::load_appended(&log.0, ChainParams::regtest()).unwrap();
+        assert_eq!(reloaded.tip_hash().unwrap(), loaded.tip_hash().unwrap());
+    }
+
+    #[test]
+    fn oversized_frame_in_the_log_is_an_error() {
+        let log = TempFile::new();
+        let mut blockchain = ChainBuilder::new().mine(1).build();
+        blockchain.save_appending(&log.0).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        file.write_all(&u64::MAX.to_be_bytes()).unwrap();
+        drop(file);
+
+        let error = Blockchain::load_appended(&log.0, ChainParams::regtest()).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+    }
+
+    // a chain about to retarget, its window a little faster than ideal
+    fn chain_at_retarget() -> Blockchain {
+        ChainBuilder::new()
+            .mine(crate::DIFFICULTY_UPDATE_INTERVAL as usize)
+            .build()
+    }
+
+    #[test]
+    fn retarget_from_the_smallest_target_keeps_it_above_zero() {
+        let mut blockchain = chain_at_retarget();
+        blockchain.target = U256::one();
+
+        assert_eq!(blockchain.try_adjust_target(), None);
+        assert_eq!(blockchain.target(), U256::one());
+    }
+
+    #[test]
+    fn retarget_from_the_largest_target_does_not_overflow() {
+        let mut blockchain = chain_at_retarget();
+        blockchain.target = U256::MAX;
+
+        assert_eq!(
+            blockchain.try_adjust_target(),
+            Some((U256::MAX, crate::MIN_TARGET))
+        );
+    }
+
+    #[test]
+    fn retarget_near_the_minimum_difficulty_follows_the_window() {
+        let mut blockchain = chain_at_retarget();
+        blockchain.target = crate::MIN_TARGET;
+
+        let (_, new_target) = blockchain.try_adjust_target().unwrap();
+        assert!(new_target < crate::MIN_TARGET);
+        assert!(new_target > crate::MIN_TARGET / 4);
+    }
+
+    #[test]
+    fn no_retarget_between_windows() {
+        let mut blockchain = ChainBuilder::new().mine(7).build();
+        blockchain.target = U256::one();
+
+        assert_eq!(blockchain.try_adjust_target(), None);
+        assert_eq!(blockchain.target(), U256::one());
+    }
+
+    #[test]
+    fn package_child_pays_for_its_parent() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        blockchain.params.min_relay_fee = 1_000;
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let parent = spend(&mut keys[0], &inputs[..1], 0, None);
+        let child = spend(&mut keys[0], &parent.outputs, 2_000, None);
+
+        // too cheap on its own
+        assert!(matches!(
+            blockchain.add_to_mempool(parent.clone()),
+            Err(BtcError::FeeTooLow { .. })
+        ));
+
+        let package = vec![parent.clone(), child.clone()];
+        assert_eq!(
+            blockchain.add_package_to_mempool(package.clone()).unwrap(),
+            MempoolAccept::Added
+        );
+        assert_eq!(
+            mempool_hashes(&blockchain),
+            HashSet::from([parent.hash().unwrap(), child.hash().unwrap()])
+        );
+        assert_eq!(
+            blockchain.add_package_to_mempool(package).unwrap(),
+            MempoolAccept::AlreadyPresent
+        );
+    }
+
+    #[test]
+    fn package_with_an_invalid_member_is_rejected_whole() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let parent = spend(&mut keys[0], &inputs[..1], 0, None);
+        // the child is signed by someone who doesn't own the parent's output
+        let child = spend(&mut PrivateKey::new_key(), &parent.outputs, 10, None);
+
+        assert!(matches!(
+            blockchain.add_package_to_mempool(vec![parent, child]),
+            Err(BtcError::InvalidSignature)
+        ));
+        assert!(blockchain.mempool().is_empty());
+        let input = inputs[0].hash().unwrap();
+        assert!(matches!(
+            blockchain.utxos().get(&input).unwrap(),
+            Some((false, _))
+        ));
+    }
+
+    #[test]
+    fn rebuilding_utxos_keeps_mempool_spends_marked() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        blockchain
+            .add_to_mempool(spend(&mut keys[0], &inputs[..1], 0, None))
+            .unwrap();
+
+        blockchain.rebuild_utxos().unwrap();
+
+        let marked = |output: &TransactionOutput| {
+            let (marked, _) = blockchain
+                .utxos()
+                .get(&output.hash().unwrap())
+                .unwrap()
+                .unwrap();
+            marked
+        };
+        assert!(marked(&inputs[0]));
+        assert!(!marked(&inputs[1]));
+    }
+
+    fn value_of(selected: &[(Hash, TransactionOutput)]) -> u64 {
+        selected.iter().map(|(_, output)| output.value).sum()
+    }
+
+    #[test]
+    fn coin_selection_prefers_an_exact_match() {
+        let (blockchain, keys) = ChainBuilder::new().mine(3).build_with_keys();
+        let pubkey = keys[0].public_key();
+        let reward = outputs_of(&blockchain, &keys[0])[0].value;
+
+        let selected = blockchain
+            .select_coins(&pubkey, reward * 2 - 10, 10)
+            .unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(value_of(&selected), reward * 2);
+    }
+
+    #[test]
+    fn coin_selection_fails_without_enough_funds() {
+        let (blockchain, keys) = ChainBuilder::new().mine(3).build_with_keys();
+        let pubkey = keys[0].public_key();
+        let reward = outputs_of(&blockchain, &keys[0])[0].value;
+
+        assert!(blockchain.select_coins(&pubkey, reward * 3, 1).is_none());
+        assert!(blockchain.select_coins(&pubkey, u64::MAX, 1).is_none());
+        assert!(
+            blockchain
+                .select_coins(&PrivateKey::new_key().public_key(), 1, 0)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn coin_selection_skips_outputs_spent_in_the_mempool() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(3).build_with_keys();
+        let pubkey = keys[0].public_key();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let reward = inputs[0].value;
+        blockchain
+            .add_to_mempool(spend(&mut keys[0], &inputs[..1], 0, None))
+            .unwrap();
+
+        assert!(blockchain.select_coins(&pubkey, reward * 3, 0).is_none());
+        let selected = blockchain.select_coins(&pubkey, reward * 2, 0).unwrap();
+        let spent = inputs[0].hash().unwrap();
+        assert!(selected.iter().all(|(hash, _)| *hash != spent));
+        assert_eq!(value_of(&selected), reward * 2);
+    }
+
+    // key 0 mines three blocks, paying 30 to key 1 in the last
+    fn chain_with_a_payment() -> (Blockchain, Vec<PrivateKey>) {
+        ChainBuilder::new()
+            .mine(2)
+            .spend(0, 1, 30)
+            .mine(1)
+            .build_with_keys()
+    }
+
+    #[test]
+    fn address_history_nets_what_each_transaction_moved() {
+        let (blockchain, keys) = chain_with_a_payment();
+        let hash_at = |height: usize, index: usize| {
+            blockchain.blocks[height].transactions[index]
+                .hash()
+                .unwrap()
+        };
+        let reward = Block::block_reward(0) as i64;
+
+        assert_eq!(
+            blockchain.address_history(&keys[1].public_key()),
+            vec![(2, hash_at(2, 1), 30)]
+        );
+        // the payment spent a whole coinbase and took the change back
+        assert_eq!(
+            blockchain.address_history(&keys[0].public_key()),
+            vec![
+                (0, hash_at(0, 0), reward),
+                (1, hash_at(1, 0), reward),
+                (2, hash_at(2, 0), reward),
+                (2, hash_at(2, 1), -30),
+            ]
+        );
+        assert!(
+            blockchain
+                .address_history(&PrivateKey::new_key().public_key())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn rescan_finds_what_the_utxo_set_holds() {
+        let (blockchain, keys) = chain_with_a_payment();
+
+        for key in &keys {
+            let (mut found, balance) = blockchain.rescan(&key.public_key());
+            found.sort_by_key(|output| output.hash().unwrap().as_bytes());
+            let expected = outputs_of(&blockchain, key);
+            assert_eq!(balance, expected.iter().map(|output| output.value).sum());
+            assert_eq!(
+                found
+                    .iter()
+                    .map(|output| output.hash().unwrap())
+                    .collect::<Vec<_>>(),
+                expected
+                    .iter()
+                    .map(|output| output.hash().unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // the spent coinbase is gone, the change and the payment are found
+        let (found, balance) = blockchain.rescan(&keys[1].public_key());
+        assert_eq!((found.len(), balance), (1, 30));
+        let (found, _) = blockchain.rescan(&keys[0].public_key());
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn spender_is_found_for_spent_outputs_only() {
+        let (mut blockchain, _) = chain_with_a_payment();
+        let payment = blockchain.blocks[2].transactions[1].clone();
+        let spent = payment.inputs[0].prev_transaction_output_hash;
+        let expected = Some((2, payment.hash().unwrap()));
+
+        assert_eq!(blockchain.find_spender(&spent), expected);
+        assert_eq!(
+            blockchain.find_spender(&payment.outputs[0].hash().unwrap()),
+            None
+        );
+
+        // the index is rebuilt along with the utxos
+        blockchain.rebuild_utxos().unwrap();
+        assert_eq!(blockchain.find_spender(&spent), expected);
+    }
+
+    #[test]
+    fn confirmations_count_the_blocks_from_the_transaction_up() {
+        let (mut blockchain, mut keys) = chain_with_a_payment();
+        let hash_at = |height: usize, index: usize| {
+            blockchain.blocks[height].transactions[index]
+                .hash()
+                .unwrap()
+        };
+        assert_eq!(blockchain.confirmations(&hash_at(2, 1)), Some(1));
+        assert_eq!(blockchain.confirmations(&hash_at(0, 0)), Some(3));
+
+        let inputs = outputs_of(&blockchain, &keys[1]);
+        let waiting = spend(&mut keys[1], &inputs, 0, None);
+        blockchain.add_to_mempool(waiting.clone()).unwrap();
+        assert_eq!(blockchain.confirmations(&waiting.hash().unwrap()), Some(0));
+
+        assert_eq!(
+            blockchain.confirmations(&Hash::hash(&"unknown").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn simulating_a_block_leaves_the_chain_alone() {
+        let (mut blockchain, mut keys) = chain_with_a_payment();
+        let inputs = outputs_of(&blockchain, &keys[1]);
+        let payment = spend(&mut keys[1], &inputs, 0, None);
+        blockchain.add_to_mempool(payment.clone()).unwrap();
+        let miner = keys[0].public_key();
+        let mut block = blockchain.build_template(&[(miner, 1)]).unwrap();
+        // an output created and spent within the block never reaches the set
+        let child = spend(&mut keys[1], &payment.outputs, 0, None);
+        block.transactions.push(child.clone());
+
+        let commitment = blockchain.utxo_commitment().unwrap();
+        let delta = blockchain.simulate_block(&block).unwrap();
+
+        let hashes = |outputs: &[(Hash, TransactionOutput)]| -> HashSet<Hash> {
+            outputs.iter().map(|(hash, _)| *hash).collect()
+        };
+        assert_eq!(
+            hashes(&delta.removed),
+            HashSet::from([inputs[0].hash().unwrap()])
+        );
+        assert_eq!(
+            hashes(&delta.added),
+            HashSet::from([
+                block.transactions[0].outputs[0].hash().unwrap(),
+                child.outputs[0].hash().unwrap(),
+            ])
+        );
+        assert_eq!(
+            delta.supply_change,
+            block.transactions[0].outputs[0].value as i64
+        );
+
+        assert_eq!(blockchain.utxo_commitment().unwrap(), commitment);
+        assert_eq!(blockchain.block_height(), 3);
+        assert_eq!(blockchain.mempool().len(), 1);
+    }
+
+    #[test]
+    fn compacted_chain_rebuilds_the_same_utxos() {
+        let (mut blockchain, mut keys) = chain_with_a_payment();
+        let inputs = outputs_of(&blockchain, &keys[1]);
+        blockchain
+            .add_to_mempool(spend(&mut keys[1], &inputs, 0, None))
+            .unwrap();
+
+        let mut compacted = blockchain.compacted().unwrap();
+        assert!(compacted.mempool().is_empty());
+        assert_eq!(compacted.block_height(), blockchain.block_height());
+        assert_eq!(compacted.target(), blockchain.target());
+
+        compacted.rebuild_utxos().unwrap();
+        assert_eq!(
+            compacted.utxo_commitment().unwrap(),
+            blockchain.utxo_commitment().unwrap()
+        );
+        // the copy has no mempool to hold the output spent
+        assert!(matches!(
+            compacted.utxos().get(&inputs[0].hash().unwrap()).unwrap(),
+            Some((false, _))
+        ));
+    }
+
+    #[test]
+    fn utxo_commitment_follows_the_set_not_the_node() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(2).build_with_keys();
+        let commitment = blockchain.utxo_commitment().unwrap();
+        // the same chain built again, and the set rebuilt from the blocks
+        assert_eq!(
+            ChainBuilder::new()
+                .mine(2)
+                .build()
+                .utxo_commitment()
+                .unwrap(),
+            commitment
+        );
+        blockchain.rebuild_utxos().unwrap();
+        assert_eq!(blockchain.utxo_commitment().unwrap(), commitment);
+
+        // marks are local, only mining the spend changes the set
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        blockchain
+            .add_to_mempool(spend(&mut keys[0], &inputs[..1], 0, None))
+            .unwrap();
+        assert_eq!(blockchain.utxo_commitment().unwrap(), commitment);
+
+        let miner = keys[0].public_key();
+        let mut block = blockchain.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos().unwrap();
+        assert_ne!(blockchain.utxo_commitment().unwrap(), commitment);
+    }
+
+    // a spend of input back to the key paying rate per byte, half a byte's worth over
+    // so a fee that encodes a byte longer keeps the rate
+    fn paying_rate(key: &mut PrivateKey, input: &TransactionOutput, rate: u64) -> Transaction {
+        let size = spend(key, std::slice::from_ref(input), 0, None).serialized_size() as u64;
+        let transaction = spend(
+            key,
+            std::slice::from_ref(input),
+            rate * size + size / 2,
+            None,
+        );
+        let size = transaction.serialized_size() as u64;
+        let fee = input.value - transaction.outputs[0].value;
+        assert_eq!(fee / size, rate);
+        transaction
+    }
+
+    #[test]
+    fn fee_histogram_buckets_mempool_bytes_by_rate() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(4).build_with_keys();
+        assert!(blockchain.mempool_fee_histogram().unwrap().is_empty());
+
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let mut sizes = vec![];
+        for (input, rate) in inputs.iter().zip([0, 3, 5, 7]) {
+            let transaction = paying_rate(&mut keys[0], input, rate);
+            sizes.push(transaction.serialized_size() as u64);
+            blockchain.add_to_mempool(transaction).unwrap();
+        }
+
+        // 3 starts at 2, 5 and 7 share the bucket starting at 4
+        assert_eq!(
+            blockchain.mempool_fee_histogram().unwrap(),
+            vec![(0, sizes[0]), (2, sizes[1]), (4, sizes[2] + sizes[3])]
+        );
+    }
+
+    #[test]
+    fn locator_is_dense_near_the_tip_then_doubles_back_to_genesis() {
+        assert!(locator_indices(0).is_empty());
+        assert_eq!(locator_indices(1), [0]);
+        let mut expected: Vec<usize> = (30..40).rev().collect();
+        expected.extend([28, 24, 16, 0]);
+        assert_eq!(locator_indices(40), expected);
+
+        let blockchain = ChainBuilder::new().mine(12).build();
+        let locator = blockchain.block_locator().unwrap();
+        let hash_at = |height: usize| blockchain.blocks[height].hash().unwrap();
+        assert_eq!(locator.len(), 11);
+        assert_eq!(locator[0], blockchain.tip_hash().unwrap());
+        assert_eq!(locator[9], hash_at(2));
+        assert_eq!(locator[10], hash_at(0));
+    }
+
+    #[test]
+    fn version_bit_support_counts_the_window() {
+        assert_eq!(Blockchain::new().version_bit_support(1, 10), 0.0);
+
+        let mut blockchain = ChainBuilder::new().mine(5).build();
+        // bit 1 set on the two newest blocks and one older one
+        for height in [0, 3, 4] {
+            blockchain.blocks[height].header.version = 0b10;
+        }
+        assert_eq!(blockchain.version_bit_support(1, 5), 0.6);
+        assert_eq!(blockchain.version_bit_support(1, 2), 1.0);
+        // a window past genesis covers the whole chain
+        assert_eq!(blockchain.version_bit_support(1, 100), 0.6);
+        assert_eq!(blockchain.version_bit_support(0, 5), 0.0);
+        assert_eq!(blockchain.version_bit_support(32, 5), 0.0);
+        assert_eq!(blockchain.version_bit_support(1, 0), 0.0);
+    }
+
+    #[test]
+    fn headers_extend_fork_from_or_miss_the_chain() {
+        let blockchain = ChainBuilder::new().mine(4).build();
+
+        assert_eq!(
+            blockchain.connects_to(&header_on(&blockchain, 3)).unwrap(),
+            Connection::Extends
+        );
+        assert_eq!(
+            blockchain.connects_to(&header_on(&blockchain, 0)).unwrap(),
+            Connection::Forks(0)
+        );
+
+        let mut orphan = header_on(&blockchain, 3);
+        orphan.prev_block_hash = Hash::hash(&"a block we never saw").unwrap();
+        assert_eq!(blockchain.connects_to(&orphan).unwrap(), Connection::Orphan);
+
+        // only a genesis header extends an empty chain
+        let empty = Blockchain::new();
+        orphan.prev_block_hash = Hash::zero();
+        assert_eq!(empty.connects_to(&orphan).unwrap(), Connection::Extends);
+        assert_eq!(
+            empty.connects_to(&header_on(&blockchain, 0)).unwrap(),
+            Connection::Orphan
+        );
+    }
+
+    #[test]
+    fn difficulty_series_follows_each_block() {
+        assert!(Blockchain::new().difficulty_series().is_empty());
+
+        let mut blockchain = ChainBuilder::new().mine(3).build();
+        blockchain.blocks[1].header.target = crate::MIN_TARGET / 2;
+
+        let series = blockchain.difficulty_series();
+        let heights: Vec<u64> = series.iter().map(|(height, _, _)| *height).collect();
+        assert_eq!(heights, [0, 1, 2]);
+        for ((_, time, _), block) in series.iter().zip(blockchain.blocks()) {
+            assert_eq!(*time, block.header.timestamp);
+        }
+        assert_eq!(series[1].2, 2.0);
+        // regtest's target is easier than the minimum difficulty
+        assert!(series[0].2 < 1.0);
+        assert_eq!(series[0].2, series[2].2);
+    }
+
+    #[test]
+    fn tip_and_blocks_by_height() {
+        let empty = Blockchain::new();
+        assert!(empty.tip_block().is_none());
+        assert!(empty.block_at(0).is_none());
+
+        let blockchain = ChainBuilder::new().mine(3).build();
+        let hash = |block: Option<Block>| block.unwrap().hash().unwrap();
+        assert_eq!(hash(blockchain.tip_block()), blockchain.tip_hash().unwrap());
+        assert_eq!(hash(blockchain.block_at(2)), blockchain.tip_hash().unwrap());
+        assert_eq!(
+            hash(blockchain.block_at(0)),
+            blockchain.blocks[0].hash().unwrap()
+        );
+        assert!(blockchain.block_at(3).is_none());
+        assert!(blockchain.block_at(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn tip_age_is_measured_from_its_timestamp() {
+        assert_eq!(Blockchain::new().seconds_since_tip(), 0);
+
+        let mut blockchain = ChainBuilder::new().mine(2).build();
+        blockchain.blocks[1].header.timestamp = Utc::now() - chrono::Duration::seconds(100);
+        assert!((100..=101).contains(&blockchain.seconds_since_tip()));
+    }
+
+    #[test]
+    fn mined_conflicts_evict_mempool_spends_and_their_children() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(3).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+
+        // the same chain elsewhere mines a different spend of the first output
+        let (mut elsewhere, _) = ChainBuilder::new().mine(3).build_with_keys();
+        elsewhere
+            .add_to_mempool(spend(&mut keys[0], &inputs[..1], 0, None))
+            .unwrap();
+        let miner = keys[0].public_key();
+        let mut block = elsewhere.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+
+        let parent = spend(&mut keys[0], &inputs[..1], 0, None);
+        let child = spend(&mut keys[0], &parent.outputs, 0, None);
+        let unrelated = spend(&mut keys[0], &inputs[1..2], 0, None);
+        blockchain.add_to_mempool(parent).unwrap();
+        blockchain.add_package_to_mempool(vec![child]).unwrap();
+        blockchain.add_to_mempool(unrelated.clone()).unwrap();
+
+        blockchain.add_block(block).unwrap();
+        assert_eq!(
+            mempool_hashes(&blockchain),
+            HashSet::from([unrelated.hash().unwrap()])
+        );
+        let marked = |output: &TransactionOutput| {
+            let (marked, _) = blockchain
+                .utxos()
+                .get(&output.hash().unwrap())
+                .unwrap()
+                .unwrap();
+            marked
+        };
+        assert!(!marked(&inputs[0]));
+        assert!(marked(&inputs[1]));
+    }
+
+    #[test]
+    fn restored_mempool_drops_what_no_longer_fits() {
+        let (mut blockchain, mut keys) = ChainBuilder::new().mine(4).build_with_keys();
+        let inputs = outputs_of(&blockchain, &keys[0]);
+        let kept = spend(&mut keys[0], &inputs[..1], 0, None);
+        let conflicted = spend(&mut keys[0], &inputs[1..2], 0, None);
+        let expired = spend(&mut keys[0], &inputs[2..3], 0, None);
+        for transaction in [&kept, &conflicted, &expired] {
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+        let max_age = chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        for (accepted, transaction) in &mut blockchain.mempool {
+            if transaction.hash().unwrap() == expired.hash().unwrap() {
+                *accepted -= max_age + chrono::Duration::seconds(1);
+            }
+        }
+
+        let mut file = vec![];
+        SavedMempool(blockchain.mempool().to_vec())
+            .save(&mut file)
+            .unwrap();
+        let saved = SavedMempool::load(file.as_slice()).unwrap();
+
+        // meanwhile the chain mined another spend of the conflicted output
+        let (mut restarted, _) = ChainBuilder::new().mine(4).build_with_keys();
+        restarted
+            .add_to_mempool(spend(&mut keys[0], &inputs[1..2], 0, None))
+            .unwrap();
+        let miner = keys[0].public_key();
+        let mut block = restarted.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+        restarted.add_block(block).unwrap();
+        restarted.rebuild_utxos().unwrap();
+
+        assert_eq!(restarted.restore_mempool(saved).unwrap(), 1);
+        assert_eq!(
+            mempool_hashes(&restarted),
+            HashSet::from([kept.hash().unwrap()])
+        );
+        // it keeps the age it had
+        let accepted_at = |blockchain: &Blockchain| {
+            blockchain
+                .mempool()
+                .iter()
+                .find(|(_, transaction)| transaction.hash().unwrap() == kept.hash().unwrap())
+                .map(|(accepted, _)| *accepted)
+        };
+        assert_eq!(accepted_at(&restarted), accepted_at(&blockchain));
+    }
+
+    #[test]
+    fn headers_in_range_clamp_to_the_chain() {
+        let blockchain = ChainBuilder::new().mine(5).build();
+        let in_range = |from, to| -> Vec<Hash> {
+            blockchain
+                .headers_in_range(from, to)
+                .iter()
+                .map(|header| header.hash().unwrap())
+                .collect()
+        };
+        let hashes_at = |range: std::ops::Range<usize>| -> Vec<Hash> {
+            blockchain.blocks[range]
+                .iter()
+                .map(|block| block.hash().unwrap())
+                .collect()
+        };
+
+        assert_eq!(in_range(1, 3), hashes_at(1..3));
+        assert_eq!(in_range(3, 100), hashes_at(3..5));
+        assert!(in_range(5, 9).is_empty());
+        assert!(in_range(4, 2).is_empty());
+        assert!(in_range(u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn total_work_sums_every_header() {
+        assert_eq!(Blockchain::new().total_work(), U256::zero());
+
+        let mut blockchain = ChainBuilder::new().mine(3).build();
+        // regtest's target takes a single hash
+        assert_eq!(blockchain.total_work(), U256::from(3));
+        blockchain.blocks[1].header.target = crate::MIN_TARGET;
+        assert_eq!(
+            blockchain.total_work(),
+            U256::from(2) + blockchain.blocks[1].header.work()
+        );
+    }
+
+    #[test]
+    fn recent_blocks_are_oldest_first_and_blocks_rev_newest_first() {
+        let blockchain = ChainBuilder::new().mine(4).build();
+        let hashes = |blocks: &mut dyn Iterator<Item = &Block>| -> Vec<Hash> {
+            blocks.map(|block| block.hash().unwrap()).collect()
+        };
+        let all = hashes(&mut blockchain.blocks());
+
+        assert_eq!(hashes(&mut blockchain.recent_blocks(2).iter()), all[2..]);
+        // asking for more than there are gives the whole chain
+        assert_eq!(hashes(&mut blockchain.recent_blocks(10).iter()), all);
+        assert!(blockchain.recent_blocks(0).is_empty());
+
+        let headers: Vec<Hash> = blockchain
+            .recent_headers(3)
+            .map(|header| header.hash().unwrap())
+            .collect();
+        assert_eq!(headers, all[1..]);
+
+        let mut reversed = all.clone();
+        reversed.reverse();
+        assert_eq!(hashes(&mut blockchain.blocks_rev()), reversed);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    U256, consensus,
+    crypto::PublicKey,
+    error::{BtcError, Result},
+    params::ChainParams,
+    sha256::Hash,
+    types::{BlockHeader, Transaction, blockchain::locator_indices},
+    util::MerkleProof,
+};
+
+// a transaction paying a key, with the proof it is in the block at height
+// a full node sends these for the key's unspent outputs, listed by position in the transaction
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoProof {
+    pub height: u64,
+    pub transaction: Transaction,
+    pub outputs: Vec<u32>,
+    pub proof: MerkleProof,
+}
+
+// header-only view of the chain for light clients, checks linkage, targets and proof of work
+// and follows whichever branch has the most accumulated work
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HeaderChain {
+    headers: Vec<BlockHeader>,
+    // accumulated work up to and including each header
+    work: Vec<U256>,
+    // target of the first headers, until the first retarget
+    starting_target: U256,
+}
+
+impl HeaderChain {
+    pub fn new() -> Self {
+        Self::with_params(&ChainParams::default())
+    }
+
+    pub fn with_params(params: &ChainParams) -> Self {
+        Self {
+            headers: vec![],
+            work: vec![],
+            starting_target: params.starting_target,
+        }
+    }
+
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn total_work(&self) -> U256 {
+        self.work.last().copied().unwrap_or_else(U256::zero)
+    }
+
+    pub fn tip_hash(&self) -> Result<Hash> {
+        match self.headers.last() {
+            Some(header) => header.hash(),
+            None => Ok(Hash::zero()),
+        }
+    }
+
+    pub fn block_locator(&self) -> Result<Vec<Hash>> {
+        locator_indices(self.headers.len())
+            .into_iter()
+            .map(|index| self.headers[index].hash())
+            .collect()
+    }
+
+    // the target the next header has to claim, retargeted every DIFFICULTY_UPDATE_INTERVAL
+    // headers the way Blockchain::try_adjust_target does
+    pub fn next_target(&self) -> U256 {
+        let Some(last) = self.headers.last() else {
+            return self.starting_target;
+        };
+
+        // a full node retargets as it adds each block at a multiple of the interval, over the
+        // window before that block, so the header after it is the first with the new target
+        let interval = crate::DIFFICULTY_UPDATE_INTERVAL as usize;
+        let height = self.headers.len();
+        if height > interval && (height - 1).is_multiple_of(interval) {
+            consensus::retarget(
+                last.target,
+                &self.headers[height - 1 - interval],
+                &self.headers[height - 2],
+            )
+        } else {
+            last.target
+        }
+    }
+
+    // value of the proven outputs, once they pay pubkey and the transaction checks out
+    // against our headers
+    // that the outputs are still unspent is the full node's word, headers can't show it
+    pub fn verify_utxo_proof(&self, utxo_proof: &UtxoProof, pubkey: &PublicKey) -> Result<u64> {
+        let header = usize::try_from(utxo_proof.height)
+            .ok()
+            .and_then(|height| self.headers.get(height))
+            .ok_or(BtcError::InvalidBlockHeader)?;
+        if !utxo_proof
+            .proof
+            .verify(utxo_proof.transaction.hash()?, header.merkle_root)
+        {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+
+        let mut value = 0;
+        for &index in &utxo_proof.outputs {
+            let output = utxo_proof
+                .transaction
+                .outputs
+                .get(index as usize)
+                .filter(|output| output.pubkey == *pubkey)
+                .ok_or(BtcError::InvalidTransactionOutput)?;
+            value += output.value;
+        }
+
+        Ok(value)
+    }
+
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<()> {
+        self.add_headers(vec![header])
+    }
+
+    // headers in chain order building on one of ours, or on nothing for a genesis header
+    // a branch off an earlier header replaces ours once it has more work, extending the tip
+    // keeps the valid prefix when a header fails
+    pub fn add_headers(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        let Some(first) = headers.first() else {
+            return Ok(());
+        };
+
+        let fork = if first.prev_block_hash == Hash::zero() {
+            0
+        } else {
+            // forks are usually near the tip, search from there
+            self.headers
+                .iter()
+                .rposition(|header| {
+                    header
+                        .hash()
+                        .is_ok_and(|hash| hash == first.prev_block_hash)
+                })
+                .ok_or(BtcError::InvalidBlockHeader)?
+                + 1
+        };
+
+        if fork == self.headers.len() {
+            for header in headers {
+                self.push(header)?;
+            }
+            return Ok(());
+        }
+
+        let mut branch = Self {
+            headers: self.headers[..fork].to_vec(),
+            work: self.work[..fork].to_vec(),
+            starting_target: self.starting_target,
+        };
+        let mut result = Ok(());
+        for header in headers {
+            result = branch.push(header);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if branch.total_work() > self.total_work() {
+            *self = branch;
+        }
+
+        result
+    }
+
+    fn push(&mut self, header: BlockHeader) -> Result<()> {
+        if header.prev_block_hash != self.tip_hash()? {
+            return Err(BtcError::InvalidBlockHeader);
+        }
+
+        if let Some(prev_header) = self.headers.last() {
+            consensus::check_timestamp(&header, prev_header)
+                .map_err(|_| BtcError::InvalidBlockHeader)?;
+        }
+
+        // genesis too, a light client has nothing else to trust it by
+        consensus::check_pow(&header, self.next_target())
+            .map_err(|_| BtcError::InvalidBlockHeader)?;
+
+        self.work
+            .push(self.total_work().saturating_add(header.work()));
+        self.headers.push(header);
+
+        Ok(())
+    }
+}
+
+impl Default for HeaderChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,18 +1,70 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use uuid::Uuid;
 
 use crate::{
-    crypto::{PublicKey, Signature},
-    error::Result,
+    crypto::{PrivateKey, PublicKey, Signature},
+    error::{BtcError, Result},
+    params::ChainParams,
     sha256::Hash,
-    util::Saveable,
+    util::{self, Saveable},
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    // block height before which the transaction can't be mined
+    #[serde(default)]
+    pub locktime: Option<u64>,
+    // height of the block a coinbase is for, so coinbases paying the same outputs
+    // at different heights still hash differently (bip34)
+    #[serde(default)]
+    pub height: Option<u64>,
+    // free-form bytes a miner may put in its coinbase
+    #[serde(default)]
+    pub tag: Option<Vec<u8>>,
+}
+
+// what a transaction's hash commits to, unset optional fields are left out
+// so transactions from before they existed keep their hashes
+#[derive(Serialize)]
+struct TransactionHashView<'a> {
+    inputs: &'a [TransactionInput],
+    outputs: &'a [TransactionOutput],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locktime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a [u8]>,
+}
+
+// what an input's signature commits to, see Transaction::sighash
+#[derive(Serialize)]
+struct SighashView<'a> {
+    input_index: u64,
+    prev_output: &'a TransactionOutput,
+    inputs: &'a [Hash],
+    outputs: &'a [TransactionOutput],
+    locktime: Option<u64>,
+}
+
+fn sighash(
+    inputs: &[Hash],
+    outputs: &[TransactionOutput],
+    locktime: Option<u64>,
+    input_index: usize,
+    prev_output: &TransactionOutput,
+) -> Result<Hash> {
+    Hash::hash(&SighashView {
+        input_index: input_index as u64,
+        prev_output,
+        inputs,
+        outputs,
+        locktime,
+    })
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -21,6 +73,13 @@ pub struct TransactionInput {
     pub signature: Signature,
 }
 
+impl TransactionInput {
+    // whether the owner of the spent output signed this input's sighash
+    pub fn verify_signature(&self, sighash: &Hash, pubkey: &PublicKey) -> bool {
+        self.signature.verify(sighash, pubkey)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
     pub value: u64,
@@ -36,11 +95,261 @@ impl TransactionOutput {
 
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Self { inputs, outputs }
+        Self {
+            inputs,
+            outputs,
+            locktime: None,
+            height: None,
+            tag: None,
+        }
+    }
+
+    // the coinbase of the block at height, paying the whole reward to miner
+    // coinbases are the transactions spending nothing, so rather than a dummy input the
+    // height goes in its own field
+    pub fn coinbase(miner: &PublicKey, reward: u64, height: u64, tag: Option<Vec<u8>>) -> Self {
+        Self {
+            height: Some(height),
+            tag,
+            ..Self::new(
+                vec![],
+                vec![TransactionOutput {
+                    value: reward,
+                    unique_id: Uuid::new_v4(),
+                    pubkey: miner.clone(),
+                }],
+            )
+        }
+    }
+
+    // the block height a coinbase commits to, None for anything spending inputs
+    pub fn coinbase_height(&self) -> Option<u64> {
+        if !self.inputs.is_empty() {
+            return None;
+        }
+        self.height
+    }
+
+    // size of the encoded transaction, what it takes up on the wire and in a block
+    pub fn serialized_size(&self) -> usize {
+        util::encoded_len(self)
+    }
+
+    // giant transactions would blow up validation memory, their input and output counts are capped
+    pub fn check_limits(&self, params: &ChainParams) -> Result<()> {
+        if self.inputs.len() as u64 > params.max_tx_inputs {
+            return Err(BtcError::InvalidTransactionInput);
+        }
+        if self.outputs.len() as u64 > params.max_tx_outputs {
+            return Err(BtcError::InvalidTransactionOutput);
+        }
+
+        Ok(())
+    }
+
+    // whether the transaction may be included in a block at this height
+    pub fn is_final(&self, height: u64) -> bool {
+        self.locktime.is_none_or(|locktime| height >= locktime)
+    }
+
+    // check every input against the output it spends, without needing a blockchain
+    // prev_outputs must hold each spent output keyed by its hash
+    pub fn verify_signatures(&self, prev_outputs: &HashMap<Hash, TransactionOutput>) -> Result<()> {
+        for (i, input) in self.inputs.iter().enumerate() {
+            let prev_output = prev_outputs
+                .get(&input.prev_transaction_output_hash)
+                .ok_or(BtcError::InvalidTransactionInput)?;
+            if !input.verify_signature(&self.sighash(i, prev_output)?, &prev_output.pubkey) {
+                return Err(BtcError::InvalidSignature);
+            }
+        }
+
+        Ok(())
+    }
+
+    // the hash the input at input_index signs, spending prev_output
+    // it commits to, in this order: the input's index, the spent output (value, id and key),
+    // the hashes of all outputs spent by the transaction, all of its outputs and its locktime
+    // signatures are left out, so signing one input doesn't change what the others sign,
+    // while changing an output, input or the locktime invalidates every signature
+    pub fn sighash(&self, input_index: usize, prev_output: &TransactionOutput) -> Result<Hash> {
+        let inputs: Vec<Hash> = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+        sighash(
+            &inputs,
+            &self.outputs,
+            self.locktime,
+            input_index,
+            prev_output,
+        )
     }
 
     pub fn hash(&self) -> Result<Hash> {
-        Hash::hash(self)
+        Hash::hash(&TransactionHashView {
+            inputs: &self.inputs,
+            outputs: &self.outputs,
+            locktime: self.locktime,
+            height: self.height,
+            tag: self.tag.as_deref(),
+        })
+    }
+}
+
+// a transaction whose inputs are yet to be signed, moved between machines as hex
+// so one can build it and another, holding the key, can sign it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedTransaction {
+    // the outputs being spent, their values and keys are part of what gets signed
+    pub inputs: Vec<TransactionOutput>,
+    pub outputs: Vec<TransactionOutput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locktime: Option<u64>,
+}
+
+impl UnsignedTransaction {
+    pub fn to_hex(&self) -> Result<String> {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes).map_err(|_| BtcError::InvalidTransaction)?;
+        Ok(hex::encode(bytes))
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex.trim()).map_err(|_| BtcError::InvalidTransaction)?;
+        ciborium::from_reader(bytes.as_slice()).map_err(|_| BtcError::InvalidTransaction)
+    }
+
+    // every input is signed with the same key, so it must own all spent outputs
+    pub fn sign(self, private_key: &mut PrivateKey) -> Result<Transaction> {
+        let hashes = self
+            .inputs
+            .iter()
+            .map(|input| input.hash())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut inputs = vec![];
+        for (i, (hash, prev_output)) in hashes.iter().zip(&self.inputs).enumerate() {
+            let sighash = sighash(&hashes, &self.outputs, self.locktime, i, prev_output)?;
+            inputs.push(TransactionInput {
+                prev_transaction_output_hash: *hash,
+                signature: Signature::sign_hash(&sighash, private_key),
+            });
+        }
+
+        Ok(Transaction {
+            inputs,
+            outputs: self.outputs,
+            locktime: self.locktime,
+            height: None,
+            tag: None,
+        })
+    }
+}
+
+// assembles and signs a transaction paying any number of recipients
+#[derive(Clone, Debug)]
+pub struct TransactionBuilder {
+    inputs: Vec<TransactionOutput>,
+    recipients: Vec<(PublicKey, u64)>,
+    change: Option<PublicKey>,
+    fee: u64,
+    dust_threshold: u64,
+}
+
+impl TransactionBuilder {
+    // change is held to the chain's dust threshold unless dust_threshold overrides it
+    pub fn new(params: &ChainParams) -> Self {
+        Self {
+            inputs: vec![],
+            recipients: vec![],
+            change: None,
+            fee: 0,
+            dust_threshold: params.dust_threshold,
+        }
+    }
+
+    // spend a utxo, it must belong to the key passed to build
+    pub fn input(mut self, output: TransactionOutput) -> Self {
+        self.inputs.push(output);
+        self
+    }
+
+    pub fn recipient(mut self, pubkey: PublicKey, value: u64) -> Self {
+        self.recipients.push((pubkey, value));
+        self
+    }
+
+    pub fn change_to(mut self, pubkey: PublicKey) -> Self {
+        self.change = Some(pubkey);
+        self
+    }
+
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn dust_threshold(mut self, dust_threshold: u64) -> Self {
+        self.dust_threshold = dust_threshold;
+        self
+    }
+
+    pub fn build(self, private_key: &mut PrivateKey) -> Result<Transaction> {
+        let change = self
+            .change
+            .clone()
+            .unwrap_or_else(|| private_key.public_key());
+        self.unsigned(change)?.sign(private_key)
+    }
+
+    // the transaction without signatures, for signing on another machine
+    // the signing key isn't known here, so change needs a change_to key
+    pub fn build_unsigned(self) -> Result<UnsignedTransaction> {
+        let change = self
+            .change
+            .clone()
+            .ok_or(BtcError::InvalidTransactionOutput)?;
+        self.unsigned(change)
+    }
+
+    fn unsigned(self, change_pubkey: PublicKey) -> Result<UnsignedTransaction> {
+        if self.recipients.is_empty() {
+            return Err(BtcError::InvalidTransactionOutput);
+        }
+
+        let input_value: u64 = self.inputs.iter().map(|input| input.value).sum();
+        let sent_value: u64 = self.recipients.iter().map(|(_, value)| value).sum();
+        let change = input_value
+            .checked_sub(sent_value)
+            .and_then(|remaining| remaining.checked_sub(self.fee))
+            .ok_or(BtcError::InvalidTransaction)?;
+
+        let mut outputs: Vec<TransactionOutput> = self
+            .recipients
+            .into_iter()
+            .map(|(pubkey, value)| TransactionOutput {
+                value,
+                unique_id: Uuid::new_v4(),
+                pubkey,
+            })
+            .collect();
+
+        // dust change costs more to spend than it is worth, the miner keeps it
+        if change > 0 && change >= self.dust_threshold {
+            outputs.push(TransactionOutput {
+                value: change,
+                unique_id: Uuid::new_v4(),
+                pubkey: change_pubkey,
+            });
+        }
+
+        Ok(UnsignedTransaction {
+            inputs: self.inputs,
+            outputs,
+            locktime: None,
+        })
     }
 }
 
@@ -58,3 +367,405 @@ impl Saveable for Transaction {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialise transaction"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus,
+        types::{Block, BlockHeader, Blockchain},
+        util::MerkleRoot,
+    };
+
+    fn coinbase_block(coinbase: Transaction) -> Block {
+        let transactions = vec![coinbase];
+        Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions).unwrap(),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        )
+    }
+
+    fn output(pubkey: &PublicKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            pubkey: pubkey.clone(),
+        }
+    }
+
+    // the values paid to each key, in output order
+    fn paid(transaction: &Transaction) -> Vec<(PublicKey, u64)> {
+        transaction
+            .outputs
+            .iter()
+            .map(|output| (output.pubkey.clone(), output.value))
+            .collect()
+    }
+
+    #[test]
+    fn only_coinbases_have_a_coinbase_height() {
+        let mut key = PrivateKey::new_key();
+        let coinbase = Transaction::coinbase(&key.public_key(), 50, 7, None);
+        assert_eq!(coinbase.coinbase_height(), Some(7));
+
+        // a spend claiming a height is still not a coinbase
+        let mut spend = UnsignedTransaction {
+            inputs: coinbase.outputs.clone(),
+            outputs: coinbase.outputs.clone(),
+            locktime: None,
+        }
+        .sign(&mut key)
+        .unwrap();
+        spend.height = Some(7);
+        assert_eq!(spend.coinbase_height(), None);
+    }
+
+    #[test]
+    fn coinbases_differing_only_in_height_hash_differently() {
+        let miner = PrivateKey::new_key().public_key();
+        let mut coinbase = Transaction::coinbase(&miner, 50, 7, None);
+        let first = coinbase.hash().unwrap();
+
+        coinbase.height = Some(8);
+        assert_ne!(coinbase.hash().unwrap(), first);
+    }
+
+    // a transaction by key spending two outputs of its own, and those outputs by hash
+    fn signed_spend(key: &mut PrivateKey) -> (Transaction, HashMap<Hash, TransactionOutput>) {
+        let inputs = vec![
+            output(&key.public_key(), 600),
+            output(&key.public_key(), 400),
+        ];
+        let prev_outputs = inputs
+            .iter()
+            .map(|input| (input.hash().unwrap(), input.clone()))
+            .collect();
+        let transaction = UnsignedTransaction {
+            inputs,
+            outputs: vec![output(&PrivateKey::new_key().public_key(), 1_000)],
+            locktime: None,
+        }
+        .sign(key)
+        .unwrap();
+        (transaction, prev_outputs)
+    }
+
+    #[test]
+    fn signed_transaction_verifies_without_a_blockchain() {
+        let (transaction, prev_outputs) = signed_spend(&mut PrivateKey::new_key());
+        transaction.verify_signatures(&prev_outputs).unwrap();
+    }
+
+    #[test]
+    fn forged_signature_is_rejected() {
+        let mut owner = PrivateKey::new_key();
+        let (mut transaction, prev_outputs) = signed_spend(&mut owner);
+
+        // the right sighash, signed by someone else
+        let sighash = transaction
+            .sighash(
+                1,
+                &prev_outputs[&transaction.inputs[1].prev_transaction_output_hash],
+            )
+            .unwrap();
+        transaction.inputs[1].signature =
+            Signature::sign_hash(&sighash, &mut PrivateKey::new_key());
+        assert!(matches!(
+            transaction.verify_signatures(&prev_outputs),
+            Err(BtcError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn signatures_do_not_carry_over_to_changed_transactions() {
+        let (mut transaction, prev_outputs) = signed_spend(&mut PrivateKey::new_key());
+        transaction.outputs[0].value += 1;
+        assert!(matches!(
+            transaction.verify_signatures(&prev_outputs),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        let (mut transaction, prev_outputs) = signed_spend(&mut PrivateKey::new_key());
+        transaction.inputs.swap(0, 1);
+        assert!(matches!(
+            transaction.verify_signatures(&prev_outputs),
+            Err(BtcError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn sighash_commits_to_every_output_and_the_locktime() {
+        let (transaction, prev_outputs) = signed_spend(&mut PrivateKey::new_key());
+        let spent = &prev_outputs[&transaction.inputs[0].prev_transaction_output_hash];
+        let sighash = transaction.sighash(0, spent).unwrap();
+
+        let changes: [fn(&mut Transaction); 5] = [
+            |tx| tx.outputs[0].value -= 1,
+            |tx| tx.outputs[0].unique_id = Uuid::new_v4(),
+            |tx| tx.outputs[0].pubkey = PrivateKey::new_key().public_key(),
+            |tx| tx.outputs.push(tx.outputs[0].clone()),
+            |tx| tx.locktime = Some(1),
+        ];
+        for change in changes {
+            let mut changed = transaction.clone();
+            change(&mut changed);
+            assert_ne!(changed.sighash(0, spent).unwrap(), sighash);
+            assert!(matches!(
+                changed.verify_signatures(&prev_outputs),
+                Err(BtcError::InvalidSignature)
+            ));
+        }
+
+        // the spent output and the input's position are committed to as well
+        let mut other_output = spent.clone();
+        other_output.value += 1;
+        assert_ne!(transaction.sighash(0, &other_output).unwrap(), sighash);
+        assert_ne!(transaction.sighash(1, spent).unwrap(), sighash);
+    }
+
+    #[test]
+    fn sighash_leaves_out_the_signatures() {
+        let mut key = PrivateKey::new_key();
+        let (mut transaction, prev_outputs) = signed_spend(&mut key);
+        let spent = |i: usize| &prev_outputs[&transaction.inputs[i].prev_transaction_output_hash];
+        let sighash = transaction.sighash(0, spent(0)).unwrap();
+
+        // signing the second input again gives a different signature over the same sighash
+        let second = transaction.sighash(1, spent(1)).unwrap();
+        let resigned = Signature::sign_hash(&second, &mut key);
+        transaction.inputs[1].signature = resigned;
+
+        assert_eq!(
+            transaction
+                .sighash(
+                    0,
+                    &prev_outputs[&transaction.inputs[0].prev_transaction_output_hash]
+                )
+                .unwrap(),
+            sighash
+        );
+        transaction.verify_signatures(&prev_outputs).unwrap();
+    }
+
+    #[test]
+    fn unknown_spent_output_is_rejected() {
+        let (transaction, mut prev_outputs) = signed_spend(&mut PrivateKey::new_key());
+        prev_outputs.remove(&transaction.inputs[0].prev_transaction_output_hash);
+        assert!(matches!(
+            transaction.verify_signatures(&prev_outputs),
+            Err(BtcError::InvalidTransactionInput)
+        ));
+    }
+
+    #[test]
+    fn transactions_at_the_limits_pass_and_above_fail() {
+        let miner = PrivateKey::new_key().public_key();
+        let params = ChainParams {
+            max_tx_inputs: 3,
+            max_tx_outputs: 4,
+            ..ChainParams::regtest()
+        };
+        let input = TransactionInput {
+            prev_transaction_output_hash: Hash::zero(),
+            signature: Signature::sign_hash(&Hash::zero(), &mut PrivateKey::new_key()),
+        };
+        let with = |inputs: usize, outputs: usize| {
+            Transaction::new(
+                vec![input.clone(); inputs],
+                vec![output(&miner, 1); outputs],
+            )
+            .check_limits(&params)
+        };
+
+        assert!(with(3, 4).is_ok());
+        assert!(matches!(with(4, 4), Err(BtcError::InvalidTransactionInput)));
+        assert!(matches!(
+            with(3, 5),
+            Err(BtcError::InvalidTransactionOutput)
+        ));
+    }
+
+    #[test]
+    fn locked_transaction_is_final_from_its_locktime() {
+        let mut transaction = Transaction::new(vec![], vec![]);
+        assert!(transaction.is_final(0));
+
+        transaction.locktime = Some(100);
+        assert!(!transaction.is_final(50));
+        assert!(!transaction.is_final(99));
+        assert!(transaction.is_final(100));
+        assert!(transaction.is_final(150));
+    }
+
+    #[test]
+    fn builder_pays_every_recipient_and_returns_change() {
+        let mut sender = PrivateKey::new_key();
+        let recipients: Vec<PublicKey> =
+            (0..3).map(|_| PrivateKey::new_key().public_key()).collect();
+        let inputs = [
+            output(&sender.public_key(), 6_000),
+            output(&sender.public_key(), 4_000),
+        ];
+
+        let transaction = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(inputs[0].clone())
+            .input(inputs[1].clone())
+            .recipient(recipients[0].clone(), 1_000)
+            .recipient(recipients[1].clone(), 2_000)
+            .recipient(recipients[2].clone(), 3_000)
+            .fee(500)
+            .dust_threshold(100)
+            .build(&mut sender)
+            .unwrap();
+
+        assert_eq!(
+            paid(&transaction),
+            vec![
+                (recipients[0].clone(), 1_000),
+                (recipients[1].clone(), 2_000),
+                (recipients[2].clone(), 3_000),
+                (sender.public_key(), 10_000 - 6_000 - 500),
+            ]
+        );
+
+        let prev_outputs = inputs
+            .iter()
+            .map(|input| (input.hash().unwrap(), input.clone()))
+            .collect();
+        transaction.verify_signatures(&prev_outputs).unwrap();
+    }
+
+    #[test]
+    fn builder_leaves_dust_change_to_the_miner() {
+        let mut sender = PrivateKey::new_key();
+        let recipient = PrivateKey::new_key().public_key();
+
+        let transaction = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(output(&sender.public_key(), 10_000))
+            .recipient(recipient.clone(), 9_000)
+            .fee(950)
+            .dust_threshold(100)
+            .build(&mut sender)
+            .unwrap();
+
+        // the 50 left over goes to the fee instead of a change output
+        assert_eq!(paid(&transaction), vec![(recipient, 9_000)]);
+    }
+
+    #[test]
+    fn unsigned_transaction_survives_hex_export_and_signs_elsewhere() {
+        let mut sender = PrivateKey::new_key();
+        let recipient = PrivateKey::new_key().public_key();
+        let input = output(&sender.public_key(), 10_000);
+
+        // built without the key, signed on the machine holding it
+        let unsigned = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(input.clone())
+            .recipient(recipient, 9_000)
+            .change_to(sender.public_key())
+            .fee(100)
+            .build_unsigned()
+            .unwrap();
+        let hex = unsigned.to_hex().unwrap();
+        let imported = UnsignedTransaction::from_hex(&format!("{hex}\n")).unwrap();
+        assert_eq!(imported.to_hex().unwrap(), hex);
+
+        let transaction = imported.sign(&mut sender).unwrap();
+        let prev_outputs = HashMap::from([(input.hash().unwrap(), input)]);
+        transaction.verify_signatures(&prev_outputs).unwrap();
+        assert_eq!(paid(&transaction).len(), 2);
+
+        assert!(matches!(
+            UnsignedTransaction::from_hex("not hex"),
+            Err(BtcError::InvalidTransaction)
+        ));
+    }
+
+    #[test]
+    fn builder_takes_the_dust_threshold_from_the_chain() {
+        let sender = PrivateKey::new_key().public_key();
+        let recipient = PrivateKey::new_key().public_key();
+        let build = |params: &ChainParams| {
+            TransactionBuilder::new(params)
+                .input(output(&sender, 10_000))
+                .recipient(recipient.clone(), 9_000)
+                .change_to(sender.clone())
+                .fee(500)
+                .build_unsigned()
+                .unwrap()
+        };
+
+        // 500 change is dust on mainnet, regtest keeps any change
+        assert_eq!(build(&ChainParams::mainnet()).outputs.len(), 1);
+        assert_eq!(build(&ChainParams::regtest()).outputs.len(), 2);
+    }
+
+    #[test]
+    fn builder_sends_change_to_the_change_key() {
+        let change = PrivateKey::new_key().public_key();
+        let sender = PrivateKey::new_key().public_key();
+        let recipient = PrivateKey::new_key().public_key();
+
+        let unsigned = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(output(&sender, 10_000))
+            .recipient(recipient, 4_000)
+            .change_to(change.clone())
+            .dust_threshold(100)
+            .build_unsigned()
+            .unwrap();
+        assert_eq!(unsigned.outputs[1].pubkey, change);
+        assert_eq!(unsigned.outputs[1].value, 6_000);
+    }
+
+    #[test]
+    fn builder_rejects_overspends_and_empty_recipients() {
+        let mut sender = PrivateKey::new_key();
+        let recipient = PrivateKey::new_key().public_key();
+
+        let overspend = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(output(&sender.public_key(), 1_000))
+            .recipient(recipient, 900)
+            .fee(200)
+            .build(&mut sender);
+        assert!(matches!(overspend, Err(BtcError::InvalidTransaction)));
+
+        let nobody = TransactionBuilder::new(&ChainParams::mainnet())
+            .input(output(&sender.public_key(), 1_000))
+            .build(&mut sender);
+        assert!(matches!(nobody, Err(BtcError::InvalidTransactionOutput)));
+    }
+
+    #[test]
+    fn coinbase_passes_the_coinbase_checks_of_a_zero_fee_block() {
+        let blockchain = Blockchain::with_params(ChainParams::regtest());
+        let miner = PrivateKey::new_key().public_key();
+
+        for height in [0, 1, 210_000] {
+            let coinbase = Transaction::coinbase(&miner, Block::block_reward(height), height, None);
+            assert!(coinbase.inputs.is_empty());
+            assert_eq!(coinbase.coinbase_height(), Some(height));
+
+            let block = coinbase_block(coinbase);
+            consensus::check_coinbase(&block, height, blockchain.utxos()).unwrap();
+        }
+    }
+
+    #[test]
+    fn coinbase_for_another_height_or_reward_is_rejected() {
+        let blockchain = Blockchain::with_params(ChainParams::regtest());
+        let miner = PrivateKey::new_key().public_key();
+        let reward = Block::block_reward(5);
+
+        let block = coinbase_block(Transaction::coinbase(&miner, reward, 4, None));
+        assert!(consensus::check_coinbase(&block, 5, blockchain.utxos()).is_err());
+
+        let block = coinbase_block(Transaction::coinbase(&miner, reward + 1, 5, None));
+        assert!(consensus::check_coinbase(&block, 5, blockchain.utxos()).is_err());
+    }
+}
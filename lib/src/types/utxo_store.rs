@@ -0,0 +1,213 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{crypto::PublicKey, error::Result, sha256::Hash, types::TransactionOutput};
+
+// an unspent output, true while some mempool transaction is spending it
+pub type Utxo = (bool, TransactionOutput);
+
+// what applying a block does to the utxo set, outputs created and spent
+// within the block appear in neither list
+#[derive(Clone, Debug, Default)]
+pub struct UtxoDelta {
+    pub removed: Vec<(Hash, TransactionOutput)>,
+    pub added: Vec<(Hash, TransactionOutput)>,
+    // value added minus value removed, the coins the block creates
+    pub supply_change: i64,
+}
+
+// where the blockchain keeps its unspent outputs, keyed by output hash
+pub trait UtxoStore: fmt::Debug + Send + Sync {
+    fn get(&self, hash: &Hash) -> Result<Option<Utxo>>;
+    fn insert(&mut self, hash: Hash, utxo: Utxo) -> Result<()>;
+    fn remove(&mut self, hash: &Hash) -> Result<Option<Utxo>>;
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (Hash, Utxo)> + '_>>;
+    fn clear(&mut self) -> Result<()>;
+    // an independent copy of the current contents
+    fn clone_box(&self) -> Box<dyn UtxoStore>;
+
+    fn iter_for_pubkey<'a>(
+        &'a self,
+        pubkey: &'a PublicKey,
+    ) -> Result<Box<dyn Iterator<Item = (Hash, Utxo)> + 'a>> {
+        Ok(Box::new(
+            self.iter()?
+                .filter(move |(_, (_, output))| output.pubkey == *pubkey),
+        ))
+    }
+
+    fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(self.get(hash)?.is_some())
+    }
+
+    // mark or unmark a utxo, doing nothing if it isn't there
+    fn set_marked(&mut self, hash: &Hash, marked: bool) -> Result<()> {
+        if let Some((_, output)) = self.get(hash)? {
+            self.insert(*hash, (marked, output))?;
+        }
+        Ok(())
+    }
+}
+
+impl UtxoStore for HashMap<Hash, Utxo> {
+    fn get(&self, hash: &Hash) -> Result<Option<Utxo>> {
+        Ok(HashMap::get(self, hash).cloned())
+    }
+
+    fn insert(&mut self, hash: Hash, utxo: Utxo) -> Result<()> {
+        HashMap::insert(self, hash, utxo);
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Result<Option<Utxo>> {
+        Ok(HashMap::remove(self, hash))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (Hash, Utxo)> + '_>> {
+        Ok(Box::new(
+            HashMap::iter(self).map(|(hash, utxo)| (*hash, utxo.clone())),
+        ))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        HashMap::clear(self);
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn UtxoStore> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn UtxoStore> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl Default for Box<dyn UtxoStore> {
+    fn default() -> Self {
+        Box::new(HashMap::<Hash, Utxo>::new())
+    }
+}
+
+// keeps the utxo set in a redb file so it doesn't have to fit in memory
+#[cfg(feature = "redb")]
+pub struct RedbUtxoStore {
+    db: redb::Database,
+}
+
+#[cfg(feature = "redb")]
+const UTXO_TABLE: redb::TableDefinition<&[u8], &[u8]> = redb::TableDefinition::new("utxos");
+
+#[cfg(feature = "redb")]
+impl RedbUtxoStore {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let db = redb::Database::create(path).map_err(store_error)?;
+
+        // create the table up front so reads never find it missing
+        let transaction = db.begin_write().map_err(store_error)?;
+        transaction.open_table(UTXO_TABLE).map_err(store_error)?;
+        transaction.commit().map_err(store_error)?;
+
+        Ok(Self { db })
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut redb::Table<&[u8], &[u8]>) -> Result<T>) -> Result<T> {
+        let transaction = self.db.begin_write().map_err(store_error)?;
+        let result = {
+            let mut table = transaction.open_table(UTXO_TABLE).map_err(store_error)?;
+            f(&mut table)?
+        };
+        transaction.commit().map_err(store_error)?;
+        Ok(result)
+    }
+}
+
+#[cfg(feature = "redb")]
+impl fmt::Debug for RedbUtxoStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RedbUtxoStore")
+    }
+}
+
+#[cfg(feature = "redb")]
+impl UtxoStore for RedbUtxoStore {
+    fn get(&self, hash: &Hash) -> Result<Option<Utxo>> {
+        let transaction = self.db.begin_read().map_err(store_error)?;
+        let table = transaction.open_table(UTXO_TABLE).map_err(store_error)?;
+        table
+            .get(hash.as_bytes().as_slice())
+            .map_err(store_error)?
+            .map(|value| decode_utxo(value.value()))
+            .transpose()
+    }
+
+    fn insert(&mut self, hash: Hash, utxo: Utxo) -> Result<()> {
+        let value = encode_utxo(&utxo)?;
+        self.write(|table| {
+            table
+                .insert(hash.as_bytes().as_slice(), value.as_slice())
+                .map_err(store_error)?;
+            Ok(())
+        })
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Result<Option<Utxo>> {
+        self.write(|table| {
+            table
+                .remove(hash.as_bytes().as_slice())
+                .map_err(store_error)?
+                .map(|value| decode_utxo(value.value()))
+                .transpose()
+        })
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = (Hash, Utxo)> + '_>> {
+        use redb::ReadableTable;
+
+        let transaction = self.db.begin_read().map_err(store_error)?;
+        let table = transaction.open_table(UTXO_TABLE).map_err(store_error)?;
+        let utxos = table
+            .iter()
+            .map_err(store_error)?
+            .map(|entry| {
+                let (key, value) = entry.map_err(store_error)?;
+                let hash = Hash::from_bytes(key.value().try_into().map_err(store_error)?);
+                Ok((hash, decode_utxo(value.value())?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Box::new(utxos.into_iter()))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.write(|table| {
+            table.retain(|_, _| false).map_err(store_error)?;
+            Ok(())
+        })
+    }
+
+    // clones are in-memory snapshots, the file stays owned by this store
+    fn clone_box(&self) -> Box<dyn UtxoStore> {
+        let utxos: HashMap<Hash, Utxo> =
+            self.iter().map(|utxos| utxos.collect()).unwrap_or_default();
+        Box::new(utxos)
+    }
+}
+
+#[cfg(feature = "redb")]
+fn encode_utxo(utxo: &Utxo) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    ciborium::into_writer(utxo, &mut bytes).map_err(store_error)?;
+    Ok(bytes)
+}
+
+#[cfg(feature = "redb")]
+fn decode_utxo(bytes: &[u8]) -> Result<Utxo> {
+    ciborium::from_reader(bytes).map_err(store_error)
+}
+
+#[cfg(feature = "redb")]
+fn store_error(e: impl fmt::Display) -> crate::error::BtcError {
+    crate::error::BtcError::UtxoStore(e.to_string())
+}
@@ -7,6 +7,7 @@ use std::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    U256,
     error::{BtcError, Result},
     sha256::Hash,
     types::Transaction,
@@ -22,7 +23,7 @@ impl MerkleRoot {
 
         let mut layer = transactions
             .iter()
-            .map(Hash::hash)
+            .map(Transaction::hash)
             .collect::<Result<Vec<_>>>()
             .ok()?;
 
@@ -40,6 +41,69 @@ impl MerkleRoot {
 
         Some(Self(layer[0]))
     }
+
+    // the path from the transaction at index up to the root calculate gives, None where
+    // calculate gives none or there is no such transaction
+    pub fn prove(transactions: &[Transaction], index: usize) -> Option<MerkleProof> {
+        let mut layer = transactions
+            .iter()
+            .map(Transaction::hash)
+            .collect::<Result<Vec<_>>>()
+            .ok()?;
+        layer.get(index)?;
+
+        let mut siblings = vec![];
+        let mut position = index;
+        while layer.len() > 1 {
+            siblings.push(*layer.get(position ^ 1)?);
+            layer = layer
+                .chunks(2)
+                .map(|pair| {
+                    let left = pair.first().ok_or(BtcError::InvalidTransaction)?;
+                    let right = pair.get(1).ok_or(BtcError::InvalidTransaction)?;
+                    Hash::hash(&[left, right])
+                })
+                .collect::<Result<Vec<Hash>>>()
+                .ok()?;
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            index: index as u64,
+            siblings,
+        })
+    }
+}
+
+// sibling hashes from a transaction up to a merkle root, lowest first
+// index is the transaction's position in its block, its bits say which side each sibling is on
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    // true when the transaction with this hash leads up to root
+    pub fn verify(&self, transaction: Hash, root: MerkleRoot) -> bool {
+        let mut hash = transaction;
+        let mut position = self.index;
+        for sibling in &self.siblings {
+            let pair = if position.is_multiple_of(2) {
+                [&hash, sibling]
+            } else {
+                [sibling, &hash]
+            };
+            let Ok(parent) = Hash::hash(&pair) else {
+                return false;
+            };
+            hash = parent;
+            position /= 2;
+        }
+
+        // a longer index than the path would prove a position the tree doesn't have
+        position == 0 && hash == root.0
+    }
 }
 
 pub trait Saveable
@@ -55,3 +119,39 @@ where
         self.save(File::create(&path)?)
     }
 }
+
+// how many times harder than the easiest allowed target, MIN_TARGET itself is 1.0
+pub fn difficulty(target: U256) -> f64 {
+    to_f64(crate::MIN_TARGET) / to_f64(target.max(U256::one()))
+}
+
+fn to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+}
+
+// counts the bytes written through it, to size an encoding without keeping it
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+// length of the cbor encoding of data, as sent on the wire
+pub(crate) fn encoded_len<T: Serialize>(data: &T) -> usize {
+    let mut counter = ByteCounter::default();
+    // the counter never fails and our types always serialise
+    let _ = ciborium::into_writer(data, &mut counter);
+    counter.0
+}
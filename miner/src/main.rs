@@ -2,14 +2,21 @@ use anyhow::{Result, anyhow};
 use btclib::network::Message;
 use std::sync::atomic::Ordering;
 use std::{
+    ops::Range,
     sync::{Arc, atomic::AtomicBool},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{net::TcpStream, sync::Mutex, time::interval};
 
-use btclib::{crypto::PublicKey, types::Block, util::Saveable};
-use clap::Parser;
+use btclib::{
+    crypto::PublicKey,
+    mining::{self, CpuBackend, MiningBackend, NonceCounters},
+    sha256::Hash,
+    types::{Block, BlockHeader},
+    util::Saveable,
+};
+use clap::{Parser, ValueEnum};
 
 const ATOMIC_ORDERING: Ordering = Ordering::Relaxed;
 
@@ -20,10 +27,38 @@ struct Cli {
     node_address: String,
     #[arg(short, long)]
     public_key_file: String,
+    /// Nonces to try before checking back on the template
+    #[arg(short, long, default_value_t = 2_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+    /// What searches for nonces
+    #[arg(long, value_enum, default_value_t = Backend::Cpu)]
+    backend: Backend,
+    /// Threads to split each batch between, one per core by default
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: Option<u64>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    Cpu,
+}
+
+impl Backend {
+    fn build(self) -> Arc<dyn MiningBackend> {
+        match self {
+            Backend::Cpu => Arc::new(CpuBackend),
+        }
+    }
 }
 
 struct Miner {
+    address: String,
     public_key: PublicKey,
+    batch_size: usize,
+    threads: usize,
+    backend: Arc<dyn MiningBackend>,
+    // one counter per mining thread
+    nonces: Arc<NonceCounters>,
     stream: Mutex<TcpStream>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
     mining: Arc<AtomicBool>,
@@ -32,12 +67,23 @@ struct Miner {
 }
 
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
+    async fn new(
+        address: String,
+        public_key: PublicKey,
+        batch_size: usize,
+        threads: usize,
+        backend: Arc<dyn MiningBackend>,
+    ) -> Result<Self> {
         let stream = TcpStream::connect(&address).await?;
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
 
         Ok(Self {
+            address,
             public_key,
+            batch_size,
+            threads,
+            backend,
+            nonces: Arc::new(NonceCounters::new(threads)),
             stream: Mutex::new(stream),
             current_template: Arc::new(std::sync::Mutex::new(None)),
             mining: Arc::new(AtomicBool::new(false)),
@@ -46,40 +92,123 @@ impl Miner {
         })
     }
 
-    // todo: multithreaded mining
     async fn run(&self) -> Result<()> {
         let _ = self.spawn_mining_thread()?;
+        let tip_receiver = self.spawn_tip_watcher().await?;
         let mut poll_interval = interval(Duration::from_secs(5));
+        let mut last_report = (Instant::now(), 0);
 
         loop {
             let receiver_clone = self.mined_block_receiver.clone();
 
+            // a mined block goes out before any new template is fetched, or the
+            // template would still build on the tip the block replaces
             tokio::select! {
-                _ = poll_interval.tick() => self.fetch_and_validate_template().await?,
-                Ok(mined_block) = receiver_clone.recv_async() => self.submit_block(mined_block).await?
+                biased;
+                Ok(mined_block) = receiver_clone.recv_async() => self.submit_block(mined_block).await?,
+                _ = poll_interval.tick() => {
+                    self.report_hashrate(&mut last_report);
+                    self.fetch_and_validate_template().await?
+                }
+                Ok(tip) = tip_receiver.recv_async() => {
+                    println!("New tip {tip}, refreshing template");
+                    self.fetch_template().await?
+                }
+            }
+        }
+    }
+
+    // long-poll the node for new tips on a second connection, the first one stays free for requests
+    async fn spawn_tip_watcher(&self) -> Result<flume::Receiver<Hash>> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let (sender, receiver) = flume::unbounded();
+
+        tokio::spawn(async move {
+            let mut known_tip = Hash::zero();
+            loop {
+                if let Err(e) = Message::WaitForTip(known_tip).send_async(&mut stream).await {
+                    println!("Stopped watching for new tips: {e}");
+                    return;
+                }
+
+                match Message::receive_async(&mut stream).await {
+                    Ok(Message::NewBlock(block)) => {
+                        let Ok(tip) = block.hash() else {
+                            continue;
+                        };
+                        known_tip = tip;
+                        if sender.send(tip).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(m) => println!("Unexpected message received when waiting for a tip: {m:?}"),
+                    Err(e) => {
+                        println!("Stopped watching for new tips: {e}");
+                        return;
+                    }
+                }
             }
+        });
+
+        Ok(receiver)
+    }
+
+    // hashes per second since the last report, from the nonces the threads tried
+    fn report_hashrate(&self, last_report: &mut (Instant, u64)) {
+        let total = self.nonces.total();
+        let (since, previous) = *last_report;
+        let seconds = since.elapsed().as_secs_f64();
+        if seconds > 0.0 && total > previous {
+            println!(
+                "Hashrate: {:.0} H/s, nonces per thread: {:?}",
+                (total - previous) as f64 / seconds,
+                self.nonces.per_thread()
+            );
         }
+        *last_report = (Instant::now(), total);
     }
 
     fn spawn_mining_thread(&self) -> Result<thread::JoinHandle<()>> {
         let template = self.current_template.clone();
         let mining = self.mining.clone();
         let sender = self.mined_block_sender.clone();
+        let batch_size = self.batch_size as u64;
+        let threads = self.threads;
+        let backend = self.backend.clone();
+        let nonces = self.nonces.clone();
 
         let handle = thread::spawn(move || {
             loop {
-                if let Some(mut block) = template.lock().unwrap().clone() {
-                    println!("Mining block with target: {}", block.header.target);
-
-                    if block.header.mine(2_000_000).expect("Error mining block") {
+                let current = template.lock().unwrap().clone();
+                let Some(mut block) = current else {
+                    continue;
+                };
+                println!("Mining block with target: {}", block.header.target);
+
+                let start = block.header.nonce;
+                let end = start.saturating_add(batch_size);
+                let found = search_batch(&*backend, &block.header, start..end, threads, &nonces);
+                match found {
+                    Some(nonce) => {
+                        block.header.nonce = nonce;
                         println!(
                             "Block mined: {}",
                             block.hash().expect("Error hashing block")
                         );
 
+                        // drop the template so the same block isn't found again until a new one arrives
+                        template.lock().unwrap().take();
                         sender.send(block).expect("Failed to send mined block");
                         mining.store(false, ATOMIC_ORDERING);
                     }
+                    None => {
+                        // pick up after this batch next time, unless the template was replaced meanwhile
+                        if let Some(current) = template.lock().unwrap().as_mut()
+                            && current.header.merkle_root == block.header.merkle_root
+                        {
+                            current.header.nonce = end;
+                        }
+                    }
                 }
             }
         });
@@ -119,6 +248,9 @@ impl Miner {
 
                 Ok(())
             }
+            Message::Error { code, message } => Err(anyhow!(
+                "Node refused the template request ({code}): {message}"
+            )),
             m => Err(anyhow!(
                 "Unexpected message received when fetching template: {m:?}"
             )),
@@ -126,8 +258,9 @@ impl Miner {
     }
 
     async fn validate_template(&self) -> Result<()> {
-        // todo: fix clippy lint
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        // clone out of the guard so the lock is not held across awaits
+        let template = self.current_template.lock().unwrap().clone();
+        if let Some(template) = template {
             let message = Message::ValidateTemplate(template);
 
             let mut stream_lock = self.stream.lock().await;
@@ -149,6 +282,9 @@ impl Miner {
 
                     Ok(())
                 }
+                Message::Error { code, message } => Err(anyhow!(
+                    "Node refused to validate the template ({code}): {message}"
+                )),
                 m => Err(anyhow!(
                     "Unexpected message received when validating template: {m:?}"
                 )),
@@ -164,18 +300,165 @@ impl Miner {
 
         let mut stream_lock = self.stream.lock().await;
         message.send_async(&mut *stream_lock).await?;
-
         self.mining.store(false, ATOMIC_ORDERING);
 
-        Ok(())
+        match Message::receive_async(&mut *stream_lock).await? {
+            Message::BlockAccepted(hash) => {
+                println!("Block {hash} accepted");
+                Ok(())
+            }
+            Message::Error { code, message } => {
+                drop(stream_lock);
+
+                // most likely someone else found a block first, mine on top of it instead
+                println!("Block rejected ({code}): {message}");
+                self.fetch_template().await
+            }
+            m => Err(anyhow!(
+                "Unexpected message received when submitting block: {m:?}"
+            )),
+        }
     }
 }
 
+// search the batch on threads, each taking its own part of it, the lowest nonce found wins
+fn search_batch(
+    backend: &dyn MiningBackend,
+    header: &BlockHeader,
+    batch: Range<u64>,
+    threads: usize,
+    nonces: &NonceCounters,
+) -> Option<u64> {
+    thread::scope(|scope| {
+        let searches: Vec<_> = mining::partition_nonces(batch, threads)
+            .into_iter()
+            .enumerate()
+            .map(|(thread, part)| {
+                scope.spawn(move || {
+                    let found = backend.find_nonce(header, header.target, part.clone());
+                    nonces.record(
+                        thread,
+                        found.map_or(part.end, |nonce| nonce + 1) - part.start,
+                    );
+                    found
+                })
+            })
+            .collect();
+
+        searches
+            .into_iter()
+            .filter_map(|search| search.join().expect("Mining thread panicked"))
+            .min()
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     let public_key = PublicKey::load_from_file(&cli.public_key_file)
         .map_err(|e| anyhow!("Error reading public key: {e}"))?;
-    let miner = Miner::new(cli.node_address, public_key).await?;
+    let threads = match cli.threads {
+        Some(threads) => threads as usize,
+        None => thread::available_parallelism().map_or(1, |threads| threads.get()),
+    };
+    let miner = Miner::new(
+        cli.node_address,
+        public_key,
+        cli.batch_size as usize,
+        threads,
+        cli.backend.build(),
+    )
+    .await?;
     miner.run().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::{U256, crypto::PrivateKey, params::ChainParams, types::Blockchain};
+
+    // finds nothing, remembers what it was asked to search
+    #[derive(Default)]
+    struct RecordingBackend(std::sync::Mutex<Vec<Range<u64>>>);
+
+    impl MiningBackend for RecordingBackend {
+        fn find_nonce(&self, _: &BlockHeader, _: U256, range: Range<u64>) -> Option<u64> {
+            self.0.lock().unwrap().push(range);
+            None
+        }
+    }
+
+    // hands back a nonce worked out elsewhere, as external hardware would
+    struct PrecomputedBackend(u64);
+
+    impl MiningBackend for PrecomputedBackend {
+        fn find_nonce(&self, _: &BlockHeader, _: U256, range: Range<u64>) -> Option<u64> {
+            range.contains(&self.0).then_some(self.0)
+        }
+    }
+
+    fn template(blockchain: &Blockchain) -> Block {
+        let miner = PrivateKey::new_key().public_key();
+        blockchain.build_template(&[(miner, 1)]).unwrap()
+    }
+
+    fn template_header() -> BlockHeader {
+        template(&Blockchain::with_params(ChainParams::regtest())).header
+    }
+
+    #[test]
+    fn batch_size_is_validated() {
+        let args = ["miner", "-n", "127.0.0.1:9000", "-p", "key.pem"];
+        assert_eq!(Cli::try_parse_from(args).unwrap().batch_size, 2_000_000);
+
+        let small = Cli::try_parse_from(args.iter().chain(&["-b", "16"])).unwrap();
+        assert_eq!(small.batch_size, 16);
+        assert!(Cli::try_parse_from(args.iter().chain(&["-b", "0"])).is_err());
+    }
+
+    #[test]
+    fn worker_searches_exactly_the_batch() {
+        let backend = RecordingBackend::default();
+        let nonces = NonceCounters::new(4);
+
+        let found = search_batch(&backend, &template_header(), 100..116, 4, &nonces);
+        assert_eq!(found, None);
+
+        let mut searched = backend.0.into_inner().unwrap();
+        searched.sort_by_key(|range| range.start);
+        assert_eq!(searched, vec![100..104, 104..108, 108..112, 112..116]);
+        assert_eq!(nonces.per_thread(), vec![4, 4, 4, 4]);
+    }
+
+    #[test]
+    fn nonce_from_another_backend_completes_the_block() {
+        let mut blockchain = Blockchain::with_params(ChainParams::regtest());
+        let mut block = template(&blockchain);
+        let nonces = NonceCounters::new(3);
+
+        let found = search_batch(
+            &PrecomputedBackend(1_234),
+            &block.header,
+            1_000..2_000,
+            3,
+            &nonces,
+        );
+        assert_eq!(found, Some(1_234));
+        // only the thread whose part held the nonce stopped early
+        assert_eq!(nonces.per_thread(), vec![235, 333, 333]);
+
+        block.header.nonce = found.unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.block_height(), 1);
+    }
+
+    #[test]
+    fn worker_takes_the_lowest_nonce_found() {
+        // regtest accepts any hash, so every thread finds the first nonce of its part
+        let nonces = NonceCounters::new(4);
+        let found = search_batch(&CpuBackend, &template_header(), 40..80, 4, &nonces);
+
+        assert_eq!(found, Some(40));
+        assert_eq!(nonces.per_thread(), vec![1, 1, 1, 1]);
+    }
+}
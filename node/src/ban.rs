@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+
+use btclib::error::BtcError;
+use tokio::time::{Duration, Instant};
+
+use crate::SCORES;
+
+// misbehaviour points at which a peer gets banned
+const BAN_THRESHOLD: u32 = 100;
+// how long a banned peer is refused for
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+// points for each kind of protocol violation
+pub const MALFORMED_MESSAGE: u32 = 20;
+pub const OVERSIZED_MESSAGE: u32 = 50;
+pub const FLOODING: u32 = 50;
+pub const INVALID_BLOCK: u32 = 20;
+pub const INVALID_TRANSACTION: u32 = 10;
+
+#[derive(Debug, Default)]
+pub struct BanScore {
+    pub(crate) score: u32,
+    banned_until: Option<Instant>,
+}
+
+pub fn is_banned(peer: IpAddr) -> bool {
+    let Some(mut entry) = SCORES.get_mut(&peer) else {
+        return false;
+    };
+
+    match entry.banned_until {
+        Some(until) if Instant::now() < until => true,
+        Some(_) => {
+            // ban served, start over with a clean slate
+            *entry = BanScore::default();
+            false
+        }
+        None => false,
+    }
+}
+
+// add points to a peer's score, true once that gets it banned
+pub fn misbehaving(peer: IpAddr, points: u32, reason: &str) -> bool {
+    let mut entry = SCORES.entry(peer).or_default();
+    entry.score = entry.score.saturating_add(points);
+    println!("peer {peer} misbehaving ({reason}), score {}", entry.score);
+
+    if entry.score >= BAN_THRESHOLD {
+        entry.banned_until = Some(Instant::now() + BAN_DURATION);
+        println!("banning {peer} for {}s", BAN_DURATION.as_secs());
+        return true;
+    }
+
+    false
+}
+
+// a block that merely doesn't extend our tip could be an honest race, one mined faster than
+// the bootstrap pace an honest miner that's ahead of itself, anything else is invalid
+pub fn block_points(error: &BtcError) -> u32 {
+    match error {
+        BtcError::InvalidHash | BtcError::BlockTooSoon(_) => 0,
+        _ => INVALID_BLOCK,
+    }
+}
+
+// a relayed transaction can fail against our mempool or chain without anyone cheating:
+// it lost a race with a conflicting one, it or its inputs got mined since, or the relaying
+// node's policy is looser than ours, only a transaction that could never be valid scores
+pub fn transaction_points(error: &BtcError) -> u32 {
+    match error {
+        BtcError::DoubleSpend(_)
+        | BtcError::UnknownOutput(_)
+        | BtcError::DuplicateOutput(_)
+        | BtcError::FeeTooLow { .. }
+        | BtcError::TimeLocked(_)
+        | BtcError::ImmatureCoinbase(_) => 0,
+        _ => INVALID_TRANSACTION,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // documentation addresses, each test scores its own
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::from([198, 51, 100, last])
+    }
+
+    #[test]
+    fn peer_is_banned_at_the_threshold() {
+        let peer = peer(1);
+        assert!(!misbehaving(peer, BAN_THRESHOLD - 1, "test"));
+        assert!(!is_banned(peer));
+
+        assert!(misbehaving(peer, 1, "test"));
+        assert!(is_banned(peer));
+    }
+
+    #[test]
+    fn served_ban_starts_over() {
+        let peer = peer(2);
+        assert!(misbehaving(peer, BAN_THRESHOLD, "test"));
+        SCORES.get_mut(&peer).unwrap().banned_until = Some(Instant::now());
+
+        assert!(!is_banned(peer));
+        assert_eq!(SCORES.get(&peer).unwrap().score, 0);
+    }
+
+    #[test]
+    fn honest_races_cost_nothing() {
+        assert_eq!(block_points(&BtcError::InvalidHash), 0);
+        assert_eq!(block_points(&BtcError::BlockTooSoon(chrono::Utc::now())), 0);
+        assert_eq!(block_points(&BtcError::InvalidBlock), INVALID_BLOCK);
+    }
+
+    #[test]
+    fn relay_races_cost_nothing() {
+        let hash = btclib::sha256::Hash::zero();
+        assert_eq!(transaction_points(&BtcError::DoubleSpend(hash)), 0);
+        assert_eq!(transaction_points(&BtcError::UnknownOutput(hash)), 0);
+        assert_eq!(transaction_points(&BtcError::DuplicateOutput(hash)), 0);
+        assert_eq!(
+            transaction_points(&BtcError::InvalidSignature),
+            INVALID_TRANSACTION
+        );
+    }
+}
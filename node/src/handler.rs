@@ -1 +1,802 @@
+use std::net::IpAddr;
 
+use btclib::{
+    codec::WireFormat,
+    crypto::PublicKey,
+    error::BtcError,
+    network::{self, Message, ReceiveError},
+    types::{Block, CompactBlock, MempoolAccept, Transaction},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::broadcast::{self, error::RecvError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ACCEPTED, BLOCKCHAIN, NODES, TIP, UTXOS, ban,
+    transport::{self, Transport},
+    util,
+};
+
+// most keys a single connection may watch
+const MAX_WATCHED_KEYS: usize = 1_000;
+
+// counts messages in fixed one second windows
+struct RateLimiter {
+    max_per_second: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // record a message, false once the peer has gone over its allowance
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+
+        self.count += 1;
+        self.count <= self.max_per_second
+    }
+}
+
+pub async fn handle_connection(socket: impl Transport, peer: IpAddr, max_message_rate: u32) {
+    let mut socket = BufReader::new(socket);
+    let mut rate_limiter = RateLimiter::new(max_message_rate);
+    // keys this peer watches, and once it watches any, the accepted transactions
+    // with the format to push them in
+    let mut watched: Vec<PublicKey> = vec![];
+    let mut accepted: Option<(broadcast::Receiver<Transaction>, WireFormat)> = None;
+
+    loop {
+        // push transactions for watched keys until the peer's next message arrives,
+        // fill_buf is cancel safe and leaves what it read for the receive below
+        if let Some((receiver, format)) = &mut accepted {
+            let pushed = tokio::select! {
+                _ = socket.fill_buf() => None,
+                transaction = receiver.recv() => Some(transaction),
+            };
+
+            match pushed {
+                None => {}
+                Some(Ok(transaction)) => {
+                    if involves(&transaction, &watched)
+                        && let Err(e) = Message::NewTransaction(transaction)
+                            .send_async_with(&mut socket, *format)
+                            .await
+                    {
+                        println!("failed to push watched transaction: {e}");
+                        return;
+                    }
+                    continue;
+                }
+                Some(Err(RecvError::Lagged(missed))) => {
+                    println!("watcher fell behind, {missed} transactions not checked");
+                    continue;
+                }
+                Some(Err(RecvError::Closed)) => {
+                    accepted = None;
+                    continue;
+                }
+            }
+        }
+
+        let (message, format) = match Message::receive_async_with_format(&mut socket).await {
+            Ok(received) => received,
+            Err(ReceiveError::Closed) => {
+                println!("peer closed the connection");
+                return;
+            }
+            Err(e) => {
+                println!("invalid message from peer: {e}, closing connection");
+                match e {
+                    ReceiveError::Malformed(_) => {
+                        ban::misbehaving(peer, ban::MALFORMED_MESSAGE, "malformed message");
+                    }
+                    ReceiveError::Oversized(_) => {
+                        ban::misbehaving(peer, ban::OVERSIZED_MESSAGE, "oversized message");
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        };
+
+        if !rate_limiter.allow() {
+            println!("peer exceeded {max_message_rate} messages per second, closing connection");
+            ban::misbehaving(peer, ban::FLOODING, "flooding");
+            return;
+        }
+
+        use Message::*;
+        match message {
+            UTXOs(_)
+            | UtxoResponse(_)
+            | UtxoCommitment { .. }
+            | BlockAccepted(_)
+            | Block(_)
+            | Rescanned { .. }
+            | UtxoProofs(_)
+            | Template(_)
+            | Difference(_)
+            | TemplateValidity(_)
+            | NodeList(_)
+            | MempoolContents(_)
+            | BlockTransactions(_)
+            | Headers(_)
+            | HeaderChain(_) => {
+                println!("I am neither a miner nor a wallet! Goodbye");
+                return;
+            }
+            FetchBlock(height) => {
+                let Some(block) = BLOCKCHAIN.read().await.block_at(height) else {
+                    let message = Error {
+                        code: network::ERROR_NOT_FOUND,
+                        message: format!("no block at height {height}"),
+                    };
+                    if let Err(e) = message.send_async_with(&mut socket, format).await {
+                        println!("failed to send error: {e}");
+                        return;
+                    }
+                    continue;
+                };
+
+                let message = NewBlock(block);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send block: {e}");
+                    return;
+                }
+            }
+            FetchBlockByHash(hash) => {
+                let Some(block) = BLOCKCHAIN.read().await.block_by_hash(&hash) else {
+                    let message = Error {
+                        code: network::ERROR_NOT_FOUND,
+                        message: format!("no block with hash {hash}"),
+                    };
+                    if let Err(e) = message.send_async_with(&mut socket, format).await {
+                        println!("failed to send error: {e}");
+                        return;
+                    }
+                    continue;
+                };
+
+                let message = Block(block);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send block: {e}");
+                    return;
+                }
+            }
+            FetchHeaders { locator, stop } => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let headers = blockchain.headers_after_locator(&locator, stop);
+                drop(blockchain);
+
+                let message = match headers {
+                    Ok(headers) => Headers(headers),
+                    Err(e) => {
+                        println!("failed to collect headers: {e}");
+                        Message::error(&e)
+                    }
+                };
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send headers: {e}");
+                    return;
+                }
+            }
+            FetchHeaderChain { from, to } => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let headers = blockchain.headers_in_range(from, to);
+                drop(blockchain);
+
+                let message = HeaderChain(headers);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send header chain: {e}");
+                    return;
+                }
+            }
+            DiscoverNodes => {
+                let nodes = NODES
+                    .iter()
+                    .map(|entry| entry.key().clone())
+                    .collect::<Vec<_>>();
+                let message = NodeList(nodes);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send node list: {e}");
+                    return;
+                }
+            }
+            Announce(port) => {
+                let node = std::net::SocketAddr::new(peer, port).to_string();
+                if NODES.contains_key(&node) {
+                    continue;
+                }
+                match util::connect(&node).await {
+                    Ok(stream) => {
+                        println!("connected back to announced node {node}");
+                        NODES.insert(node, transport::shared(stream));
+                    }
+                    Err(e) => println!("failed to connect back to {node}: {e}"),
+                }
+            }
+            AskDifference(height) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                // heights beyond i64 are out of reach, saturate rather than wrap
+                let count = i64::try_from(blockchain.block_height())
+                    .unwrap_or(i64::MAX)
+                    .saturating_sub(i64::try_from(height).unwrap_or(i64::MAX));
+                drop(blockchain);
+
+                let message = Difference(count);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send difference: {e}");
+                    return;
+                }
+            }
+            FetchUTXOs(key) => {
+                println!("received request to fetch UTXOs");
+                let utxos = UTXOS
+                    .load()
+                    .values()
+                    .filter(|(_, output)| output.pubkey == key)
+                    .map(|(marked, output)| (output.clone(), *marked))
+                    .collect::<Vec<_>>();
+
+                let message = UTXOs(utxos);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send UTXOs: {e}");
+                    return;
+                }
+            }
+            WatchAddress(pubkey) => {
+                if watched.len() >= MAX_WATCHED_KEYS {
+                    println!("peer already watches {MAX_WATCHED_KEYS} keys, ignoring another");
+                    continue;
+                }
+                if !watched.contains(&pubkey) {
+                    watched.push(pubkey);
+                }
+                accepted.get_or_insert_with(|| (ACCEPTED.subscribe(), format));
+            }
+            Unwatch(pubkey) => {
+                watched.retain(|key| *key != pubkey);
+                if watched.is_empty() {
+                    accepted = None;
+                }
+            }
+            GetUtxoCommitment => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let height = blockchain.block_height();
+                let commitment = blockchain.utxo_commitment();
+                drop(blockchain);
+
+                let message = match commitment {
+                    Ok(commitment) => UtxoCommitment { height, commitment },
+                    Err(e) => Message::error(&e),
+                };
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send utxo commitment: {e}");
+                    return;
+                }
+            }
+            GetUtxo(hash) => {
+                let utxo = UTXOS
+                    .load()
+                    .get(&hash)
+                    .map(|(marked, output)| (output.clone(), *marked));
+
+                let message = UtxoResponse(utxo);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send UTXO: {e}");
+                    return;
+                }
+            }
+            Rescan(pubkey) => {
+                println!("received request to rescan the chain");
+                let blockchain = BLOCKCHAIN.read().await;
+                let (utxos, balance) = blockchain.rescan(&pubkey);
+                drop(blockchain);
+
+                let message = Rescanned { utxos, balance };
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send rescan result: {e}");
+                    return;
+                }
+            }
+            FetchUtxoProofs(pubkey) => {
+                let proofs = BLOCKCHAIN.read().await.utxo_proofs(&pubkey);
+                let message = match proofs {
+                    Ok(proofs) => UtxoProofs(proofs),
+                    Err(e) => Message::error(&e),
+                };
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send utxo proofs: {e}");
+                    return;
+                }
+            }
+            FetchMempool(pubkey) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let transactions = blockchain.mempool_transactions(pubkey.as_ref());
+                drop(blockchain);
+
+                let message = MempoolContents(transactions);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send mempool: {e}");
+                    return;
+                }
+            }
+            NewBlock(block) => {
+                let mut blockchain = BLOCKCHAIN.write().await;
+                println!("received new block");
+                let height = blockchain.block_height();
+                if let Err(e) = blockchain.add_block(block.clone()) {
+                    println!("block rejected: {e}");
+                    util::log_rejected_block(&block, height, &e);
+                    if ban::misbehaving(peer, ban::block_points(&e), "invalid block") {
+                        return;
+                    }
+                } else {
+                    if let Err(e) = blockchain.rebuild_utxos() {
+                        println!("failed to rebuild utxos: {e}");
+                    }
+                    util::tip_changed(&blockchain);
+                }
+            }
+            CompactBlock(compact_block) => {
+                println!("received compact block");
+                let block = match util::complete_compact_block(compact_block).await {
+                    Ok(block) => block,
+                    Err(e) => {
+                        println!("failed to reconstruct compact block: {e}");
+                        continue;
+                    }
+                };
+
+                let mut blockchain = BLOCKCHAIN.write().await;
+                let height = blockchain.block_height();
+                if let Err(e) = blockchain.add_block(block.clone()) {
+                    println!("block rejected: {e}");
+                    util::log_rejected_block(&block, height, &e);
+                    if ban::misbehaving(peer, ban::block_points(&e), "invalid compact block") {
+                        return;
+                    }
+                } else {
+                    if let Err(e) = blockchain.rebuild_utxos() {
+                        println!("failed to rebuild utxos: {e}");
+                    }
+                    util::tip_changed(&blockchain);
+                }
+            }
+            GetData(header_hash, short_txids) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let transactions = blockchain
+                    .block_by_hash(&header_hash)
+                    .map(|block| {
+                        block
+                            .transactions
+                            .into_iter()
+                            .filter(|transaction| {
+                                transaction.hash().is_ok_and(|hash| {
+                                    short_txids
+                                        .contains(&btclib::types::CompactBlock::short_txid(&hash))
+                                })
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                drop(blockchain);
+
+                let message = BlockTransactions(transactions);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send block transactions: {e}");
+                    return;
+                }
+            }
+            NewTransaction(transaction) => {
+                let mut blockchain = BLOCKCHAIN.write().await;
+                println!("received transaction from friend");
+                match blockchain.add_to_mempool(transaction.clone()) {
+                    Ok(MempoolAccept::AlreadyPresent) => {}
+                    Ok(accepted) => {
+                        drop(blockchain);
+                        // pass it on, peers that already have it stop the relay
+                        let _ = ACCEPTED.send(transaction.clone());
+                        broadcast(NewTransaction(transaction)).await;
+                        if let MempoolAccept::Replaced(hashes) = accepted {
+                            for hash in hashes {
+                                broadcast(ReplacedTransaction(hash)).await;
+                            }
+                        }
+                    }
+                    // an archival node has no mempool, relaying to it isn't misbehaviour
+                    Err(BtcError::MempoolDisabled) => {}
+                    Err(e) => {
+                        println!("transaction rejected: {e}");
+                        if ban::misbehaving(
+                            peer,
+                            ban::transaction_points(&e),
+                            "invalid transaction",
+                        ) {
+                            return;
+                        }
+                    }
+                }
+            }
+            NewPackage(package) => {
+                let mut blockchain = BLOCKCHAIN.write().await;
+                println!(
+                    "received package of {} transactions from friend",
+                    package.len()
+                );
+                match blockchain.add_package_to_mempool(package.clone()) {
+                    Ok(MempoolAccept::AlreadyPresent) => {}
+                    Ok(_) => {
+                        drop(blockchain);
+                        for transaction in &package {
+                            let _ = ACCEPTED.send(transaction.clone());
+                        }
+                        broadcast(NewPackage(package)).await;
+                    }
+                    Err(BtcError::MempoolDisabled) => {}
+                    Err(e) => {
+                        println!("package rejected: {e}");
+                        if ban::misbehaving(peer, ban::transaction_points(&e), "invalid package") {
+                            return;
+                        }
+                    }
+                }
+            }
+            Error { code, message } => {
+                println!("peer reported an error {code}: {message}");
+            }
+            ReplacedTransaction(hash) => {
+                // our own mempool drops it when the replacement reaches us, nothing to relay
+                println!("peer reports transaction {hash} was replaced");
+            }
+            ValidateTemplate(block_template) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let status = blockchain
+                    .tip_hash()
+                    .is_ok_and(|hash| hash == block_template.header.prev_block_hash);
+                drop(blockchain);
+
+                let message = TemplateValidity(status);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send template validity: {e}");
+                    return;
+                }
+            }
+            SubmitTemplate(block) => {
+                println!("received allegedly mined template");
+                let mut blockchain = BLOCKCHAIN.write().await;
+                let height = blockchain.block_height();
+                if let Err(e) = blockchain.add_block(block.clone()) {
+                    drop(blockchain);
+                    util::log_rejected_block(&block, height, &e);
+                    let sent = Message::error(&e)
+                        .send_async_with(&mut socket, format)
+                        .await;
+
+                    // a stale block lost an honest race, the miner can carry on with a new template
+                    if ban::block_points(&e) == 0 && sent.is_ok() {
+                        println!("block rejected: {e}");
+                        continue;
+                    }
+
+                    println!("block rejected: {e}, closing connection");
+                    ban::misbehaving(peer, ban::block_points(&e), "invalid template");
+                    return;
+                }
+                if let Err(e) = blockchain.rebuild_utxos() {
+                    println!("failed to rebuild utxos: {e}");
+                }
+                util::tip_changed(&blockchain);
+                println!("block looks good, broadcasting");
+                drop(blockchain);
+
+                match block.hash() {
+                    Ok(hash) => {
+                        if let Err(e) = BlockAccepted(hash)
+                            .send_async_with(&mut socket, format)
+                            .await
+                        {
+                            println!("failed to confirm block: {e}");
+                        }
+                    }
+                    Err(e) => println!("failed to hash accepted block: {e}"),
+                }
+
+                broadcast_block(&block).await;
+            }
+            SubmitTransaction(transaction) => {
+                println!("submit tx");
+                let mut blockchain = BLOCKCHAIN.write().await;
+                let replaced = match blockchain.add_to_mempool(transaction.clone()) {
+                    Ok(MempoolAccept::AlreadyPresent) => {
+                        println!("transaction already in mempool");
+                        continue;
+                    }
+                    Ok(MempoolAccept::Replaced(hashes)) => {
+                        for hash in &hashes {
+                            println!("added transaction to mempool, replacing {hash}");
+                        }
+                        hashes
+                    }
+                    Ok(MempoolAccept::Added) => {
+                        println!("added transaction to mempool");
+                        vec![]
+                    }
+                    Err(BtcError::MempoolDisabled) => {
+                        println!("not accepting transactions, the mempool is disabled");
+                        if let Err(e) = Message::error(&BtcError::MempoolDisabled)
+                            .send_async_with(&mut socket, format)
+                            .await
+                        {
+                            println!("failed to send error: {e}");
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("transaction rejected, closing connection: {e}");
+                        // the connection closes either way, the reason is a courtesy
+                        let _ = Message::error(&e)
+                            .send_async_with(&mut socket, format)
+                            .await;
+                        ban::misbehaving(peer, ban::INVALID_TRANSACTION, "invalid transaction");
+                        return;
+                    }
+                };
+                drop(blockchain);
+
+                // only fails when nobody is watching
+                let _ = ACCEPTED.send(transaction.clone());
+                broadcast(NewTransaction(transaction)).await;
+                for hash in replaced {
+                    broadcast(ReplacedTransaction(hash)).await;
+                }
+                println!("transaction sent to friends");
+            }
+            SubmitPackage(package) => {
+                println!("submit package");
+                let mut blockchain = BLOCKCHAIN.write().await;
+                match blockchain.add_package_to_mempool(package.clone()) {
+                    Ok(MempoolAccept::AlreadyPresent) => {
+                        println!("package already in mempool");
+                        continue;
+                    }
+                    Ok(_) => println!("added package to mempool"),
+                    Err(BtcError::MempoolDisabled) => {
+                        println!("not accepting transactions, the mempool is disabled");
+                        if let Err(e) = Message::error(&BtcError::MempoolDisabled)
+                            .send_async_with(&mut socket, format)
+                            .await
+                        {
+                            println!("failed to send error: {e}");
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("package rejected, closing connection: {e}");
+                        // the connection closes either way, the reason is a courtesy
+                        let _ = Message::error(&e)
+                            .send_async_with(&mut socket, format)
+                            .await;
+                        ban::misbehaving(peer, ban::INVALID_TRANSACTION, "invalid package");
+                        return;
+                    }
+                }
+                drop(blockchain);
+
+                for transaction in &package {
+                    let _ = ACCEPTED.send(transaction.clone());
+                }
+                broadcast(NewPackage(package)).await;
+                println!("package sent to friends");
+            }
+            WaitForTip(known_tip) => {
+                let mut tip = TIP.subscribe();
+                // the tip may have moved before we subscribed, only wait if it hasn't
+                let current = BLOCKCHAIN.read().await.tip_hash();
+                if current.is_ok_and(|hash| hash == known_tip)
+                    && tip.wait_for(|hash| *hash != known_tip).await.is_err()
+                {
+                    return;
+                }
+
+                let Some(block) = BLOCKCHAIN.read().await.tip_block() else {
+                    continue;
+                };
+                let message = NewBlock(block);
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send new tip: {e}");
+                    return;
+                }
+            }
+            FetchTemplate(pubkey) => {
+                let blockchain = BLOCKCHAIN.read().await;
+                let template = blockchain.build_template(&[(pubkey, 1)]);
+                drop(blockchain);
+
+                let message = match template {
+                    Ok(template) => Template(template),
+                    Err(e) => {
+                        println!("failed to build template: {e}");
+                        Message::error(&e)
+                    }
+                };
+                if let Err(e) = message.send_async_with(&mut socket, format).await {
+                    println!("failed to send template: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// whether a transaction pays any of the keys or spends one of their utxos
+fn involves(transaction: &Transaction, keys: &[PublicKey]) -> bool {
+    let utxos = UTXOS.load();
+    transaction
+        .outputs
+        .iter()
+        .any(|output| keys.contains(&output.pubkey))
+        || transaction.inputs.iter().any(|input| {
+            utxos
+                .get(&input.prev_transaction_output_hash)
+                .is_some_and(|(_, output)| keys.contains(&output.pubkey))
+        })
+}
+
+// send a message to every known node, ignoring the ones that fail
+pub async fn broadcast(message: Message) {
+    let nodes = NODES
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in nodes {
+        let Some(peer) = NODES.get(&node).map(|peer| peer.clone()) else {
+            continue;
+        };
+        if let Err(e) = message
+            .send_async_with(&mut *peer.lock().await, util::wire_format())
+            .await
+        {
+            println!("failed to send message to {node}: {e}");
+        }
+    }
+}
+
+// relay a block compactly, peers already hold most of its transactions
+pub async fn broadcast_block(block: &Block) {
+    match CompactBlock::from_block(block) {
+        Ok(compact_block) => broadcast(Message::CompactBlock(compact_block)).await,
+        Err(_) => broadcast(Message::NewBlock(block.clone())).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::{
+        crypto::PrivateKey,
+        sha256::Hash,
+        types::{BlockHeader, TransactionOutput, UnsignedTransaction},
+        util::MerkleRoot,
+    };
+
+    // the node's chain is empty in tests, where a genesis block needs a coinbase
+    fn invalid_block() -> Block {
+        let miner = PrivateKey::new_key().public_key();
+        let coinbase = [Transaction::coinbase(&miner, 0, 0, None)];
+        Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&coinbase).unwrap(),
+                btclib::MIN_TARGET,
+            ),
+            vec![],
+        )
+    }
+
+    #[tokio::test]
+    async fn peer_sending_invalid_blocks_is_banned_and_refused() {
+        // a documentation address, no other test scores it
+        let peer: IpAddr = "192.0.2.14".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let connection = tokio::spawn(handle_connection(server, peer, 100));
+
+        let blocks = 100u32.div_ceil(ban::INVALID_BLOCK);
+        for _ in 0..blocks {
+            assert_eq!(crate::refusal(peer, usize::MAX), None);
+            Message::NewBlock(invalid_block())
+                .send_async(&mut client)
+                .await
+                .unwrap();
+        }
+
+        // the block that gets it banned also gets it hung up on
+        tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .expect("banned peer is still connected")
+            .unwrap();
+        assert!(ban::is_banned(peer));
+        assert_eq!(crate::refusal(peer, usize::MAX), Some("banned"));
+    }
+
+    #[tokio::test]
+    async fn peer_over_the_message_rate_is_dropped() {
+        let peer: IpAddr = "192.0.2.15".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let connection = tokio::spawn(handle_connection(server, peer, 2));
+
+        // none of these get a reply, the third is one too many for the second
+        for _ in 0..3 {
+            Message::ReplacedTransaction(Hash::zero())
+                .send_async(&mut client)
+                .await
+                .unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .expect("flooding peer is still connected")
+            .unwrap();
+        assert_eq!(crate::SCORES.get(&peer).unwrap().score, ban::FLOODING);
+        assert!(!ban::is_banned(peer));
+    }
+
+    #[tokio::test]
+    async fn relaying_a_transaction_we_cant_use_is_not_misbehaviour() {
+        let peer: IpAddr = "192.0.2.16".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        tokio::spawn(handle_connection(server, peer, 100));
+
+        // spends an output this node never saw, as one mined or replaced since would
+        let mut key = PrivateKey::new_key();
+        let output = TransactionOutput {
+            value: 1,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        };
+        let transaction = UnsignedTransaction {
+            inputs: vec![output.clone()],
+            outputs: vec![output],
+            locktime: None,
+        }
+        .sign(&mut key)
+        .unwrap();
+        Message::NewTransaction(transaction)
+            .send_async(&mut client)
+            .await
+            .unwrap();
+
+        // still connected and answering
+        Message::AskDifference(0)
+            .send_async(&mut client)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Message::receive_async(&mut client).await.unwrap(),
+            Message::Difference(_)
+        ));
+        assert!(
+            crate::SCORES
+                .get(&peer)
+                .is_none_or(|entry| entry.score == 0)
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::BLOCKCHAIN;
+
+// minimal read-only http api for tools that don't speak the cbor protocol
+pub async fn serve(port: u16) -> Result<()> {
+    let address = format!("0.0.0.0:{port}");
+    let listener = TcpListener::bind(&address).await?;
+    println!("http api listening on {address}");
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(socket).await {
+                println!("http request failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut socket: TcpStream) -> Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&mut socket)
+        .read_line(&mut request_line)
+        .await?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, body) = match (method, path) {
+        ("GET", "/mempool") => {
+            let blockchain = BLOCKCHAIN.read().await;
+            let transactions = blockchain.mempool_transactions(None);
+            drop(blockchain);
+
+            ("200 OK", serde_json::to_string(&transactions)?)
+        }
+        ("GET", "/mempool/histogram") => {
+            let histogram = BLOCKCHAIN.read().await.mempool_fee_histogram()?;
+            ("200 OK", serde_json::to_string(&histogram)?)
+        }
+        ("GET", "/headers") => {
+            let blockchain = BLOCKCHAIN.read().await;
+            let range = (
+                query_param(query, "from").unwrap_or(Ok(0)),
+                query_param(query, "to").unwrap_or(Ok(blockchain.block_height())),
+            );
+
+            match range {
+                (Ok(from), Ok(to)) => {
+                    let headers = blockchain.headers_in_range(from, to);
+                    drop(blockchain);
+                    ("200 OK", serde_json::to_string(&headers)?)
+                }
+                _ => (
+                    "400 Bad Request",
+                    json!({ "error": "from and to must be block heights" }).to_string(),
+                ),
+            }
+        }
+        _ => ("404 Not Found", json!({ "error": "not found" }).to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+// value of a numeric query parameter, None when absent
+fn query_param(query: &str, name: &str) -> Option<Result<u64, std::num::ParseIntError>> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.parse())
+}
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use btclib::{crypto::PublicKey, network::Message, params::ChainParams, types::HeaderChain};
+use tokio::time::{Duration, interval};
+
+use crate::{NODES, util};
+
+// follow our peers' header chain without holding blocks or utxos, and the balance of
+// watch_key from merkle proofs checked against it
+pub async fn run(params: ChainParams, watch_key: Option<PublicKey>) -> Result<()> {
+    println!("running as a light client");
+    let mut header_chain = HeaderChain::with_params(&params);
+    let mut poll_interval = interval(Duration::from_secs(10));
+
+    loop {
+        poll_interval.tick().await;
+
+        let all_nodes = NODES
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        for node in all_nodes {
+            let message = Message::FetchHeaders {
+                locator: header_chain.block_locator()?,
+                stop: None,
+            };
+            match util::request(&node, &message).await {
+                Ok(Message::Headers(headers)) => {
+                    if let Err(e) = header_chain.add_headers(headers) {
+                        println!("rejected headers from {node}: {e}");
+                    }
+                }
+                Ok(Message::Error { code, message }) => {
+                    println!("{node} refused to send headers ({code}): {message}")
+                }
+                Ok(m) => println!("unexpected message from {node}: {m:?}"),
+                Err(e) => println!("failed to get headers from {node}: {e}"),
+            }
+        }
+
+        println!(
+            "header chain height: {}, total work: {}",
+            header_chain.height(),
+            header_chain.total_work()
+        );
+
+        if let Some(pubkey) = &watch_key {
+            prove_balance(&header_chain, pubkey).await;
+        }
+    }
+}
+
+// ask peers for the key's proven outputs until one answers, proofs that don't check out are
+// left out of the balance
+async fn prove_balance(header_chain: &HeaderChain, pubkey: &PublicKey) {
+    let all_nodes = NODES
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in all_nodes {
+        let message = Message::FetchUtxoProofs(pubkey.clone());
+        match util::request(&node, &message).await {
+            Ok(Message::UtxoProofs(proofs)) => {
+                let mut balance = 0;
+                for proof in &proofs {
+                    match header_chain.verify_utxo_proof(proof, pubkey) {
+                        Ok(value) => balance += value,
+                        Err(e) => println!("rejected utxo proof from {node}: {e}"),
+                    }
+                }
+                println!(
+                    "proven balance: {balance} from {} transactions",
+                    proofs.len()
+                );
+                return;
+            }
+            Ok(m) => println!("unexpected message from {node}: {m:?}"),
+            Err(e) => println!("failed to get utxo proofs from {node}: {e}"),
+        }
+    }
+}
@@ -1,21 +1,86 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use argh::FromArgs;
 
+mod ban;
 mod handler;
+mod http;
+mod light;
+mod miner;
+#[cfg(feature = "proxy")]
+mod proxy;
+mod transport;
 mod util;
 
-use btclib::types::Blockchain;
+use arc_swap::ArcSwap;
+use btclib::{
+    codec::WireFormat,
+    crypto::PublicKey,
+    network::Message,
+    params::ChainParams,
+    sha256::Hash,
+    types::{Blockchain, Transaction, Utxo},
+    util::Saveable,
+};
 use dashmap::DashMap;
 use static_init::dynamic;
-use tokio::{net::TcpStream, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, broadcast, watch},
+};
+use transport::SharedPeer;
 
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
 
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static NODES: DashMap<String, SharedPeer> = DashMap::new();
+
+// hash of the tip, updated whenever a block is added so waiting miners hear about it
+#[dynamic]
+pub static TIP: watch::Sender<Hash> = watch::Sender::new(Hash::zero());
+
+// transactions as they enter the mempool, for connections watching keys
+#[dynamic]
+pub static ACCEPTED: broadcast::Sender<Transaction> = broadcast::channel(1_024).0;
+
+// copy of the utxo set that balance queries read without waiting on the blockchain lock
+// replaced after every new block, so it is at most one block behind while one is being applied,
+// and the marks of utxos spent in the mempool are refreshed with the mempool cleanup
+#[dynamic]
+pub static UTXOS: ArcSwap<HashMap<Hash, Utxo>> = ArcSwap::from_pointee(HashMap::new());
+
+// misbehaviour scores of the peers that connected to us
+#[dynamic]
+pub static SCORES: DashMap<IpAddr, ban::BanScore> = DashMap::new();
+
+// discovered peers, saved in the data directory
+const PEERS_FILE: &str = "peers.cbor";
+// mempool transactions kept across restarts, in the data directory
+const MEMPOOL_FILE: &str = "mempool.cbor";
+
+// format of the messages we start conversations with, peers are answered in their own
+pub static WIRE_FORMAT: OnceLock<WireFormat> = OnceLock::new();
+
+// file rejected blocks are appended to, unset when not logging them
+pub static REJECTED_BLOCKS_LOG: OnceLock<PathBuf> = OnceLock::new();
+
+// socks5 proxy outbound peer connections go through, unset to dial peers directly
+#[cfg(feature = "proxy")]
+pub static PROXY: OnceLock<String> = OnceLock::new();
+
+// number of inbound connections currently being handled
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(FromArgs)]
 /// My toy blockchain node
@@ -24,10 +89,72 @@ struct Args {
     /// port number
     port: u16,
 
+    #[argh(option, default = "String::from(\".\")")]
+    /// directory holding the blockchain file and other node state
+    datadir: String,
+
     #[argh(option, default = "String::from(\".blockchain.cbor\")")]
-    /// blockchain file path
+    /// blockchain file path, relative to the data directory
     blockchain_file: String,
 
+    #[argh(option)]
+    /// port for the read-only http api, disabled when unset
+    http_port: Option<u16>,
+
+    #[argh(option, default = "64")]
+    /// maximum number of inbound connections
+    max_connections: usize,
+
+    #[argh(option, default = "100")]
+    /// maximum messages per second a peer may send before being dropped
+    max_message_rate: u32,
+
+    #[argh(option, default = "WireFormat::Cbor")]
+    /// message encoding for outgoing connections, cbor or bincode
+    wire_format: WireFormat,
+
+    #[argh(switch)]
+    /// use regtest chain parameters, which relax maturity and fee rules for testing
+    regtest: bool,
+
+    #[argh(switch)]
+    /// keep the blockchain file as an append-only block log instead of rewriting it on every save
+    block_log: bool,
+
+    #[argh(option)]
+    /// bootstrap file to import blocks from when there is no blockchain file
+    bootstrap: Option<String>,
+
+    #[argh(switch)]
+    /// archive blocks without keeping a mempool, transactions are neither accepted nor relayed
+    no_mempool: bool,
+
+    #[argh(option)]
+    /// append the hash, height, reason and time of every rejected block to this file,
+    /// relative to the data directory
+    rejected_blocks_log: Option<String>,
+
+    #[argh(switch)]
+    /// save the mempool on shutdown and with the blockchain, and offer it to the mempool again on startup
+    persist_mempool: bool,
+
+    #[cfg(feature = "proxy")]
+    #[argh(option)]
+    /// socks5 proxy (host:port) to dial peers through, e.g. tor's 127.0.0.1:9050
+    proxy: Option<String>,
+
+    #[argh(switch)]
+    /// follow the header chain only, without holding blocks (light client)
+    light: bool,
+
+    #[argh(option)]
+    /// as a light client, prove the balance of the public key in this file with merkle proofs
+    watch: Option<String>,
+
+    #[argh(option)]
+    /// mine in-process, paying rewards to the public key in this file
+    mine: Option<String>,
+
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
@@ -37,38 +164,174 @@ struct Args {
 async fn main() -> Result<()> {
     let args: Args = argh::from_env();
     let port = args.port;
-    let blockchain_file = args.blockchain_file;
+    let datadir = PathBuf::from(args.datadir);
+    fs::create_dir_all(&datadir)?;
+    let blockchain_file = datadir.join(args.blockchain_file);
+    let peers_file = datadir.join(PEERS_FILE);
+    let mempool_file = args.persist_mempool.then(|| datadir.join(MEMPOOL_FILE));
     let nodes = args.nodes;
+    let http_port = args.http_port;
+    let max_connections = args.max_connections;
+    let max_message_rate = args.max_message_rate;
+    let light = args.light;
+    let bootstrap = args.bootstrap;
+    let block_log = args.block_log;
+    WIRE_FORMAT.get_or_init(|| args.wire_format);
+    #[cfg(feature = "proxy")]
+    if let Some(proxy) = args.proxy {
+        PROXY.get_or_init(|| proxy);
+    }
+    if let Some(rejected_blocks_log) = args.rejected_blocks_log {
+        REJECTED_BLOCKS_LOG.get_or_init(|| datadir.join(rejected_blocks_log));
+    }
+    let mut params = if args.regtest {
+        ChainParams::regtest()
+    } else {
+        ChainParams::mainnet()
+    };
+    params.mempool_enabled = !args.no_mempool;
+    let mining_key = args
+        .mine
+        .map(|path| {
+            PublicKey::load_from_file(&path).map_err(|e| anyhow!("Error reading public key: {e}"))
+        })
+        .transpose()?;
+    let watch_key = args
+        .watch
+        .map(|path| {
+            PublicKey::load_from_file(&path).map_err(|e| anyhow!("Error reading public key: {e}"))
+        })
+        .transpose()?;
+
+    if light {
+        util::populate_connection(&nodes).await?;
+        return light::run(params, watch_key).await;
+    }
 
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+    // phase 1: load the chain from disk, if we have one, or import a bootstrap file
+    let mut loaded = blockchain_file.exists();
+    if loaded {
+        util::load_blockchain(&blockchain_file, block_log, &params).await?;
     } else {
         println!("blockchain file is missing!");
-        if nodes.is_empty() {
-            println!("starting nodes are empty, starting as a seed node");
+    }
+    BLOCKCHAIN.write().await.set_params(params);
+    if let (false, Some(bootstrap)) = (loaded, bootstrap) {
+        let file = fs::File::open(&bootstrap)?;
+        let imported = BLOCKCHAIN
+            .write()
+            .await
+            .import_bootstrap(std::io::BufReader::new(file))?;
+        println!("imported {imported} blocks from {bootstrap}");
+        loaded = imported > 0;
+    }
+
+    // phase 2: connect to peers, command line ones first and then those we saw last time
+    let saved_peers = util::load_peers(&peers_file, &nodes);
+    if nodes.is_empty() && saved_peers.is_empty() {
+        println!("starting nodes are empty, starting as a seed node");
+    } else {
+        util::populate_connection(&nodes).await?;
+        for peer in saved_peers {
+            if NODES.contains_key(&peer) {
+                continue;
+            }
+            // saved peers may have gone away since, don't let them stop startup
+            if let Err(e) = util::populate_connection(std::slice::from_ref(&peer)).await {
+                println!("skipping saved peer {peer}: {e}");
+            }
+        }
+        println!("total nodes: {}", NODES.len());
+    }
+
+    // phases 3-5: bootstrap from the network when there was nothing on disk
+    if !loaded && !NODES.is_empty() {
+        let (longest_name, longest_count) = util::find_longest_chain_node().await?;
+
+        if longest_count > 0 {
+            util::download_blockchain(&longest_name, longest_count).await?;
+            println!("blockchain downloaded from {longest_name}");
+
+            let mut blockchain = BLOCKCHAIN.write().await;
+            // add_block kept the target adjusted while downloading
+            blockchain.rebuild_utxos()?;
         } else {
-            util::populate_connection(&nodes).await?;
-            println!("total nodes: {}", NODES.len());
-
-            if nodes.is_empty() {
-                println!("starting nodes are empty, starting as a seed node");
-            } else {
-                let (longest_name, longest_count) = util::find_longest_chain_node().await?;
-                util::dowload_blockchain(&longest_name, longest_count).await?;
-                println!("blockchain downloaded from {longest_name}");
-
-                // limit rwlock scope to within block
-                // lock is released as blockchain goes out of scope
-                {
-                    let mut blockchain = BLOCKCHAIN.write().await;
-                    let _ = blockchain.rebuild_utxos();
-                }
-                {
-                    let mut blockchain = BLOCKCHAIN.write().await;
-                    blockchain.try_adjust_target();
-                }
+            println!("no peer has any blocks, starting from an empty chain");
+        }
+    }
+
+    if let Some(mempool_file) = &mempool_file {
+        util::load_mempool(mempool_file).await;
+    }
+
+    util::tip_changed(&*BLOCKCHAIN.read().await);
+
+    // phase 6: serve peers
+    let address = format!("0.0.0.0:{port}");
+    let listener = TcpListener::bind(&address).await?;
+    println!("listening on {address}");
+    // the nodes we dialled don't know where we listen, tell them so they relay to us too
+    handler::broadcast(Message::Announce(port)).await;
+
+    tokio::spawn(util::cleanup());
+    tokio::spawn(util::watch_tip());
+    tokio::spawn(util::save(
+        blockchain_file.clone(),
+        peers_file,
+        mempool_file.clone(),
+        block_log,
+    ));
+
+    if let Some(mempool_file) = mempool_file {
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                util::save_mempool(&mempool_file).await;
+                std::process::exit(0);
             }
+        });
+    }
+
+    if let Some(http_port) = http_port {
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(http_port).await {
+                println!("http api stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(public_key) = mining_key {
+        tokio::spawn(async move {
+            if let Err(e) = miner::mine(public_key).await {
+                println!("in-process miner stopped: {e}");
+            }
+        });
+    }
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+
+        if let Some(reason) = refusal(peer.ip(), max_connections) {
+            println!("refusing connection from {peer}: {reason}");
+            continue;
         }
+
+        CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            handler::handle_connection(socket, peer.ip(), max_message_rate).await;
+            CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+// why a new connection from peer is turned away, None to let it in
+fn refusal(peer: IpAddr, max_connections: usize) -> Option<&'static str> {
+    if ban::is_banned(peer) {
+        return Some("banned");
+    }
+
+    if CONNECTIONS.load(Ordering::SeqCst) >= max_connections {
+        return Some("too many connections");
     }
-    Ok(())
+
+    None
 }
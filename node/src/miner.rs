@@ -0,0 +1,49 @@
+use anyhow::Result;
+use btclib::crypto::PublicKey;
+
+use crate::{BLOCKCHAIN, handler, util};
+
+// steps to grind before checking whether the tip has moved
+const MINING_STEPS: usize = 2_000_000;
+
+// mine on top of the node's own blockchain, paying rewards to public_key
+pub async fn mine(public_key: PublicKey) -> Result<()> {
+    println!("mining in-process");
+
+    loop {
+        let template = BLOCKCHAIN
+            .read()
+            .await
+            .build_template(&[(public_key.clone(), 1)])?;
+        println!("mining block with target: {}", template.header.target);
+
+        // hashing is cpu bound, keep it off the async workers
+        let mined = tokio::task::spawn_blocking(move || {
+            let mut block = template;
+            block
+                .header
+                .mine(MINING_STEPS)
+                .map(|found| found.then_some(block))
+        })
+        .await??;
+
+        let Some(block) = mined else {
+            continue;
+        };
+
+        let mut blockchain = BLOCKCHAIN.write().await;
+        let height = blockchain.block_height();
+        if let Err(e) = blockchain.add_block(block.clone()) {
+            // tip moved while mining, start over with a fresh template
+            println!("mined block rejected: {e}");
+            util::log_rejected_block(&block, height, &e);
+            continue;
+        }
+        blockchain.rebuild_utxos()?;
+        util::tip_changed(&blockchain);
+        println!("block mined: {}", block.hash()?);
+        drop(blockchain);
+
+        handler::broadcast_block(&block).await;
+    }
+}
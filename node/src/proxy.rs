@@ -0,0 +1,134 @@
+use anyhow::{Result, anyhow};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+
+// dial node (host:port) through the socks5 proxy, without authentication
+// the host is passed on as a domain name so the proxy resolves it, tor needs that for .onion
+pub async fn connect(proxy: &str, node: &str) -> Result<TcpStream> {
+    let (host, port) = node
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("node address {node} has no port"))?;
+    let port: u16 = port.parse()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host_len = u8::try_from(host.len()).map_err(|_| anyhow!("host name {host} too long"))?;
+
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream
+        .write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])
+        .await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS_VERSION, NO_AUTHENTICATION] {
+        return Err(anyhow!("proxy {proxy} refused unauthenticated connections"));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0, ADDRESS_DOMAIN, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION || reply[1] != SUCCEEDED {
+        return Err(anyhow!(
+            "proxy {proxy} failed to connect to {node}, reply {}",
+            reply[1]
+        ));
+    }
+
+    // the reply ends with the address the proxy bound, which we have no use for
+    let bound_len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+        atyp => return Err(anyhow!("proxy {proxy} replied with address type {atyp}")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // a socks5 proxy that takes one connection, checks it asks for node and answers with reply
+    async fn mock_proxy(node: &'static str, reply: u8) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, NO_AUTHENTICATION]);
+            stream
+                .write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])
+                .await
+                .unwrap();
+
+            let (host, port) = node.rsplit_once(':').unwrap();
+            let mut request = vec![0u8; 5 + host.len() + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(
+                request[..5],
+                [SOCKS_VERSION, CONNECT, 0, ADDRESS_DOMAIN, host.len() as u8]
+            );
+            assert_eq!(&request[5..5 + host.len()], host.as_bytes());
+            assert_eq!(
+                request[5 + host.len()..],
+                port.parse::<u16>().unwrap().to_be_bytes()
+            );
+
+            // bound to 127.0.0.1:0, then whatever the node would send
+            stream
+                .write_all(&[SOCKS_VERSION, reply, 0, ADDRESS_IPV4, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            if reply == SUCCEEDED {
+                stream.write_all(b"node").await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_proxy() {
+        let node = "example.onion:9000";
+        let proxy = mock_proxy(node, SUCCEEDED).await;
+
+        let mut stream = connect(&proxy, node).await.unwrap();
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"node");
+    }
+
+    #[tokio::test]
+    async fn proxy_failing_to_connect_is_an_error() {
+        let node = "example.onion:9000";
+        // 5 is connection refused
+        let proxy = mock_proxy(node, 5).await;
+
+        let error = connect(&proxy, node).await.unwrap_err();
+        assert!(error.to_string().contains("reply 5"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn node_without_a_port_is_an_error() {
+        assert!(connect("127.0.0.1:1", "example.onion").await.is_err());
+    }
+}
@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+
+// anything a peer can be reached over, tcp today but unix sockets or in-memory pipes work too
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Transport for T {}
+
+pub type Peer = Box<dyn Transport>;
+
+// a peer as kept in NODES, each conversation locks it so requests and replies don't interleave
+// and no map guard has to be held across awaits
+pub type SharedPeer = Arc<Mutex<Peer>>;
+
+pub fn shared(peer: Peer) -> SharedPeer {
+    Arc::new(Mutex::new(peer))
+}
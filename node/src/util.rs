@@ -1,10 +1,46 @@
-use anyhow::Result;
-use btclib::{network::Message, types::Blockchain, util::Saveable};
-use tokio::net::TcpStream;
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
+use anyhow::{Result, anyhow};
+use btclib::{
+    codec::WireFormat,
+    error::BtcError,
+    network::{Message, PeerBook},
+    params::ChainParams,
+    sha256::Hash,
+    types::{Block, Blockchain, CompactBlock, SavedMempool, Transaction},
+    util::Saveable,
+};
+use chrono::Utc;
+use tokio::{io::BufReader, net::TcpStream, time::Duration};
+
+use crate::transport::{self, Peer};
+
+// how long a peer gets to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// the format we start conversations in, cbor unless chosen otherwise at startup
+pub fn wire_format() -> WireFormat {
+    crate::WIRE_FORMAT.get().copied().unwrap_or_default()
+}
+
+// a block log is replayed with params, a full chain file carries its own
+pub async fn load_blockchain(
+    blockchain_file: &Path,
+    block_log: bool,
+    params: &ChainParams,
+) -> Result<()> {
     println!("loading blockchain from file.. (questionable, I know)");
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
+    let new_blockchain = if block_log {
+        Blockchain::load_appended(blockchain_file, params.clone())?
+    } else {
+        Blockchain::load_from_file(blockchain_file)?
+    };
     println!("blockchain loaded!");
 
     let mut blockchain = crate::BLOCKCHAIN.write().await;
@@ -14,48 +50,578 @@ pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
     blockchain.rebuild_utxos()?;
     println!("utxos rebuild");
 
-    println!("adjusting target...");
-    println!("current target: {}", blockchain.target());
-    blockchain.try_adjust_target();
-    println!("new target: {}", blockchain.target());
+    // the saved target is already adjusted for the next block, blocks are checked against it
+    // and adjusting again here would disagree with peers that never restarted
+    println!(
+        "current target: {} (difficulty {:.2})",
+        blockchain.target(),
+        blockchain.difficulty()
+    );
 
     println!("blockchain initialisation complete!");
     Ok(())
 }
 
+// transactions saved by a previous run go back into the mempool if they are still valid
+pub async fn load_mempool(mempool_file: &Path) {
+    if !mempool_file.exists() {
+        return;
+    }
+
+    let saved = match SavedMempool::load_from_file(mempool_file) {
+        Ok(saved) => saved,
+        Err(e) => {
+            println!("failed to load saved mempool: {e}");
+            return;
+        }
+    };
+    let count = saved.0.len();
+    match crate::BLOCKCHAIN.write().await.restore_mempool(saved) {
+        Ok(kept) => println!("restored {kept} of {count} saved mempool transactions"),
+        Err(e) => println!("failed to restore mempool: {e}"),
+    }
+}
+
+pub async fn save_mempool(mempool_file: &Path) {
+    let saved = SavedMempool(crate::BLOCKCHAIN.read().await.mempool().to_vec());
+    match saved.save_to_file(mempool_file) {
+        Ok(()) => println!("saved {} mempool transactions", saved.0.len()),
+        Err(e) => println!("failed to save mempool: {e}"),
+    }
+}
+
+// peers saved by a previous run, minus the ones already given on the command line
+pub fn load_peers(peers_file: &Path, nodes: &[String]) -> Vec<String> {
+    if !peers_file.exists() {
+        return vec![];
+    }
+
+    match PeerBook::load_from_file(peers_file) {
+        Ok(PeerBook(mut peers)) => {
+            let mut seen = HashSet::new();
+            peers.retain(|peer| !nodes.contains(peer) && seen.insert(peer.clone()));
+            println!("loaded {} saved peers", peers.len());
+            peers
+        }
+        Err(e) => {
+            println!("failed to load saved peers: {e}");
+            vec![]
+        }
+    }
+}
+
+// connection attempts per starting node, and the wait before the second one, doubling after
+const CONNECT_ATTEMPTS: u32 = 4;
+const CONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+// unreachable nodes are skipped, only failing to reach every one of them is an error
 pub async fn populate_connection(nodes: &[String]) -> Result<()> {
     println!("connecting to other nodes...");
 
+    let mut contacted = 0;
     for node in nodes {
-        let mut stream = TcpStream::connect(node).await?;
+        match discover(node).await {
+            Ok(()) => contacted += 1,
+            Err(e) => println!("skipping node {node}: {e}"),
+        }
+    }
+
+    if contacted == 0 && !nodes.is_empty() {
+        return Err(anyhow!(
+            "none of the {} nodes could be reached",
+            nodes.len()
+        ));
+    }
+    Ok(())
+}
+
+// connect to a node and to the nodes it knows about
+async fn discover(node: &str) -> Result<()> {
+    let stream = connect_with_retry(node).await?;
+    handshake(node, stream).await
+}
 
-        let message = Message::DiscoverNodes;
-        message.send_async(&mut stream).await?;
-        println!("sent discover nodes message to {node}");
+// ask a freshly connected node for its peers and keep it, over whatever transport reached it
+async fn handshake(node: &str, mut stream: Peer) -> Result<()> {
+    let message = Message::DiscoverNodes;
+    message.send_async_with(&mut stream, wire_format()).await?;
+    println!("sent discover nodes message to {node}");
 
-        let message = Message::receive_async(&mut stream).await?;
-        match message {
-            Message::NodeList(neighours) => {
-                println!("received node list from {node}");
+    let message = Message::receive_async(&mut stream).await?;
+    match message {
+        Message::NodeList(neighours) => {
+            println!("received node list from {node}");
 
-                for neighbour in neighours {
-                    println!("adding node {neighbour}");
-                    let stream = TcpStream::connect(&neighbour).await?;
-                    crate::NODES.insert(neighbour, stream);
+            for neighbour in neighours {
+                println!("adding node {neighbour}");
+                match connect(&neighbour).await {
+                    Ok(stream) => {
+                        crate::NODES.insert(neighbour, transport::shared(stream));
+                    }
+                    Err(e) => println!("skipping node {neighbour}: {e}"),
                 }
             }
-            m => println!("unexpected message from {node}: {m:?}"),
         }
-
-        crate::NODES.insert(node.clone(), stream);
+        m => println!("unexpected message from {node}: {m:?}"),
     }
+
+    crate::NODES.insert(node.to_string(), transport::shared(stream));
     Ok(())
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    Ok((String::new(), 0))
+async fn connect_with_retry(node: &str) -> Result<Peer> {
+    let mut backoff = CONNECT_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match connect(node).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt < CONNECT_ATTEMPTS => {
+                println!("failed to connect to {node} ({e}), retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// send a request to a peer and wait for its reply
+// only the peer is locked for the exchange, not NODES, and a peer that fails or doesn't answer
+// in time is dropped, its connection could be left in the middle of a message
+pub async fn request(node: &str, message: &Message) -> Result<Message> {
+    let peer = crate::NODES
+        .get(node)
+        .map(|peer| peer.clone())
+        .ok_or_else(|| anyhow!("node {node} disconnected"))?;
+    let mut stream = peer.lock().await;
+
+    let exchange = async {
+        message.send_async_with(&mut *stream, wire_format()).await?;
+        Ok(Message::receive_async(&mut *stream).await?)
+    };
+    let reply = match tokio::time::timeout(REQUEST_TIMEOUT, exchange).await {
+        Ok(reply) => reply,
+        Err(_) => Err(anyhow!(
+            "node {node} did not answer within {REQUEST_TIMEOUT:?}"
+        )),
+    };
+
+    if reply.is_err() {
+        drop(stream);
+        crate::NODES.remove_if(node, |_, current| Arc::ptr_eq(current, &peer));
+    }
+    reply
+}
+
+// peers are addressed by host:port and reached over tcp, through the proxy if there is one
+pub async fn connect(node: &str) -> Result<Peer> {
+    #[cfg(feature = "proxy")]
+    if let Some(proxy) = crate::PROXY.get() {
+        return Ok(Box::new(BufReader::new(
+            crate::proxy::connect(proxy, node).await?,
+        )));
+    }
+
+    Ok(Box::new(BufReader::new(TcpStream::connect(node).await?)))
+}
+
+pub async fn find_longest_chain_node() -> Result<(String, u64)> {
+    println!("finding nodes with the highest blockchain length...");
+    let mut longest_name = String::new();
+    let mut longest_count = 0;
+
+    let all_nodes = crate::NODES
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in all_nodes {
+        println!("asking {node} for blockchain length");
+        // a difference against height 0 is the peer's full height
+        // one peer failing to answer shouldn't stop us syncing from the others
+        match request(&node, &Message::AskDifference(0)).await {
+            Ok(Message::Difference(count)) => {
+                println!("got difference from {node}: {count}");
+                if count > longest_count {
+                    longest_count = count;
+                    longest_name = node.clone();
+                }
+            }
+            Ok(m) => println!("unexpected message from {node}: {m:?}"),
+            Err(e) => println!("skipping {node}, failed to ask for its height: {e}"),
+        }
+    }
+
+    // the longest count is never below its starting 0
+    Ok((longest_name, longest_count.unsigned_abs()))
 }
 
-pub async fn dowload_blockchain(longest_name: &str, longest_count: u32) -> Result<()> {
+pub async fn download_blockchain(node: &str, count: u64) -> Result<()> {
+    for height in 0..count {
+        match request(node, &Message::FetchBlock(height)).await? {
+            Message::NewBlock(block) => {
+                // the next block is validated against the utxos this one creates,
+                // the caller rebuilds them in full once the download is done
+                crate::BLOCKCHAIN
+                    .write()
+                    .await
+                    .add_block_connecting(block)?;
+            }
+            m => return Err(anyhow!("unexpected message from {node}: {m:?}")),
+        }
+    }
+
     Ok(())
 }
+
+// fill in a compact block from our mempool, asking peers for whatever is missing
+pub async fn complete_compact_block(compact_block: CompactBlock) -> Result<Block> {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let mut candidates = blockchain
+        .mempool()
+        .iter()
+        .map(|(_, transaction)| transaction.clone())
+        .collect::<Vec<_>>();
+    let height = blockchain.block_height();
+    drop(blockchain);
+
+    let missing = compact_block.missing(&candidates)?;
+    if !missing.is_empty() {
+        println!("missing {} transactions, asking peers", missing.len());
+        let header_hash = compact_block.header.hash()?;
+        candidates.extend(fetch_block_transactions(header_hash, missing).await);
+    }
+
+    if let Some(block) = compact_block.reconstruct(&candidates)? {
+        return Ok(block);
+    }
+
+    println!("failed to reconstruct compact block, downloading it in full");
+    fetch_block(height).await
+}
+
+async fn fetch_block_transactions(header_hash: Hash, short_txids: Vec<u64>) -> Vec<Transaction> {
+    let all_nodes = crate::NODES
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in all_nodes {
+        let message = Message::GetData(header_hash, short_txids.clone());
+        if let Ok(Message::BlockTransactions(transactions)) = request(&node, &message).await
+            && !transactions.is_empty()
+        {
+            return transactions;
+        }
+    }
+
+    vec![]
+}
+
+async fn fetch_block(height: u64) -> Result<Block> {
+    let all_nodes = crate::NODES
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in all_nodes {
+        if let Ok(Message::NewBlock(block)) = request(&node, &Message::FetchBlock(height)).await {
+            return Ok(block);
+        }
+    }
+
+    Err(anyhow!("no peer could provide block {height}"))
+}
+
+// one line per rejected block when the node was asked to keep them:
+// time,hash,height,reason with the height the block would have had
+pub fn log_rejected_block(block: &Block, height: u64, error: &BtcError) {
+    let Some(path) = crate::REJECTED_BLOCKS_LOG.get() else {
+        return;
+    };
+    let hash = match block.hash() {
+        Ok(hash) => hash.to_string(),
+        Err(_) => "unhashable".to_string(),
+    };
+
+    let record = format!("{},{hash},{height},{error}\n", Utc::now().to_rfc3339());
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(record.as_bytes()));
+    if let Err(e) = written {
+        println!("failed to log rejected block: {e}");
+    }
+}
+
+// the chain has a new tip, wake up waiting miners and publish a fresh utxo snapshot
+pub fn tip_changed(blockchain: &Blockchain) {
+    if let Ok(hash) = blockchain.tip_hash() {
+        crate::TIP.send_replace(hash);
+    }
+    refresh_utxos(blockchain);
+}
+
+pub fn refresh_utxos(blockchain: &Blockchain) {
+    match blockchain.utxos().iter() {
+        Ok(utxos) => crate::UTXOS.store(Arc::new(utxos.collect())),
+        Err(e) => println!("failed to snapshot utxos: {e}"),
+    }
+}
+
+pub async fn cleanup() {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+
+    loop {
+        interval.tick().await;
+        println!("cleaning the mempool from old transactions");
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        if let Err(e) = blockchain.cleanup_mempool() {
+            println!("failed to clean the mempool: {e}");
+        }
+        refresh_utxos(&blockchain);
+    }
+}
+
+// retargets only happen every DIFFICULTY_UPDATE_INTERVAL blocks, so a drop in hashrate
+// can leave the chain stuck at a difficulty nobody mines at, make that visible
+pub async fn watch_tip() {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        if let Some(seconds) = stalled_for(&blockchain) {
+            println!(
+                "warning: no block for {seconds}s, the chain may be stalled at target {}",
+                blockchain.target()
+            );
+        }
+    }
+}
+
+// age of the tip once it is past STALL_BLOCK_TIMES block times, None while blocks keep coming
+fn stalled_for(blockchain: &Blockchain) -> Option<i64> {
+    let threshold = (btclib::IDEAL_BLOCK_TIME * btclib::STALL_BLOCK_TIMES) as i64;
+    let seconds = blockchain.seconds_since_tip();
+    (blockchain.block_height() > 0 && seconds > threshold).then_some(seconds)
+}
+
+pub async fn save(
+    name: PathBuf,
+    peers_file: PathBuf,
+    mempool_file: Option<PathBuf>,
+    block_log: bool,
+) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(15));
+
+    loop {
+        interval.tick().await;
+        println!("saving blockchain to drive...");
+        if block_log {
+            // appending moves the persisted watermark, hence the write lock
+            match crate::BLOCKCHAIN.write().await.save_appending(&name) {
+                Ok(written) => println!("appended {written} blocks"),
+                Err(e) => println!("failed to save blockchain: {e}"),
+            }
+        } else {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            if let Err(e) = blockchain.save_to_file(&name) {
+                println!("failed to save blockchain: {e}");
+            }
+        }
+
+        let peers = crate::NODES
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        if let Err(e) = PeerBook(peers).save_to_file(&peers_file) {
+            println!("failed to save peers: {e}");
+        }
+
+        if let Some(mempool_file) = &mempool_file {
+            save_mempool(mempool_file).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{self, SharedPeer};
+
+    // tests that add peers share NODES, this keeps them from seeing each other's
+    static NODES_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // a peer answering every request with answer's reply to it, until it is dropped
+    fn answering_peer(answer: impl Fn(Message) -> Message + Send + 'static) -> SharedPeer {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 20);
+        tokio::spawn(async move {
+            while let Ok(request) = Message::receive_async(&mut theirs).await {
+                if answer(request).send_async(&mut theirs).await.is_err() {
+                    return;
+                }
+            }
+        });
+        transport::shared(Box::new(ours))
+    }
+
+    #[tokio::test]
+    async fn longest_chain_search_skips_peers_that_fail() {
+        let _nodes = NODES_LOCK.lock().await;
+        // the other end is gone, asking this one fails
+        let (gone, _) = tokio::io::duplex(64);
+        crate::NODES.insert("gone:1".into(), transport::shared(Box::new(gone)));
+        crate::NODES.insert("short:1".into(), answering_peer(|_| Message::Difference(2)));
+        crate::NODES.insert("long:1".into(), answering_peer(|_| Message::Difference(5)));
+
+        let longest = find_longest_chain_node().await.unwrap();
+        assert_eq!(longest, ("long:1".to_string(), 5));
+        assert!(!crate::NODES.contains_key("gone:1"));
+        crate::NODES.clear();
+    }
+
+    // four distinct transactions under a matching merkle root, nothing else has to be valid
+    fn block_of_four() -> Block {
+        let miner = btclib::crypto::PrivateKey::new_key().public_key();
+        let transactions: Vec<_> = (0..4)
+            .map(|height| Transaction::coinbase(&miner, 1, height, None))
+            .collect();
+        Block::new(
+            btclib::types::BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                btclib::util::MerkleRoot::calculate(&transactions).unwrap(),
+                btclib::MIN_TARGET,
+            ),
+            transactions,
+        )
+    }
+
+    #[tokio::test]
+    async fn compact_block_is_completed_with_transactions_from_peers() {
+        let _nodes = NODES_LOCK.lock().await;
+        let block = block_of_four();
+        let spends = block.transactions[1..].to_vec();
+        crate::NODES.insert(
+            "relay:1".into(),
+            answering_peer(move |request| match request {
+                Message::GetData(..) => Message::BlockTransactions(spends.clone()),
+                m => panic!("unexpected request {m:?}"),
+            }),
+        );
+
+        let compact_block = CompactBlock::from_block(&block).unwrap();
+        let completed = complete_compact_block(compact_block).await.unwrap();
+        assert_eq!(completed.hash().unwrap(), block.hash().unwrap());
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn compact_block_peers_cant_complete_is_downloaded_in_full() {
+        let _nodes = NODES_LOCK.lock().await;
+        let block = block_of_four();
+        let full = block.clone();
+        crate::NODES.insert(
+            "relay:1".into(),
+            answering_peer(move |request| match request {
+                Message::GetData(..) => Message::BlockTransactions(vec![]),
+                Message::FetchBlock(_) => Message::NewBlock(full.clone()),
+                m => panic!("unexpected request {m:?}"),
+            }),
+        );
+
+        let compact_block = CompactBlock::from_block(&block).unwrap();
+        let completed = complete_compact_block(compact_block).await.unwrap();
+        assert_eq!(completed.hash().unwrap(), block.hash().unwrap());
+        crate::NODES.clear();
+    }
+
+    #[tokio::test]
+    async fn handshake_runs_over_an_in_memory_transport() {
+        let _nodes = NODES_LOCK.lock().await;
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let peer = "127.0.0.1".parse().unwrap();
+        tokio::spawn(crate::handler::handle_connection(theirs, peer, 100));
+
+        handshake("memory:1", Box::new(ours)).await.unwrap();
+        assert!(crate::NODES.contains_key("memory:1"));
+
+        // the kept peer answers requests like any tcp one
+        let height = crate::BLOCKCHAIN.read().await.block_height() as i64;
+        assert!(matches!(
+            request("memory:1", &Message::AskDifference(0)).await.unwrap(),
+            Message::Difference(difference) if difference == height
+        ));
+        crate::NODES.clear();
+    }
+
+    #[test]
+    fn saved_peers_are_reloaded_without_the_given_ones() {
+        let peers_file =
+            std::env::temp_dir().join(format!("btc-peers-{}.cbor", std::process::id()));
+        assert!(load_peers(&peers_file, &[]).is_empty());
+
+        let saved = [
+            "10.0.0.1:9000",
+            "10.0.0.2:9000",
+            "10.0.0.1:9000",
+            "10.0.0.3:9000",
+        ];
+        PeerBook(saved.iter().map(|peer| peer.to_string()).collect())
+            .save_to_file(&peers_file)
+            .unwrap();
+        let loaded = load_peers(&peers_file, &["10.0.0.2:9000".to_string()]);
+        std::fs::remove_file(&peers_file).unwrap();
+
+        assert_eq!(loaded, ["10.0.0.1:9000", "10.0.0.3:9000"]);
+    }
+
+    #[test]
+    fn stall_is_reported_once_the_tip_is_old() {
+        assert_eq!(stalled_for(&Blockchain::new()), None);
+
+        // the builder timestamps its blocks long in the past
+        let mut blockchain = btclib::testutil::ChainBuilder::new().mine(2).build();
+        assert!(stalled_for(&blockchain).is_some());
+
+        let miner = btclib::crypto::PrivateKey::new_key().public_key();
+        let mut block = blockchain.build_template(&[(miner, 1)]).unwrap();
+        while !block.header.mine(1_000).unwrap() {}
+        blockchain.add_block(block).unwrap();
+        assert_eq!(stalled_for(&blockchain), None);
+    }
+
+    #[test]
+    fn rejected_blocks_are_appended_to_the_log() {
+        let path = std::env::temp_dir().join(format!("btc-rejected-{}.log", std::process::id()));
+        // the log is set once per process, no other test sets it
+        crate::REJECTED_BLOCKS_LOG.set(path.clone()).unwrap();
+
+        let block = block_of_four();
+        let hash = block.hash().unwrap().to_string();
+        log_rejected_block(&block, 7, &BtcError::InvalidMerkleRoot);
+        log_rejected_block(&block, 8, &BtcError::ZeroTarget);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // other tests rejecting blocks may write to it too
+        let records: Vec<Vec<&str>> = log
+            .lines()
+            .map(|line| line.splitn(4, ',').collect())
+            .filter(|fields: &Vec<&str>| fields[1] == hash)
+            .collect();
+        assert_eq!(records.len(), 2);
+        for (fields, (height, error)) in records.iter().zip([
+            ("7", BtcError::InvalidMerkleRoot),
+            ("8", BtcError::ZeroTarget),
+        ]) {
+            chrono::DateTime::parse_from_rfc3339(fields[0]).unwrap();
+            assert_eq!(fields[2], height);
+            assert_eq!(fields[3], error.to_string());
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use btclib::{crypto::PrivateKey, testnet::TestNetwork};
+
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn blocks_mined_on_one_node_reach_the_others() {
+    let network = TestNetwork::start_with(env!("CARGO_BIN_EXE_node"), 3).unwrap();
+    let miner = PrivateKey::new_key().public_key();
+
+    // the first block goes onto empty chains, the second has to extend them
+    for height in 1..=2 {
+        let mined = network.node(0).mine_block(&miner).unwrap();
+
+        let tip = network.wait_for_convergence(CONVERGENCE_TIMEOUT).unwrap();
+        assert_eq!(tip, Some(mined));
+        for node in network.nodes() {
+            assert_eq!(node.height().unwrap(), height);
+        }
+    }
+}
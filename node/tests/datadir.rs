@@ -0,0 +1,33 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use btclib::testnet::TestNode;
+
+const SAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn node_keeps_its_files_under_the_data_directory() {
+    let node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_node").as_ref(),
+        &[],
+        &["--persist-mempool", "--blockchain-file", "chain.cbor"],
+    )
+    .unwrap();
+
+    // the first save runs as soon as the node serves peers
+    let files = ["chain.cbor", "peers.cbor", "mempool.cbor"].map(|name| node.datadir().join(name));
+    let start = Instant::now();
+    while !files.iter().all(|file| file.exists()) {
+        assert!(
+            start.elapsed() < SAVE_TIMEOUT,
+            "missing from the data directory: {:?}",
+            files
+                .iter()
+                .filter(|file| !file.exists())
+                .collect::<Vec<_>>()
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
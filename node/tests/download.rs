@@ -0,0 +1,69 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use btclib::{
+    crypto::PrivateKey,
+    network::Message,
+    testnet::TestNode,
+    types::{TransactionOutput, UnsignedTransaction},
+};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn node_binary() -> &'static std::path::Path {
+    env!("CARGO_BIN_EXE_node").as_ref()
+}
+
+// poll until the node's template includes a transaction besides the coinbase
+fn wait_for_template_transaction(node: &TestNode, miner: &PrivateKey) {
+    let start = Instant::now();
+    loop {
+        match node
+            .request(&Message::FetchTemplate(miner.public_key()))
+            .unwrap()
+        {
+            Message::Template(block) if block.transactions.len() > 1 => return,
+            _ if start.elapsed() > SYNC_TIMEOUT => panic!("transaction never reached the template"),
+            _ => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+#[test]
+fn new_node_downloads_the_chain_from_its_peer() {
+    let seed = TestNode::start_with(node_binary(), &[], &[]).unwrap();
+    let mut key = PrivateKey::new_key();
+    for _ in 0..2 {
+        seed.mine_block(&key.public_key()).unwrap();
+    }
+
+    // a block spending an earlier coinbase, so downloading has to track the utxos it creates
+    let coinbase = match seed
+        .request(&Message::FetchUTXOs(key.public_key()))
+        .unwrap()
+    {
+        Message::UTXOs(utxos) => utxos[0].0.clone(),
+        m => panic!("unexpected reply to utxos: {m:?}"),
+    };
+    let transaction = UnsignedTransaction {
+        outputs: vec![TransactionOutput {
+            value: coinbase.value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }],
+        inputs: vec![coinbase],
+        locktime: None,
+    }
+    .sign(&mut key)
+    .unwrap();
+    seed.submit_transaction(transaction).unwrap();
+    wait_for_template_transaction(&seed, &key);
+    seed.mine_block(&key.public_key()).unwrap();
+    assert_eq!(seed.height().unwrap(), 3);
+
+    let node = TestNode::start_with(node_binary(), &[seed.address()], &[]).unwrap();
+    node.wait_for_height(3, SYNC_TIMEOUT).unwrap();
+    assert_eq!(node.tip().unwrap(), seed.tip().unwrap());
+}
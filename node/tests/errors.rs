@@ -0,0 +1,56 @@
+use btclib::{
+    crypto::PrivateKey,
+    network::{ERROR_INVALID_TRANSACTION, ERROR_UNKNOWN_OUTPUT, Message},
+    testnet::TestNode,
+    types::{TransactionOutput, UnsignedTransaction},
+};
+
+fn spend(key: &mut PrivateKey, input: TransactionOutput, value: u64) -> Message {
+    let transaction = UnsignedTransaction {
+        outputs: vec![TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }],
+        inputs: vec![input],
+        locktime: None,
+    }
+    .sign(key)
+    .unwrap();
+    Message::SubmitTransaction(transaction)
+}
+
+fn error_code(reply: Message) -> u32 {
+    match reply {
+        Message::Error { code, .. } => code,
+        m => panic!("expected an error, got {m:?}"),
+    }
+}
+
+#[test]
+fn rejected_transactions_are_answered_with_their_error_code() {
+    let node = TestNode::start_with(env!("CARGO_BIN_EXE_node").as_ref(), &[], &[]).unwrap();
+    let mut key = PrivateKey::new_key();
+    node.mine_block(&key.public_key()).unwrap();
+    let coinbase = match node
+        .request(&Message::FetchUTXOs(key.public_key()))
+        .unwrap()
+    {
+        Message::UTXOs(utxos) => utxos[0].0.clone(),
+        m => panic!("unexpected reply to utxos: {m:?}"),
+    };
+
+    // an output the node has never seen
+    let unknown = TransactionOutput {
+        value: 1,
+        unique_id: uuid::Uuid::new_v4(),
+        pubkey: key.public_key(),
+    };
+    let reply = node.request(&spend(&mut key, unknown, 1)).unwrap();
+    assert_eq!(error_code(reply), ERROR_UNKNOWN_OUTPUT);
+
+    // paying out more than the coinbase holds
+    let value = coinbase.value + 1;
+    let reply = node.request(&spend(&mut key, coinbase, value)).unwrap();
+    assert_eq!(error_code(reply), ERROR_INVALID_TRANSACTION);
+}
@@ -0,0 +1,88 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use btclib::{
+    crypto::PrivateKey,
+    network::Message,
+    testnet::TestNode,
+    types::{TransactionOutput, UnsignedTransaction},
+};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the body of a GET to the node's http api, retried until the api is listening
+fn get(port: u16, path: &str) -> String {
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < HTTP_TIMEOUT => thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("http api never came up: {e}"),
+        }
+    };
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+#[test]
+fn mempool_histogram_is_served_over_http() {
+    let http_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_node").as_ref(),
+        &[],
+        &["--http-port", &http_port.to_string()],
+    )
+    .unwrap();
+    assert_eq!(get(http_port, "/mempool/histogram"), "[]");
+
+    let mut key = PrivateKey::new_key();
+    node.mine_block(&key.public_key()).unwrap();
+    let coinbase = match node
+        .request(&Message::FetchUTXOs(key.public_key()))
+        .unwrap()
+    {
+        Message::UTXOs(utxos) => utxos[0].0.clone(),
+        m => panic!("unexpected reply to utxos: {m:?}"),
+    };
+    // a free transaction lands in the 0 bucket
+    let transaction = UnsignedTransaction {
+        outputs: vec![TransactionOutput {
+            value: coinbase.value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }],
+        inputs: vec![coinbase],
+        locktime: None,
+    }
+    .sign(&mut key)
+    .unwrap();
+    let size = transaction.serialized_size() as u64;
+    node.submit_transaction(transaction).unwrap();
+
+    let start = Instant::now();
+    loop {
+        let histogram: Vec<(u64, u64)> =
+            serde_json::from_str(&get(http_port, "/mempool/histogram")).unwrap();
+        if !histogram.is_empty() {
+            assert_eq!(histogram, [(0, size)]);
+            return;
+        }
+        assert!(
+            start.elapsed() < HTTP_TIMEOUT,
+            "transaction never reached the mempool"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,25 @@
+use std::{env, fs, time::Duration};
+
+use btclib::{crypto::PrivateKey, testnet::TestNode, util::Saveable};
+
+const MINING_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn node_started_with_mine_extends_its_chain() {
+    let key_file = env::temp_dir().join(format!("btc-mining-{}.pub.pem", std::process::id()));
+    PrivateKey::new_key()
+        .public_key()
+        .save_to_file(&key_file)
+        .unwrap();
+
+    let node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_node").as_ref(),
+        &[],
+        &["--mine", key_file.to_str().unwrap()],
+    )
+    .unwrap();
+    let result = node.wait_for_height(2, MINING_TIMEOUT);
+    fs::remove_file(&key_file).unwrap();
+
+    result.unwrap();
+}